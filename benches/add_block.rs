@@ -0,0 +1,62 @@
+// Grows a single branch to 100k blocks and reports how long `add_block` took
+// for every slice of 10k blocks. Finding the branch of the parent and the
+// rules run on a block do not depend on the height, but the growing hash
+// maps and block list do get slower as they leave the caches: expect some
+// drift and noise between slices, not a time proportional to the height.
+//
+//     cargo bench --bench add_block
+use std::time::{Duration, Instant};
+
+use blockchain::block::{Block, BlockContent};
+use blockchain::merkle::Merkle;
+use blockchain::mining::Miner;
+use blockchain::sha256::Sha256Hash;
+use blockchain::target::Target;
+use blockchain::time::Time;
+use blockchain::transaction::Transaction;
+use blockchain::user::User;
+
+const BLOCKS: u32 = 100_000;
+const SLICE: u32 = 10_000;
+
+fn main() {
+    let miner = User::from_nothingness().as_public();
+    let mut merkle = Merkle::new_from_nothingness();
+    // every hash meets the target, what is measured is add_block and not mining
+    merkle.params.initial_target = Target::max();
+
+    let mut prev_block_hash = Sha256Hash::zero();
+    let mut elapsed = Duration::ZERO;
+    for index in 1..=BLOCKS {
+        let reward = merkle.params.halving.subsidy(index);
+        let mut block_content = BlockContent::new(
+            index,
+            Time::from_second(index as u64 * merkle.params.target_block_time / 1000),
+            prev_block_hash,
+            merkle.main().next_target(&merkle.blocks, &merkle.params),
+            vec![Transaction::new_from_coinbase(&miner, &reward)],
+        );
+        block_content.set_state_root(&merkle.main().ledger);
+        let (nonce, hash) = Miner::mine(&block_content);
+        let block = Block::new_mined(block_content, nonce, hash);
+        prev_block_hash = block.hash;
+
+        let start = Instant::now();
+        match merkle.add_block(block) {
+            Ok(_) => (),
+            Err(e) => panic!("failed to add block {}: {:?}", index, e),
+        }
+        elapsed += start.elapsed();
+
+        if index % SLICE == 0 {
+            println!(
+                "blocks {:>6} to {:>6}: {:>8.2?} ({:.2?} per block)",
+                index - SLICE + 1,
+                index,
+                elapsed,
+                elapsed / SLICE
+            );
+            elapsed = Duration::ZERO;
+        }
+    }
+}
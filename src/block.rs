@@ -1,12 +1,14 @@
-use super::ledger::Ledger;
+use super::ledger::PartialLedger;
 use super::sha256::Sha256Hash;
 use super::time::Time;
+use crate::consensus::{median_time_past, BlockContext, Rejection, RuleSet, TransactionContext};
 use crate::error::Error;
-use crate::ledger::PartialLedger;
 use crate::merkle::Merkle;
-use crate::mining::Miner;
+use crate::merkle_tree::{MerkleProof, MerkleTree};
 use crate::num::Num;
 use crate::reader::{read_struct, read_u128, read_u32, read_vec_struct, Readable};
+use crate::state::{BlockTransaction, StateModel};
+use crate::target::Target;
 use crate::transaction::Transaction;
 use crate::user::PublicUser;
 use crate::writer::{write_struct, write_u128, write_u32, write_vec_struct, Writable};
@@ -14,21 +16,30 @@ use std::fmt::{self, Debug, Formatter};
 use std::io::{Read, Write};
 use std::vec;
 
+use num::{BigUint, Zero};
+
+// A block of a chain whose transactions are `T`, the accounts of the ledger
+// by default.
 #[derive(Clone)]
-pub struct BlockContent {
+pub struct BlockContent<T = Transaction> {
     pub index: u32,
     pub timestamp: Time,
     pub prev_block_hash: Sha256Hash,
-    pub transactions: Vec<Transaction>,
+    pub target: Target,
+    // root of the transaction tree, commits the header to the transactions
+    pub transactions_root: Sha256Hash,
+    // state root of the ledger once the block is applied, see `StateModel::state_root`
+    pub state_root: Sha256Hash,
+    pub transactions: Vec<T>,
 }
 #[derive(Clone)]
-pub struct Block {
-    pub content: BlockContent,
+pub struct Block<T = Transaction> {
+    pub content: BlockContent<T>,
     pub proof_of_work: u128,
     pub hash: Sha256Hash,
 }
 
-impl Debug for Block {
+impl<T: BlockTransaction> Debug for Block<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
@@ -41,7 +52,7 @@ impl Debug for Block {
     }
 }
 
-impl Block {
+impl<T: BlockTransaction> Block<T> {
     pub fn zero() -> Self {
         Block {
             content: BlockContent::zero(),
@@ -55,6 +66,9 @@ impl Block {
                 index: 0,
                 timestamp: Time::zero(),
                 prev_block_hash: Sha256Hash::zero(),
+                target: Target::zero(),
+                transactions_root: Sha256Hash::zero(),
+                state_root: Sha256Hash::zero(),
                 transactions: vec![],
             },
             proof_of_work: 0,
@@ -74,14 +88,10 @@ impl Block {
         index: u32,
         timestamp: Time,
         prev_block_hash: Sha256Hash,
-        transactions: Vec<Transaction>,
+        target: Target,
+        transactions: Vec<T>,
     ) -> Self {
-        let content = BlockContent {
-            index: index,
-            timestamp: timestamp,
-            prev_block_hash: prev_block_hash,
-            transactions: transactions,
-        };
+        let content = BlockContent::new(index, timestamp, prev_block_hash, target, transactions);
 
         Block {
             content: content,
@@ -90,7 +100,11 @@ impl Block {
         }
     }
 
-    pub fn new_mined(block_content: BlockContent, proof_of_work: u128, hash: Sha256Hash) -> Self {
+    pub fn new_mined(
+        block_content: BlockContent<T>,
+        proof_of_work: u128,
+        hash: Sha256Hash,
+    ) -> Self {
         Block {
             content: block_content,
             hash: hash,
@@ -98,46 +112,72 @@ impl Block {
         }
     }
 
-    pub fn verify(&self, difficulty: u128) -> Result<(), Error> {
-        // The genesis should never be verified
-        if self.is_genesis() {
-            return Err(Error::VerifyingGenesisBlock);
-        }
-
-        if self.content.transactions.is_empty() {
-            return Err(Error::BlockContainsNoTransaction);
-        }
-
-        if !Miner::check_difficulty(&self.hash, difficulty) {
-            return Err(Error::BlockProofOfWorkIsNotDone);
-        }
+    pub fn verify<S: StateModel<Transaction = T>>(
+        &self,
+        rules: &RuleSet<S>,
+        context: &BlockContext<S>,
+    ) -> Result<(), Rejection> {
+        rules.check_block(self, context)
+    }
 
-        if self.hash != self.hash() {
-            return Err(Error::BlockHashIsInvalid);
+    pub fn verify_payload<S: StateModel<Transaction = T>>(
+        &self,
+        rules: &RuleSet<S>,
+        ledger: &S,
+    ) -> Result<S, Rejection> {
+        match self.verify_changes(rules, ledger) {
+            Ok((_, new_ledger)) => Ok(new_ledger),
+            Err(e) => Err(e),
         }
-
-        return Ok(());
     }
 
-    pub fn verify_payload(&self, ledger: &Ledger) -> Result<Ledger, Error> {
-        let mut partial_ledger: PartialLedger = PartialLedger::empty();
+    // same as verify_payload but also returns the entries the block modifies
+    pub fn verify_changes<S: StateModel<Transaction = T>>(
+        &self,
+        rules: &RuleSet<S>,
+        ledger: &S,
+    ) -> Result<(S::Changes, S), Rejection> {
+        let mut partial_ledger = S::empty_changes();
 
         for transaction in &self.content.transactions {
-            match partial_ledger.apply_transaction(ledger, transaction) {
+            let context = TransactionContext::new(ledger, &partial_ledger, Some(&self.content));
+            match rules.check_transaction(transaction, &context) {
                 Ok(_) => (),
                 Err(e) => return Err(e),
             }
+            match ledger.apply_transaction(&mut partial_ledger, transaction, self.content.index) {
+                Ok(_) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let new_ledger = ledger.apply_changes(&partial_ledger);
+        match rules.check_state(self, &new_ledger) {
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        }
+        return Ok((partial_ledger, new_ledger));
+    }
+
+    // the genesis is shared by every branch, it does not count
+    pub fn work(&self) -> BigUint {
+        if self.is_genesis() {
+            return BigUint::zero();
         }
-        return Ok(partial_ledger.to_ledger(ledger));
+        self.content.target.work()
     }
 
     pub fn hash(&self) -> Sha256Hash {
         Sha256Hash::new(&[
-            &self.content.to_bytes(),
+            &self.content.header_bytes(),
             &self.proof_of_work.to_be_bytes().to_vec(),
         ])
     }
 
+    // proves that the transaction at `index` is committed by the header
+    pub fn transaction_proof(&self, index: usize) -> Option<MerkleProof> {
+        self.content.transaction_tree().proof(index)
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
 
@@ -152,7 +192,7 @@ impl Block {
     }
 }
 
-impl Writable for Block {
+impl<T: BlockTransaction> Writable for Block<T> {
     fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
         write_u128(writer, self.proof_of_work)
             .and_then(|_| write_struct(writer, &self.hash))
@@ -160,7 +200,7 @@ impl Writable for Block {
     }
 }
 
-impl Readable for Block {
+impl<T: BlockTransaction> Readable for Block<T> {
     fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
         let mut block = Block::zero();
 
@@ -176,12 +216,15 @@ impl Readable for Block {
     }
 }
 
-impl BlockContent {
+impl<T: BlockTransaction> BlockContent<T> {
     pub fn zero() -> Self {
         BlockContent {
             index: 0,
             timestamp: Time::zero(),
             prev_block_hash: Sha256Hash::zero(),
+            target: Target::zero(),
+            transactions_root: Sha256Hash::zero(),
+            state_root: Sha256Hash::zero(),
             transactions: vec![],
         }
     }
@@ -190,38 +233,64 @@ impl BlockContent {
         index: u32,
         timestamp: Time,
         prev_block_hash: Sha256Hash,
-        transactions: Vec<Transaction>,
+        target: Target,
+        transactions: Vec<T>,
     ) -> Self {
-        BlockContent {
+        let mut block_content = BlockContent {
             index: index,
             timestamp: timestamp,
             prev_block_hash: prev_block_hash,
+            target: target,
+            transactions_root: Sha256Hash::zero(),
+            state_root: Sha256Hash::zero(),
             transactions: transactions,
-        }
+        };
+        block_content.transactions_root = block_content.compute_transactions_root();
+
+        block_content
     }
 
-    pub fn new_from_pending_transaction(
-        pending_transactions: &Vec<Transaction>,
-        merkle: &Merkle,
-        public_user: &PublicUser,
-    ) -> Self {
-        let ledger = &merkle.main().ledger;
-        let mut block_transactions: Vec<Transaction> = vec![Transaction::new_from_coinbase(
-            &public_user,
-            &Num::from_u64(1),
-        )];
-        for transaction in pending_transactions {
-            if !ledger.contains(transaction) {
-                block_transactions.push(transaction.clone());
-            }
-        }
+    // the state root the block commits to when it is applied on `parent_ledger`
+    pub fn compute_state_root<S: StateModel<Transaction = T>>(
+        &self,
+        parent_ledger: &S,
+    ) -> Sha256Hash {
+        parent_ledger
+            .apply_unsafe(&self.transactions, self.index)
+            .state_root()
+    }
 
-        BlockContent {
-            index: merkle.main().last(&merkle.blocks).content.index + 1,
-            timestamp: Time::now(),
-            prev_block_hash: merkle.main().last(&merkle.blocks).hash,
-            transactions: block_transactions,
-        }
+    pub fn set_state_root<S: StateModel<Transaction = T>>(&mut self, parent_ledger: &S) {
+        self.state_root = self.compute_state_root(parent_ledger);
+    }
+
+    // serialized size, the block size limit applies to it
+    pub fn size(&self) -> usize {
+        self.to_bytes().len()
+    }
+
+    pub fn transaction_tree(&self) -> MerkleTree {
+        MerkleTree::new(self.transactions.iter().map(T::hash).collect())
+    }
+
+    pub fn compute_transactions_root(&self) -> Sha256Hash {
+        self.transaction_tree().root()
+    }
+
+    // what the proof of work is computed on, the transactions are only
+    // committed through their root
+    pub fn header_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        write_u32(&mut bytes, self.index)
+            .and_then(|_| write_struct(&mut bytes, &self.timestamp))
+            .and_then(|_| write_struct(&mut bytes, &self.prev_block_hash))
+            .and_then(|_| write_struct(&mut bytes, &self.target))
+            .and_then(|_| write_struct(&mut bytes, &self.transactions_root))
+            .and_then(|_| write_struct(&mut bytes, &self.state_root))
+            .ok();
+
+        bytes
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -238,22 +307,108 @@ impl BlockContent {
     }
 }
 
-impl Writable for BlockContent {
+impl BlockContent {
+    pub fn new_from_pending_transaction(
+        pending_transactions: &Vec<Transaction>,
+        merkle: &Merkle,
+        public_user: &PublicUser,
+    ) -> Self {
+        let ledger = &merkle.main().ledger;
+        let index = merkle.main().last(&merkle.blocks).content.index + 1;
+        // a slow clock must not produce a block older than its ancestors
+        let median = median_time_past(&merkle.main().index, &merkle.blocks).to_u128();
+        let timestamp = Time::from_u128(Time::now().to_u128().max(median + 1));
+
+        let mut block_content = BlockContent {
+            index: index,
+            timestamp: timestamp,
+            prev_block_hash: merkle.main().last(&merkle.blocks).hash,
+            target: merkle.main().next_target(&merkle.blocks, &merkle.params),
+            transactions_root: Sha256Hash::zero(),
+            state_root: Sha256Hash::zero(),
+            transactions: vec![],
+        };
+        // the room left by the coinbase, counted with the largest reward it may pay
+        let mut max_reward = merkle.params.halving.subsidy(index);
+        for transaction in pending_transactions {
+            max_reward += transaction.content.fee.clone();
+        }
+        let mut size = block_content.size()
+            + Transaction::new_from_coinbase(public_user, &max_reward)
+                .to_bytes()
+                .len();
+
+        let mut partial_ledger = PartialLedger::empty();
+        for transaction in pending_transactions {
+            if !ledger.contains(transaction)
+                && transaction.is_unlocked_at(index, &timestamp)
+                && !transaction.is_expired_at(index)
+                && transaction.verify_script(index, &timestamp).is_ok()
+            {
+                // stops at the first transaction that does not fit, skipping
+                // it would leave a gap in the sequences of its sender
+                let transaction_size = transaction.to_bytes().len();
+                if size + transaction_size > merkle.params.max_block_size {
+                    break;
+                }
+                // a claim whose hash lock timed out is skipped, and the later
+                // transactions of its sender with it
+                if partial_ledger
+                    .apply_transaction(ledger, transaction, index)
+                    .is_err()
+                {
+                    continue;
+                }
+                size += transaction_size;
+                block_content.transactions.push(transaction.clone());
+            }
+        }
+
+        let mut reward = merkle.params.halving.subsidy(index);
+        reward += block_content.fees();
+        block_content
+            .transactions
+            .insert(0, Transaction::new_from_coinbase(public_user, &reward));
+        block_content.transactions_root = block_content.compute_transactions_root();
+        block_content.set_state_root(ledger);
+
+        block_content
+    }
+
+    // sum of the fees paid by the transactions of the block, owed to its miner
+    pub fn fees(&self) -> Num {
+        let mut fees = Num::zero();
+        for transaction in &self.transactions {
+            if !transaction.content.from.is_coinbase() {
+                fees += transaction.content.fee.clone();
+            }
+        }
+        fees
+    }
+}
+
+impl<T: BlockTransaction> Writable for BlockContent<T> {
     fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
-        write_u32(writer, self.index as u32)
+        write_u32(writer, self.index)
             .and_then(|_| write_struct(writer, &self.timestamp))
             .and_then(|_| write_struct(writer, &self.prev_block_hash))
+            .and_then(|_| write_struct(writer, &self.target))
+            .and_then(|_| write_struct(writer, &self.transactions_root))
+            .and_then(|_| write_struct(writer, &self.state_root))
             .and_then(|_| write_vec_struct(writer, &self.transactions))
     }
 }
 
-impl Readable for BlockContent {
+impl<T: BlockTransaction> Readable for BlockContent<T> {
     fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
         let mut block_content = BlockContent::zero();
 
         match read_u32(reader, &mut block_content.index)
             .and_then(|_| read_struct(reader, &mut block_content.timestamp))
             .and_then(|_| read_struct(reader, &mut block_content.prev_block_hash))
+            .and_then(|_| read_struct(reader, &mut block_content.target))
+            .and_then(|_| read_struct(reader, &mut block_content.transactions_root))
+            .and_then(|_| read_struct(reader, &mut block_content.state_root))
             .and_then(|_| read_vec_struct(reader, &mut block_content.transactions))
         {
             Ok(_) => (),
@@ -264,20 +419,23 @@ impl Readable for BlockContent {
     }
 }
 
-impl PartialEq for Block {
+impl<T: BlockTransaction> PartialEq for Block<T> {
     fn eq(&self, other: &Self) -> bool {
         self.content == other.content
             && self.proof_of_work == other.proof_of_work
             && self.hash == other.hash
     }
 }
-impl Eq for Block {}
+impl<T: BlockTransaction> Eq for Block<T> {}
 
-impl PartialEq for BlockContent {
+impl<T: BlockTransaction> PartialEq for BlockContent<T> {
     fn eq(&self, other: &Self) -> bool {
         if !(self.index == other.index
             && self.timestamp == other.timestamp
-            && self.prev_block_hash == other.prev_block_hash)
+            && self.prev_block_hash == other.prev_block_hash
+            && self.target == other.target
+            && self.transactions_root == other.transactions_root
+            && self.state_root == other.state_root)
         {
             return false;
         }
@@ -291,4 +449,4 @@ impl PartialEq for BlockContent {
         return true;
     }
 }
-impl Eq for BlockContent {}
+impl<T: BlockTransaction> Eq for BlockContent<T> {}
@@ -1,45 +1,76 @@
+use std::cmp::Ordering;
 use std::io::Read;
 use std::io::Write;
 
+use num::{BigUint, Zero};
+
+use crate::consensus::{expected_target, BlockContext, Params, Rejection, RuleSet};
 use crate::error::Error;
 use crate::ledger::Ledger;
-use crate::merkle::Merkle;
-use crate::reader::{read_struct, read_vec_u32, Readable};
+use crate::reader::{read_big_uint, read_struct, read_vec_u32, Readable};
 use crate::sha256::Sha256Hash;
-use crate::writer::{write_struct, write_vec_u32, Writable};
+use crate::state::StateModel;
+use crate::target::Target;
+use crate::time::Time;
+use crate::writer::{write_big_uint, write_struct, write_vec_u32, Writable};
 
 use super::block::Block;
 use super::mining::Miner;
 
 #[derive(Clone, PartialEq, Eq)]
-pub struct Blockchain {
+pub struct Blockchain<S = Ledger> {
     pub index: Vec<u32>,
-    pub ledger: Ledger,
+    pub ledger: S,     // state once the blocks of the branch are applied
+    pub work: BigUint, // cumulative work of the blocks of the branch
 }
 
-impl Blockchain {
+impl<S: StateModel> Blockchain<S> {
     pub fn zero() -> Self {
         Blockchain {
             index: vec![],
-            ledger: Ledger::empty(),
+            ledger: S::empty(),
+            work: BigUint::zero(),
         }
     }
 
-    pub fn new(index: Vec<u32>, ledger: Ledger) -> Self {
+    pub fn new(index: Vec<u32>, ledger: S, work: BigUint) -> Self {
         Blockchain {
             index: index,
             ledger: ledger,
+            work: work,
+        }
+    }
+
+    pub fn compute_work(index: &[u32], blocks: &[Block<S::Transaction>]) -> BigUint {
+        let mut work = BigUint::zero();
+        for i in index {
+            work += blocks[*i as usize].work();
+        }
+        work
+    }
+
+    // the branch with the most work wins, ties go to the lowest tip hash so
+    // that every node ends up on the same branch whatever the arrival order
+    pub fn is_better_than(&self, other: &Self, blocks: &[Block<S::Transaction>]) -> bool {
+        match self.work.cmp(&other.work) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => self.last(blocks).hash.as_u128() < other.last(blocks).hash.as_u128(),
         }
     }
 
     pub fn len(&self) -> usize {
         self.index.len()
     }
-    pub fn verify(&self, blocks: &Vec<Block>) -> Result<(), Error> {
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+    pub fn verify(&self, blocks: &[Block<S::Transaction>], params: &Params) -> Result<(), Error> {
         let mut last_index: u32 = 0;
         let mut last_hash: Sha256Hash = Sha256Hash::zero();
 
-        for i in self.index.iter() {
+        for (height, i) in self.index.iter().enumerate() {
             let block = &blocks[*i as usize];
 
             if block.is_genesis() && last_index == 0 {
@@ -48,20 +79,82 @@ impl Blockchain {
 
             if block.content.index != last_index + 1 {
                 return Err(Error::BlockIndexAreNotContiguous);
-            } else if !Miner::check_difficulty(&block.hash, Merkle::DIFFICULTY) {
+            } else if block.content.target != expected_target(&self.index[..height], blocks, params)
+            {
+                return Err(Error::BlockTargetIsInvalid);
+            } else if !Miner::check_difficulty(&block.hash, &block.content.target) {
                 return Err(Error::BlockProofOfWorkIsNotDone);
             } else if block.content.prev_block_hash != last_hash {
                 return Err(Error::BlockPrevHashDoesNotMatch);
+            } else if block.content.transactions_root != block.content.compute_transactions_root() {
+                return Err(Error::BlockTransactionsRootIsInvalid);
             }
 
             last_index += 1;
             last_hash = block.hash;
         }
 
+        if self.work != Self::compute_work(&self.index, blocks) {
+            return Err(Error::ChainWorkDoesNotMatch);
+        }
+
         Ok(())
     }
 
-    pub fn last<'a>(&self, blocks: &'a Vec<Block>) -> &'a Block {
+    // Replays the branch from the genesis with the consensus rules, then
+    // checks the stored ledger and work against the replay. Unlike `verify`
+    // it trusts nothing but the blocks.
+    pub fn verify_full(
+        &self,
+        blocks: &Vec<Block<S::Transaction>>,
+        rules: &RuleSet<S>,
+        params: &Params,
+    ) -> Result<(), Rejection> {
+        let now = Time::now();
+        let mut replayed = Self::zero();
+
+        for i in &self.index {
+            let block = match blocks.get(*i as usize) {
+                Some(v) => v,
+                None => return Err(Error::NotFound.into()),
+            };
+
+            if replayed.index.is_empty() {
+                if *block != Block::new_genesis() {
+                    return Err(Error::GenesisDoesNotMatch.into());
+                }
+            } else {
+                let context = BlockContext::new(blocks, &replayed, params, now);
+                match block.verify(rules, &context) {
+                    Ok(_) => (),
+                    Err(e) => return Err(e),
+                }
+                replayed.ledger = match block.verify_payload(rules, &replayed.ledger) {
+                    Ok(v) => v,
+                    Err(e) => return Err(e),
+                };
+                replayed.work += block.work();
+            }
+            replayed.index.push(*i);
+        }
+
+        if replayed.index.is_empty() {
+            return Err(Error::GenesisDoesNotMatch.into());
+        } else if replayed.ledger != self.ledger {
+            return Err(Error::LedgerDoesNotMatch.into());
+        } else if replayed.work != self.work {
+            return Err(Error::ChainWorkDoesNotMatch.into());
+        }
+
+        Ok(())
+    }
+
+    // the target the next block of this branch must be mined with
+    pub fn next_target(&self, blocks: &[Block<S::Transaction>], params: &Params) -> Target {
+        expected_target(&self.index, blocks, params)
+    }
+
+    pub fn last<'a>(&self, blocks: &'a [Block<S::Transaction>]) -> &'a Block<S::Transaction> {
         &blocks[*self.index.last().unwrap() as usize]
     }
 
@@ -78,19 +171,21 @@ impl Blockchain {
     }
 }
 
-impl Writable for Blockchain {
+impl<S: StateModel> Writable for Blockchain<S> {
     fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
-        write_vec_u32(writer, &self.index) //
+        write_vec_u32(writer, &self.index)
             .and_then(|_| write_struct(writer, &self.ledger))
+            .and_then(|_| write_big_uint(writer, &self.work))
     }
 }
 
-impl Readable for Blockchain {
+impl<S: StateModel> Readable for Blockchain<S> {
     fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
-        let mut blockchain = Blockchain::zero();
+        let mut blockchain = Self::zero();
 
         match read_vec_u32(reader, &mut blockchain.index)
             .and_then(|_| read_struct(reader, &mut blockchain.ledger))
+            .and_then(|_| read_big_uint(reader, &mut blockchain.work))
         {
             Ok(_) => (),
             Err(_) => return Err(Error::InvalidFormat),
@@ -0,0 +1,983 @@
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+use num::BigUint;
+
+use crate::block::{Block, BlockContent};
+use crate::blockchain::Blockchain;
+use crate::error::Error;
+use crate::ledger::Ledger;
+use crate::mining::Miner;
+use crate::num::Num;
+use crate::state::{BlockTransaction, StateModel};
+use crate::target::Target;
+use crate::time::Time;
+use crate::transaction::{Payment, TokenDefinition, Transaction, TransactionContent};
+
+// The block reward is divided by two every `interval` blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HalvingSchedule {
+    pub initial_subsidy: u64,
+    pub interval: u32,
+}
+
+// Tunable values of the consensus, shared by every rule.
+// The target is recomputed every `retarget_interval` blocks so that blocks are
+// found every `target_block_time` milliseconds, but never gets easier than
+// `pow_limit`. A block may not be dated more than `max_future_drift`
+// milliseconds after the clock of the node, nor take more than
+// `max_block_size` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params {
+    pub halving: HalvingSchedule,
+    pub initial_target: Target,
+    pub pow_limit: Target,
+    pub retarget_interval: u32,
+    pub target_block_time: u64,
+    pub max_future_drift: u64,
+    pub max_block_size: usize,
+}
+
+// number of blocks used to compute the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+// Everything a block rule may look at: the branch the block extends and the
+// blocks it refers to. The parent of the block is the last block of `chain`.
+pub struct BlockContext<'a, S: StateModel = Ledger> {
+    pub blocks: &'a Vec<Block<S::Transaction>>,
+    pub chain: &'a Blockchain<S>,
+    pub params: &'a Params,
+    pub now: Time, // clock of the node
+}
+
+// Everything a transaction rule may look at: the state of the branch and the
+// changes already applied by the previous transactions of the block (or mempool).
+// `block` is `None` when the transaction is checked for the mempool.
+pub struct TransactionContext<'a, S: StateModel = Ledger> {
+    pub ledger: &'a S,
+    pub partial_ledger: &'a S::Changes,
+    pub block: Option<&'a BlockContent<S::Transaction>>,
+}
+
+// A rule of the chains whose state model is `S`. The rules on the headers
+// hold for every model, the ones on the transactions are specific to one.
+pub trait Rule<S: StateModel = Ledger>: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn check_block(
+        &self,
+        _block: &Block<S::Transaction>,
+        _context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn check_transaction(
+        &self,
+        _transaction: &S::Transaction,
+        _context: &TransactionContext<S>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // `state` is the state the block leads to, once all its transactions are applied
+    fn check_state(&self, _block: &Block<S::Transaction>, _state: &S) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// The reason a block or a transaction was refused. `rule` is `None` when the
+// refusal does not come from a consensus rule (e.g. unknown parent block).
+#[derive(Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub rule: Option<&'static str>,
+    pub error: Error,
+}
+
+#[derive(Clone)]
+pub struct RuleSet<S: StateModel = Ledger> {
+    rules: Vec<Arc<dyn Rule<S>>>,
+}
+
+impl HalvingSchedule {
+    pub fn subsidy(&self, height: u32) -> Num {
+        let halvings = match self.interval {
+            0 => 0,
+            interval => height / interval,
+        };
+        if halvings >= u64::BITS {
+            return Num::zero();
+        }
+        Num::from_u64(self.initial_subsidy >> halvings)
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            halving: HalvingSchedule {
+                initial_subsidy: 10,
+                interval: 100_000,
+            },
+            initial_target: Target::initial(),
+            pow_limit: Target::from_leading_zeros(8),
+            retarget_interval: 16,
+            target_block_time: 10_000,
+            max_future_drift: 2 * 60 * 60 * 1000,
+            max_block_size: 1_000_000,
+        }
+    }
+}
+
+// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks of `chain`, the next
+// block must be dated strictly after it.
+pub fn median_time_past<T>(chain: &[u32], blocks: &[Block<T>]) -> Time {
+    let start = chain.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut timestamps: Vec<u128> = chain[start..]
+        .iter()
+        .map(|i| blocks[*i as usize].content.timestamp.to_u128())
+        .collect();
+    timestamps.sort();
+
+    Time::from_u128(timestamps[timestamps.len() / 2])
+}
+
+// The target the block following `chain` (ordered block indexes, starting by
+// the genesis) must carry.
+pub fn expected_target<T: BlockTransaction>(
+    chain: &[u32],
+    blocks: &[Block<T>],
+    params: &Params,
+) -> Target {
+    let height = chain.len() as u32;
+    let parent = &blocks[chain[chain.len() - 1] as usize];
+
+    if parent.is_genesis() {
+        return params.initial_target;
+    }
+    if params.retarget_interval == 0 || !height.is_multiple_of(params.retarget_interval) {
+        return parent.content.target;
+    }
+
+    // the genesis timestamp is meaningless, the window starts at height 1
+    let first_height = height.saturating_sub(params.retarget_interval).max(1);
+    let first = &blocks[chain[first_height as usize] as usize];
+    if first.content.index == parent.content.index {
+        return parent.content.target;
+    }
+
+    let expected_timespan =
+        params.target_block_time as u128 * (parent.content.index - first.content.index) as u128;
+    let actual_timespan = parent
+        .content
+        .timestamp
+        .to_u128()
+        .saturating_sub(first.content.timestamp.to_u128())
+        .clamp(expected_timespan / 4, expected_timespan * 4);
+
+    let target = parent.content.target.to_big_uint() * BigUint::from(actual_timespan)
+        / BigUint::from(expected_timespan.max(1));
+    if target > params.pow_limit.to_big_uint() {
+        return params.pow_limit;
+    }
+    Target::from_big_uint(&target)
+}
+
+impl<'a, S: StateModel> BlockContext<'a, S> {
+    pub fn new(
+        blocks: &'a Vec<Block<S::Transaction>>,
+        chain: &'a Blockchain<S>,
+        params: &'a Params,
+        now: Time,
+    ) -> Self {
+        BlockContext {
+            blocks: blocks,
+            chain: chain,
+            params: params,
+            now: now,
+        }
+    }
+
+    pub fn parent(&self) -> &'a Block<S::Transaction> {
+        self.chain.last(self.blocks)
+    }
+}
+
+impl<'a, S: StateModel> TransactionContext<'a, S> {
+    pub fn new(
+        ledger: &'a S,
+        partial_ledger: &'a S::Changes,
+        block: Option<&'a BlockContent<S::Transaction>>,
+    ) -> Self {
+        TransactionContext {
+            ledger: ledger,
+            partial_ledger: partial_ledger,
+            block: block,
+        }
+    }
+}
+
+impl Rejection {
+    pub fn new(rule: &'static str, error: Error) -> Self {
+        Rejection {
+            rule: Some(rule),
+            error: error,
+        }
+    }
+}
+
+impl From<Error> for Rejection {
+    fn from(error: Error) -> Self {
+        Rejection {
+            rule: None,
+            error: error,
+        }
+    }
+}
+
+impl RuleSet {
+    // The rules enforced by every node of an account chain, in the order
+    // they are checked.
+    pub fn standard() -> Self {
+        let mut rules = RuleSet::headers();
+
+        rules.push(CoinbaseValue);
+
+        rules.push(CoinbaseOnlyInBlock);
+        rules.push(LockIsReleased);
+        rules.push(NotExpired);
+        rules.push(PositiveValue);
+        rules.push(NonNegativeFee);
+        rules.push(MemoSize);
+        rules.push(TokenSymbol);
+        rules.push(KnownSender);
+        rules.push(ValidSignature);
+        rules.push(ScriptSucceeds);
+        rules.push(SequenceIsNext);
+        rules.push(SufficientFunds);
+
+        rules
+    }
+}
+
+impl<S: StateModel> RuleSet<S> {
+    pub fn new() -> Self {
+        RuleSet { rules: vec![] }
+    }
+
+    // the rules on the headers and the place of the coinbase, whatever the state model
+    pub fn headers() -> Self {
+        let mut rules = RuleSet::new();
+
+        rules.push(NotGenesis);
+        rules.push(HasTransactions);
+        rules.push(BlockSize);
+        rules.push(ContiguousIndex);
+        rules.push(PrevHashMatches);
+        rules.push(AfterMedianTimePast);
+        rules.push(NotTooFarInTheFuture);
+        rules.push(TargetMatches);
+        rules.push(ProofOfWork);
+        rules.push(TransactionsRootMatches);
+        rules.push(StateRootMatches);
+        rules.push(HashMatchesContent);
+        rules.push(CoinbaseFirst);
+        rules.push(SingleCoinbase);
+
+        rules
+    }
+
+    pub fn push<R: Rule<S> + 'static>(&mut self, rule: R) {
+        self.rules.push(Arc::new(rule));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.rules.retain(|rule| rule.name() != name);
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.rules.iter().map(|rule| rule.name()).collect()
+    }
+
+    pub fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        context: &BlockContext<S>,
+    ) -> Result<(), Rejection> {
+        for rule in &self.rules {
+            match rule.check_block(block, context) {
+                Ok(_) => (),
+                Err(e) => return Err(Rejection::new(rule.name(), e)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_transaction(
+        &self,
+        transaction: &S::Transaction,
+        context: &TransactionContext<S>,
+    ) -> Result<(), Rejection> {
+        for rule in &self.rules {
+            match rule.check_transaction(transaction, context) {
+                Ok(_) => (),
+                Err(e) => return Err(Rejection::new(rule.name(), e)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_state(&self, block: &Block<S::Transaction>, state: &S) -> Result<(), Rejection> {
+        for rule in &self.rules {
+            match rule.check_state(block, state) {
+                Ok(_) => (),
+                Err(e) => return Err(Rejection::new(rule.name(), e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: StateModel> Default for RuleSet<S> {
+    fn default() -> Self {
+        RuleSet::new()
+    }
+}
+
+impl<S: StateModel> PartialEq for RuleSet<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.names() == other.names()
+    }
+}
+impl<S: StateModel> Eq for RuleSet<S> {}
+
+impl<S: StateModel> Debug for RuleSet<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.names())
+    }
+}
+
+// The genesis block is built locally and is never received.
+pub struct NotGenesis;
+
+impl<S: StateModel> Rule<S> for NotGenesis {
+    fn name(&self) -> &'static str {
+        "not-genesis"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        _context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        if block.is_genesis() {
+            return Err(Error::VerifyingGenesisBlock);
+        }
+        Ok(())
+    }
+}
+
+pub struct HasTransactions;
+
+impl<S: StateModel> Rule<S> for HasTransactions {
+    fn name(&self) -> &'static str {
+        "has-transactions"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        _context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        if block.content.transactions.is_empty() {
+            return Err(Error::BlockContainsNoTransaction);
+        }
+        Ok(())
+    }
+}
+
+// The transactions are counted with everything they carry, memos included.
+pub struct BlockSize;
+
+impl<S: StateModel> Rule<S> for BlockSize {
+    fn name(&self) -> &'static str {
+        "block-size"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        if block.content.size() > context.params.max_block_size {
+            return Err(Error::BlockIsTooLarge);
+        }
+        Ok(())
+    }
+}
+
+pub struct ProofOfWork;
+
+impl<S: StateModel> Rule<S> for ProofOfWork {
+    fn name(&self) -> &'static str {
+        "proof-of-work"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        _context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        if !Miner::check_difficulty(&block.hash, &block.content.target) {
+            return Err(Error::BlockProofOfWorkIsNotDone);
+        }
+        Ok(())
+    }
+}
+
+pub struct AfterMedianTimePast;
+
+impl<S: StateModel> Rule<S> for AfterMedianTimePast {
+    fn name(&self) -> &'static str {
+        "after-median-time-past"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        let median = median_time_past(&context.chain.index, context.blocks);
+        if block.content.timestamp.to_u128() <= median.to_u128() {
+            return Err(Error::BlockTimestampIsTooOld);
+        }
+        Ok(())
+    }
+}
+
+pub struct NotTooFarInTheFuture;
+
+impl<S: StateModel> Rule<S> for NotTooFarInTheFuture {
+    fn name(&self) -> &'static str {
+        "not-too-far-in-the-future"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        let limit = context.now.to_u128() + context.params.max_future_drift as u128;
+        if block.content.timestamp.to_u128() > limit {
+            return Err(Error::BlockTimestampIsTooFarInTheFuture);
+        }
+        Ok(())
+    }
+}
+
+// The target of a block is not chosen by its miner but by the timestamps of the
+// branch it extends.
+pub struct TargetMatches;
+
+impl<S: StateModel> Rule<S> for TargetMatches {
+    fn name(&self) -> &'static str {
+        "target-matches"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        let expected = expected_target(&context.chain.index, context.blocks, context.params);
+        if block.content.target != expected {
+            return Err(Error::BlockTargetIsInvalid);
+        }
+        Ok(())
+    }
+}
+
+// the hash only covers the header, the transactions are bound to it by their root
+pub struct TransactionsRootMatches;
+
+impl<S: StateModel> Rule<S> for TransactionsRootMatches {
+    fn name(&self) -> &'static str {
+        "transactions-root-matches"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        _context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        if block.content.transactions_root != block.content.compute_transactions_root() {
+            return Err(Error::BlockTransactionsRootIsInvalid);
+        }
+        Ok(())
+    }
+}
+
+// the state root commits to the state after the block, so it is checked once
+// the transactions are applied
+pub struct StateRootMatches;
+
+impl<S: StateModel> Rule<S> for StateRootMatches {
+    fn name(&self) -> &'static str {
+        "state-root-matches"
+    }
+
+    fn check_state(&self, block: &Block<S::Transaction>, state: &S) -> Result<(), Error> {
+        if state.state_root() != block.content.state_root {
+            return Err(Error::BlockStateRootIsInvalid);
+        }
+        Ok(())
+    }
+}
+
+pub struct HashMatchesContent;
+
+impl<S: StateModel> Rule<S> for HashMatchesContent {
+    fn name(&self) -> &'static str {
+        "hash-matches-content"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        _context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        if block.hash != block.hash() {
+            return Err(Error::BlockHashIsInvalid);
+        }
+        Ok(())
+    }
+}
+
+pub struct ContiguousIndex;
+
+impl<S: StateModel> Rule<S> for ContiguousIndex {
+    fn name(&self) -> &'static str {
+        "contiguous-index"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        if context.parent().content.index + 1 != block.content.index {
+            return Err(Error::BlockIndexAreNotContiguous);
+        }
+        Ok(())
+    }
+}
+
+pub struct PrevHashMatches;
+
+impl<S: StateModel> Rule<S> for PrevHashMatches {
+    fn name(&self) -> &'static str {
+        "prev-hash-matches"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        if context.parent().hash != block.content.prev_block_hash {
+            return Err(Error::BlockPrevHashDoesNotMatch);
+        }
+        Ok(())
+    }
+}
+
+// A block pays its miner through exactly one coinbase transaction placed first.
+pub struct CoinbaseFirst;
+
+impl<S: StateModel> Rule<S> for CoinbaseFirst {
+    fn name(&self) -> &'static str {
+        "coinbase-first"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        _context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        match block.content.transactions.first() {
+            Some(transaction) if transaction.is_coinbase() => Ok(()),
+            _ => Err(Error::CoinbaseIsNotFirst),
+        }
+    }
+}
+
+pub struct SingleCoinbase;
+
+impl<S: StateModel> Rule<S> for SingleCoinbase {
+    fn name(&self) -> &'static str {
+        "single-coinbase"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<S::Transaction>,
+        _context: &BlockContext<S>,
+    ) -> Result<(), Error> {
+        let count = block
+            .content
+            .transactions
+            .iter()
+            .filter(|transaction| transaction.is_coinbase())
+            .count();
+        if count > 1 {
+            return Err(Error::BlockHasSeveralCoinbase);
+        }
+        Ok(())
+    }
+}
+
+pub struct CoinbaseValue;
+
+impl Rule for CoinbaseValue {
+    fn name(&self) -> &'static str {
+        "coinbase-value"
+    }
+
+    fn check_block(&self, block: &Block, context: &BlockContext) -> Result<(), Error> {
+        let coinbase = match block.content.transactions.first() {
+            Some(transaction) => transaction,
+            None => return Err(Error::CoinbaseIsNotFirst),
+        };
+        let mut expected = context.params.halving.subsidy(block.content.index);
+        expected += block.content.fees();
+        if coinbase.value() != expected {
+            return Err(Error::CoinbaseValueIsInvalid);
+        }
+        Ok(())
+    }
+}
+
+// Money can only be created by the miner of a block, never relayed.
+pub struct CoinbaseOnlyInBlock;
+
+impl Rule for CoinbaseOnlyInBlock {
+    fn name(&self) -> &'static str {
+        "coinbase-only-in-block"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        context: &TransactionContext,
+    ) -> Result<(), Error> {
+        if transaction.content.from.is_coinbase() && context.block.is_none() {
+            return Err(Error::CoinbaseOutsideOfBlock);
+        }
+        Ok(())
+    }
+}
+
+// A locked transaction waits for its height or time. The mempool does not
+// know the block yet, `NodeCache` holds the locked transactions instead.
+pub struct LockIsReleased;
+
+impl Rule for LockIsReleased {
+    fn name(&self) -> &'static str {
+        "lock-is-released"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        context: &TransactionContext,
+    ) -> Result<(), Error> {
+        match context.block {
+            Some(block) if !transaction.is_unlocked_at(block.index, &block.timestamp) => {
+                Err(Error::TransactionIsLocked)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// An expired transaction is evicted from the mempool by `NodeCache`.
+pub struct NotExpired;
+
+impl Rule for NotExpired {
+    fn name(&self) -> &'static str {
+        "not-expired"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        context: &TransactionContext,
+    ) -> Result<(), Error> {
+        match context.block {
+            Some(block) if transaction.is_expired_at(block.index) => {
+                Err(Error::TransactionIsExpired)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct MemoSize;
+
+impl Rule for MemoSize {
+    fn name(&self) -> &'static str {
+        "memo-size"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        _context: &TransactionContext,
+    ) -> Result<(), Error> {
+        if transaction.content.memo.len() > TransactionContent::MAX_MEMO {
+            return Err(Error::MemoIsTooLong);
+        }
+        Ok(())
+    }
+}
+
+// A token is created with a short ascii symbol, see `TokenDefinition`.
+pub struct TokenSymbol;
+
+impl Rule for TokenSymbol {
+    fn name(&self) -> &'static str {
+        "token-symbol"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        _context: &TransactionContext,
+    ) -> Result<(), Error> {
+        match &transaction.content.payment {
+            Payment::CreateToken(definition) if !definition.has_valid_symbol() => {
+                Err(Error::InvalidTokenSymbol)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct NonNegativeFee;
+
+impl Rule for NonNegativeFee {
+    fn name(&self) -> &'static str {
+        "non-negative-fee"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        _context: &TransactionContext,
+    ) -> Result<(), Error> {
+        if transaction.content.fee < Num::zero() {
+            return Err(Error::NegativeFee);
+        }
+        Ok(())
+    }
+}
+
+// The total of the coinbase is checked by `CoinbaseValue`, it may be zero
+// once the subsidy is exhausted.
+pub struct PositiveValue;
+
+impl Rule for PositiveValue {
+    fn name(&self) -> &'static str {
+        "positive-value"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        _context: &TransactionContext,
+    ) -> Result<(), Error> {
+        // each output, not only their sum: a batch cannot debit a recipient
+        if transaction.content.from.is_coinbase() {
+            if transaction.outputs().iter().any(|o| o.value < Num::zero()) {
+                return Err(Error::TransactionOf0);
+            }
+            return Ok(());
+        }
+        match &transaction.content.payment {
+            Payment::HashLock(lock) if lock.value <= Num::zero() => {
+                return Err(Error::TransactionOf0)
+            }
+            // claims and refunds move the value of their lock, only the fee is paid
+            Payment::HashLock(_) | Payment::Claim { .. } | Payment::Refund { .. } => return Ok(()),
+            Payment::CreateToken(TokenDefinition { max_supply, .. })
+                if *max_supply <= Num::zero() =>
+            {
+                return Err(Error::TransactionOf0)
+            }
+            Payment::Mint { output, .. } | Payment::TokenTransfer { output, .. }
+                if output.value <= Num::zero() =>
+            {
+                return Err(Error::TransactionOf0)
+            }
+            // the native coin only pays the fee
+            Payment::CreateToken(_) | Payment::Mint { .. } | Payment::TokenTransfer { .. } => {
+                return Ok(())
+            }
+            _ => (),
+        }
+        if transaction.outputs().is_empty() {
+            return Err(Error::TransactionOf0);
+        }
+        for output in transaction.outputs() {
+            if output.value <= Num::zero() {
+                return Err(Error::TransactionOf0);
+            }
+        }
+        Ok(())
+    }
+}
+
+// A claim may open the account of its sender, see `PartialLedger::get_sender_data`.
+pub struct KnownSender;
+
+impl Rule for KnownSender {
+    fn name(&self) -> &'static str {
+        "known-sender"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        context: &TransactionContext,
+    ) -> Result<(), Error> {
+        if transaction.content.from.is_coinbase() {
+            return Ok(());
+        }
+        match context
+            .partial_ledger
+            .get_sender_data(context.ledger, transaction)
+        {
+            Some(_) => Ok(()),
+            None => Err(Error::TryingToSendMoneyFromUnknowUser),
+        }
+    }
+}
+
+// The coinbase has no key, every other sender must have signed the content.
+pub struct ValidSignature;
+
+impl Rule for ValidSignature {
+    fn name(&self) -> &'static str {
+        "valid-signature"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        _context: &TransactionContext,
+    ) -> Result<(), Error> {
+        if transaction.content.from.is_coinbase() {
+            return Ok(());
+        }
+        if !transaction.verify_signature() {
+            return Err(Error::WrongTransactionSignature);
+        }
+        Ok(())
+    }
+}
+
+// An account locked under a script is spent when the script succeeds in the
+// block. `NodeCache` runs it against the next block for the mempool.
+pub struct ScriptSucceeds;
+
+impl Rule for ScriptSucceeds {
+    fn name(&self) -> &'static str {
+        "script-succeeds"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        context: &TransactionContext,
+    ) -> Result<(), Error> {
+        match context.block {
+            Some(block) => transaction.verify_script(block.index, &block.timestamp),
+            None => Ok(()),
+        }
+    }
+}
+
+// Replay protection: every transaction of a sender carries the sequence of
+// the previous one plus one.
+pub struct SequenceIsNext;
+
+impl Rule for SequenceIsNext {
+    fn name(&self) -> &'static str {
+        "sequence-is-next"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        context: &TransactionContext,
+    ) -> Result<(), Error> {
+        if transaction.content.from.is_coinbase() {
+            return Ok(());
+        }
+        let user_data = match context
+            .partial_ledger
+            .get_sender_data(context.ledger, transaction)
+        {
+            Some(user_data) => user_data,
+            None => return Ok(()), // reported by `KnownSender`
+        };
+        if transaction.content.sequence <= user_data.sequence {
+            return Err(Error::TransactionWasAlreadyDone);
+        }
+        if transaction.content.sequence != user_data.next_sequence() {
+            return Err(Error::TransactionSequenceIsNotNext);
+        }
+        Ok(())
+    }
+}
+
+pub struct SufficientFunds;
+
+impl Rule for SufficientFunds {
+    fn name(&self) -> &'static str {
+        "sufficient-funds"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &Transaction,
+        context: &TransactionContext,
+    ) -> Result<(), Error> {
+        if transaction.content.from.is_coinbase() {
+            return Ok(());
+        }
+        let user_data = match context
+            .partial_ledger
+            .get_sender_data(context.ledger, transaction)
+        {
+            Some(user_data) => user_data,
+            None => return Err(Error::SenderDoNotHaveEnoughMoney),
+        };
+        // a claim or a refund pays its fee out of the released value
+        let mut money = user_data.money.clone();
+        money += context
+            .partial_ledger
+            .released_value(context.ledger, transaction);
+        if money < transaction.cost() {
+            return Err(Error::SenderDoNotHaveEnoughMoney);
+        }
+        match &transaction.content.payment {
+            Payment::TokenTransfer { token, output }
+                if user_data.token_balance(token) < output.value =>
+            {
+                Err(Error::NotEnoughTokens)
+            }
+            _ => Ok(()),
+        }
+    }
+}
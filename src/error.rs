@@ -1,21 +1,61 @@
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     PreviousBlockNotFound,
+    TooManyOrphansFromPeer,
     BlockExistButIsNotInAnyBranch,
 
     VerifyingGenesisBlock,
     BlockContainsNoTransaction,
+    BlockIsTooLarge,
     BlockProofOfWorkIsNotDone,
+    BlockTargetIsInvalid,
+    BlockTimestampIsTooOld,
+    BlockTimestampIsTooFarInTheFuture,
     BlockHashIsInvalid,
+    BlockTransactionsRootIsInvalid,
+    BlockStateRootIsInvalid,
     BlockAlreadyExist,
+    DuplicateBlockInTree,
     BlockIndexAreNotContiguous,
     BlockPrevHashDoesNotMatch,
+    ChainWorkDoesNotMatch,
+    GenesisDoesNotMatch,
+    LedgerDoesNotMatch,
+    MainBranchIsNotTheBest,
+    CoinbaseIsNotFirst,
+    BlockHasSeveralCoinbase,
+    CoinbaseValueIsInvalid,
+    CoinbaseHeightIsInvalid,
 
     TransactionWasAlreadyDone,
+    TransactionSequenceIsNotNext,
     TryingToSendMoneyFromUnknowUser,
     TransactionOf0,
+    NegativeFee,
     WrongTransactionSignature,
+    InvalidMultisigAccount,
+    NotEnoughSignatures,
+    InvalidScript,
+    ScriptFailed,
+    ScriptLimitExceeded,
     SenderDoNotHaveEnoughMoney,
+    CoinbaseOutsideOfBlock,
+    TransactionIsLocked,
+    TransactionIsExpired,
+    MemoIsTooLong,
+    UnknownHashLock,
+    WrongPreimage,
+    HashLockIsExpired,
+    HashLockIsNotExpired,
+    NotTheHashLockParty,
+    HashLockFromCoinbase,
+    UnknownToken,
+    NotTheTokenIssuer,
+    TokenSupplyExceeded,
+    NotEnoughTokens,
+    InvalidTokenSymbol,
+    TokenFromCoinbase,
+    UnknownOutput,
 
     TcpListenerBind,
     TcpStreamConnect,
@@ -1,30 +1,103 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::fmt::{self, Debug, Formatter};
 use std::io::Read;
 use std::io::Write;
 use std::vec;
 
 use crate::block::Block;
+use crate::consensus::RuleSet;
 use crate::error::Error;
+use crate::merkle_tree::{MerkleProof, SparseMerkleTree};
 use crate::num::Num;
-use crate::reader::{read_struct, read_vec_struct, read_vec_u64, Readable};
-use crate::transaction::Transaction;
+use crate::reader::{read_struct, read_u64, read_vec_len, read_vec_struct, Readable};
+use crate::sha256::Sha256Hash;
+use crate::state::StateModel;
+use crate::transaction::{HashLock, Payment, TokenDefinition, Transaction};
 use crate::user::PublicUser;
-use crate::writer::{write_struct, write_vec_struct, write_vec_u64, Writable};
+use crate::writer::{write_struct, write_u32, write_u64, write_vec_struct, Writable};
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct UserData {
     pub user: PublicUser,
     pub money: Num,
+    pub sequence: u64, // sequence of the last transaction sent by the user
+    // balance of each token held, by token id; an emptied balance is removed
+    pub tokens: HashMap<Sha256Hash, Num>,
+}
+// Money held by a hash lock until it is claimed or refunded, `id` is the
+// content hash of the transaction that locked it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockedFunds {
+    pub id: Sha256Hash,
+    pub sender: PublicUser,
+    pub lock: HashLock,
 }
+
+// A token created by `issuer`, `id` is the content hash of the transaction
+// that created it and `supply` the amount minted so far.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub id: Sha256Hash,
+    pub issuer: PublicUser,
+    pub definition: TokenDefinition,
+    pub supply: Num,
+}
+
+// The accounts are serialized sorted by user, so that the same state always
+// gives the same bytes whatever the order the accounts were created in.
+// The map is persistent: a clone shares every account with the original and
+// only the accounts modified afterwards are copied.
+// The maps are modified through the `set_` methods, which keep the state
+// tree in step with them.
 #[derive(Clone, PartialEq, Eq)]
 pub struct Ledger {
-    pub users: Vec<UserData>,
-    pub nonces_transaction: Vec<u64>,
+    pub users: im::HashMap<PublicUser, UserData>,
+    pub hash_locks: im::HashMap<Sha256Hash, LockedFunds>,
+    pub tokens: im::HashMap<Sha256Hash, Token>,
+    state_tree: SparseMerkleTree, // not serialized, rebuilt from the maps
+}
+
+// The accounts, hash locks and tokens a block modified, as they were before
+// the block (`None` for the ones it created). Reverting it walks a branch
+// back by one block.
+#[derive(Clone, PartialEq, Eq)]
+pub struct LedgerUndo {
+    pub users: Vec<(PublicUser, Option<UserData>)>,
+    pub hash_locks: Vec<(Sha256Hash, Option<LockedFunds>)>,
+    pub tokens: Vec<(Sha256Hash, Option<Token>)>,
+}
+
+// Proves the balance and sequence of one account against a state root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BalanceProof {
+    pub user_data: UserData,
+    pub proof: MerkleProof,
 }
 
+// `None` in `hash_locks` is a lock spent by the block.
 pub struct PartialLedger {
-    pub users: Vec<UserData>,
-    pub nonces_transaction: Vec<u64>,
+    pub users: HashMap<PublicUser, UserData>,
+    pub hash_locks: HashMap<Sha256Hash, Option<LockedFunds>>,
+    pub tokens: HashMap<Sha256Hash, Token>,
+}
+
+// Slots of the entries in the state tree, each kind of entry hashes its key
+// with its own prefix.
+const USER_SLOT: u8 = 0;
+const HASH_LOCK_SLOT: u8 = 1;
+const TOKEN_SLOT: u8 = 2;
+
+fn user_slot(user: &PublicUser) -> Sha256Hash {
+    Sha256Hash::new(&[&vec![USER_SLOT], &user.to_bytes().to_vec()])
+}
+
+fn hash_lock_slot(id: &Sha256Hash) -> Sha256Hash {
+    Sha256Hash::new(&[&vec![HASH_LOCK_SLOT], &id.to_bytes().to_vec()])
+}
+
+fn token_slot(id: &Sha256Hash) -> Sha256Hash {
+    Sha256Hash::new(&[&vec![TOKEN_SLOT], &id.to_bytes().to_vec()])
 }
 
 impl UserData {
@@ -32,56 +105,243 @@ impl UserData {
         UserData {
             user: user,
             money: Num::zero(),
+            sequence: 0,
+            tokens: HashMap::new(),
+        }
+    }
+
+    // the sequence the next transaction of the user must carry
+    pub fn next_sequence(&self) -> u64 {
+        self.sequence + 1
+    }
+
+    pub fn token_balance(&self, token: &Sha256Hash) -> Num {
+        match self.tokens.get(token) {
+            Some(balance) => balance.clone(),
+            None => Num::zero(),
+        }
+    }
+
+    pub fn credit_token(&mut self, token: Sha256Hash, value: Num) {
+        *self.tokens.entry(token).or_insert_with(Num::zero) += value;
+    }
+
+    // the caller checks the balance beforehand
+    pub fn debit_token(&mut self, token: Sha256Hash, value: Num) {
+        let mut balance = self.token_balance(&token);
+        balance -= value;
+        if balance == Num::zero() {
+            self.tokens.remove(&token);
+        } else {
+            self.tokens.insert(token, balance);
         }
     }
+
+    // the token balances in serialization order
+    pub fn sorted_tokens(&self) -> Vec<(&Sha256Hash, &Num)> {
+        let mut tokens: Vec<(&Sha256Hash, &Num)> = self.tokens.iter().collect();
+        tokens.sort_by_key(|(id, _)| id.to_bytes());
+        tokens
+    }
 }
 
 impl Ledger {
     pub fn empty() -> Self {
         Ledger {
-            users: vec![],
-            nonces_transaction: vec![],
+            users: im::HashMap::new(),
+            hash_locks: im::HashMap::new(),
+            tokens: im::HashMap::new(),
+            state_tree: SparseMerkleTree::new(),
         }
     }
 
-    pub fn new_unsafe(chain: &Vec<u32>, blocks: &Vec<Block>) -> Self {
-        let ledger = Ledger::empty();
-        let mut partial_ledger = PartialLedger::empty();
+    pub fn new_unsafe(chain: &[u32], blocks: &[Block]) -> Self {
+        let mut ledger = Ledger::empty();
 
         for i in chain {
             let block = &blocks[*i as usize];
-
-            for transaction in &block.content.transactions {
-                partial_ledger.apply_transaction(&ledger, &transaction).ok();
-            }
+            ledger = ledger.apply_unsafe(&block.content.transactions, block.content.index);
         }
-        partial_ledger.to_ledger(&ledger)
+        ledger
     }
 
+    // whether the sender already used the sequence of the transaction
     pub fn contains(&self, transaction: &Transaction) -> bool {
-        if self.nonces_transaction.contains(&transaction.content.nonce) {
-            return true;
+        if transaction.content.from.is_coinbase() {
+            return false;
+        }
+        match self.get_user_data(transaction.content.from) {
+            Some(user_data) => transaction.content.sequence <= user_data.sequence,
+            None => false,
         }
-        return false;
     }
 
-    pub fn find_user_data(&self, public_user: PublicUser) -> Option<usize> {
-        for (i, elem) in self.users.iter().enumerate() {
-            if elem.user == public_user {
-                return Some(i);
-            }
+    pub fn get_user_data(&self, public_user: PublicUser) -> Option<&UserData> {
+        self.users.get(&public_user)
+    }
+
+    // `None` removes the account
+    pub fn set_user_data(&mut self, public_user: PublicUser, user_data: Option<UserData>) {
+        let leaf = user_data.as_ref().map(UserData::hash);
+        self.state_tree.set(&user_slot(&public_user), leaf.as_ref());
+        match user_data {
+            Some(user_data) => self.users.insert(public_user, user_data),
+            None => self.users.remove(&public_user),
+        };
+    }
+
+    pub fn set_hash_lock(&mut self, id: Sha256Hash, locked_funds: Option<LockedFunds>) {
+        let leaf = locked_funds.as_ref().map(LockedFunds::hash);
+        self.state_tree.set(&hash_lock_slot(&id), leaf.as_ref());
+        match locked_funds {
+            Some(locked_funds) => self.hash_locks.insert(id, locked_funds),
+            None => self.hash_locks.remove(&id),
+        };
+    }
+
+    pub fn set_token(&mut self, id: Sha256Hash, token: Option<Token>) {
+        let leaf = token.as_ref().map(Token::hash);
+        self.state_tree.set(&token_slot(&id), leaf.as_ref());
+        match token {
+            Some(token) => self.tokens.insert(id, token),
+            None => self.tokens.remove(&id),
+        };
+    }
+
+    pub fn get_hash_lock(&self, id: &Sha256Hash) -> Option<&LockedFunds> {
+        self.hash_locks.get(id)
+    }
+
+    pub fn get_token(&self, id: &Sha256Hash) -> Option<&Token> {
+        self.tokens.get(id)
+    }
+
+    // whether the transaction creates or claims a hash lock after its timeout
+    pub fn is_past_hash_lock_timeout(&self, transaction: &Transaction, index: u32) -> bool {
+        match &transaction.content.payment {
+            Payment::HashLock(lock) => index > lock.timeout,
+            Payment::Claim { lock, .. } => match self.get_hash_lock(lock) {
+                Some(locked_funds) => index > locked_funds.lock.timeout,
+                None => false,
+            },
+            _ => false,
         }
-        return None;
     }
 
-    pub fn find_or_create_user_data(&mut self, public_user: PublicUser) -> usize {
-        match self.find_user_data(public_user) {
-            Some(i) => return i,
-            None => {
-                self.users.push(UserData::new(public_user));
-                return self.users.len() - 1;
-            }
+    pub fn revert(&mut self, undo: &LedgerUndo) {
+        for (user, user_data) in &undo.users {
+            self.set_user_data(*user, user_data.clone());
+        }
+        for (id, locked_funds) in &undo.hash_locks {
+            self.set_hash_lock(*id, locked_funds.clone());
         }
+        for (id, token) in &undo.tokens {
+            self.set_token(*id, token.clone());
+        }
+    }
+
+    // the accounts in serialization order
+    pub fn sorted_users(&self) -> Vec<&UserData> {
+        let mut users: Vec<&UserData> = self.users.values().collect();
+        users.sort_by_key(|user_data| user_data.user);
+        users
+    }
+
+    // the hash locks in serialization order
+    pub fn sorted_hash_locks(&self) -> Vec<&LockedFunds> {
+        let mut hash_locks: Vec<&LockedFunds> = self.hash_locks.values().collect();
+        hash_locks.sort_by_key(|locked_funds| locked_funds.id.to_bytes());
+        hash_locks
+    }
+
+    // the tokens in serialization order
+    pub fn sorted_tokens(&self) -> Vec<&Token> {
+        let mut tokens: Vec<&Token> = self.tokens.values().collect();
+        tokens.sort_by_key(|token| token.id.to_bytes());
+        tokens
+    }
+
+    // Commitment to every account, hash lock and token: the root of a sparse
+    // Merkle tree where each of them is a leaf in the slot of its key. Only
+    // the entries a block changes, or an undo record restores, are rehashed.
+    // The empty ledger has the zero root.
+    pub fn state_root(&self) -> Sha256Hash {
+        self.state_tree.root()
+    }
+
+    pub fn balance_proof(&self, public_user: PublicUser) -> Option<BalanceProof> {
+        self.get_user_data(public_user)
+            .map(|user_data| BalanceProof {
+                user_data: user_data.clone(),
+                proof: self.state_tree.proof(&user_slot(&public_user)),
+            })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+impl StateModel for Ledger {
+    type Transaction = Transaction;
+    type Changes = PartialLedger;
+    type Undo = LedgerUndo;
+
+    fn empty() -> Self {
+        Ledger::empty()
+    }
+
+    fn standard_rules() -> RuleSet {
+        RuleSet::standard()
+    }
+
+    fn empty_changes() -> PartialLedger {
+        PartialLedger::empty()
+    }
+
+    fn apply_transaction(
+        &self,
+        changes: &mut PartialLedger,
+        transaction: &Transaction,
+        index: u32,
+    ) -> Result<(), Error> {
+        changes.apply_transaction(self, transaction, index)
+    }
+
+    fn apply_changes(&self, changes: &PartialLedger) -> Self {
+        changes.to_ledger(self)
+    }
+
+    fn undo(&self, changes: &PartialLedger) -> LedgerUndo {
+        changes.undo(self)
+    }
+
+    fn revert(&mut self, undo: &LedgerUndo) {
+        Ledger::revert(self, undo)
+    }
+
+    fn state_root(&self) -> Sha256Hash {
+        Ledger::state_root(self)
+    }
+}
+
+impl BalanceProof {
+    pub fn verify(&self, state_root: &Sha256Hash) -> bool {
+        SparseMerkleTree::verify(
+            &self.proof,
+            &user_slot(&self.user_data.user),
+            &self.user_data.hash(),
+            state_root,
+        )
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -98,6 +358,31 @@ impl Ledger {
     }
 }
 
+impl Writable for BalanceProof {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.user_data) //
+            .and_then(|_| write_struct(writer, &self.proof))
+    }
+}
+
+impl Readable for BalanceProof {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut balance_proof = BalanceProof {
+            user_data: UserData::zero(),
+            proof: MerkleProof { steps: vec![] },
+        };
+
+        match read_struct(reader, &mut balance_proof.user_data) //
+            .and_then(|_| read_struct(reader, &mut balance_proof.proof))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(balance_proof)
+    }
+}
+
 impl Debug for Ledger {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
@@ -105,30 +390,76 @@ impl Debug for Ledger {
             "________________________\nLedger\n________________________\n"
         )
         .ok();
-        for user in &self.users {
-            write!(f, "{:?}: {:?} coins\n", user.user, user.money).ok();
+        for user in self.sorted_users() {
+            writeln!(f, "{:?}: {:?} coins", user.user, user.money).ok();
+        }
+        for locked_funds in self.sorted_hash_locks() {
+            writeln!(
+                f,
+                "{:?} -> {:?}: {:?} coins locked until {}",
+                locked_funds.sender,
+                locked_funds.lock.recipient,
+                locked_funds.lock.value,
+                locked_funds.lock.timeout
+            )
+            .ok();
+        }
+        for token in self.sorted_tokens() {
+            writeln!(
+                f,
+                "{} by {:?}: {:?} of {:?} minted",
+                token.definition.symbol, token.issuer, token.supply, token.definition.max_supply
+            )
+            .ok();
         }
-        write!(f, "________________________\n")
+        writeln!(f, "________________________")
     }
 }
 
 impl Writable for Ledger {
     fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
-        write_vec_u64(writer, &self.nonces_transaction)
-            .and_then(|_| write_vec_struct(writer, &self.users))
+        let users: Vec<UserData> = self.sorted_users().into_iter().cloned().collect();
+        let hash_locks: Vec<LockedFunds> = self.sorted_hash_locks().into_iter().cloned().collect();
+        let tokens: Vec<Token> = self.sorted_tokens().into_iter().cloned().collect();
+        write_vec_struct(writer, &users)
+            .and_then(|_| write_vec_struct(writer, &hash_locks))
+            .and_then(|_| write_vec_struct(writer, &tokens))
     }
 }
 
 impl Readable for Ledger {
     fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
         let mut ledger = Ledger::empty();
+        let mut users: Vec<UserData> = vec![];
+        let mut hash_locks: Vec<LockedFunds> = vec![];
+        let mut tokens: Vec<Token> = vec![];
 
-        match read_vec_u64(reader, &mut ledger.nonces_transaction)
-            .and_then(|_| read_vec_struct(reader, &mut ledger.users))
+        match read_vec_struct(reader, &mut users)
+            .and_then(|_| read_vec_struct(reader, &mut hash_locks))
+            .and_then(|_| read_vec_struct(reader, &mut tokens))
         {
             Ok(_) => (),
             Err(_) => return Err(Error::InvalidFormat),
         };
+        for user_data in users {
+            // an account listed twice is not a valid state
+            if ledger.users.contains_key(&user_data.user) {
+                return Err(Error::InvalidFormat);
+            }
+            ledger.set_user_data(user_data.user, Some(user_data));
+        }
+        for locked_funds in hash_locks {
+            if ledger.hash_locks.contains_key(&locked_funds.id) {
+                return Err(Error::InvalidFormat);
+            }
+            ledger.set_hash_lock(locked_funds.id, Some(locked_funds));
+        }
+        for token in tokens {
+            if ledger.tokens.contains_key(&token.id) {
+                return Err(Error::InvalidFormat);
+            }
+            ledger.set_token(token.id, Some(token));
+        }
 
         Ok(ledger)
     }
@@ -138,9 +469,16 @@ impl UserData {
         UserData {
             user: PublicUser::zero(),
             money: Num::zero(),
+            sequence: 0,
+            tokens: HashMap::new(),
         }
     }
 
+    // leaf of the state tree
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256Hash::new(&[&self.to_bytes()])
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
 
@@ -157,8 +495,22 @@ impl UserData {
 
 impl Writable for UserData {
     fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
-        write_struct(writer, &self.user) //
+        let tokens = self.sorted_tokens();
+        match write_struct(writer, &self.user)
             .and_then(|_| write_struct(writer, &self.money))
+            .and_then(|_| write_u64(writer, self.sequence))
+            .and_then(|_| write_u32(writer, tokens.len() as u32))
+        {
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        };
+        for (id, balance) in tokens {
+            match write_struct(writer, id).and_then(|_| write_struct(writer, balance)) {
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(())
     }
 }
 
@@ -166,128 +518,500 @@ impl Readable for UserData {
     fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
         let mut user_data = UserData::zero();
 
-        match read_struct(reader, &mut user_data.user) //
+        let mut len: usize = 0;
+
+        match read_struct(reader, &mut user_data.user)
             .and_then(|_| read_struct(reader, &mut user_data.money))
+            .and_then(|_| read_u64(reader, &mut user_data.sequence))
+            .and_then(|_| read_vec_len(reader, &mut len))
         {
             Ok(m) => m,
             Err(_) => return Err(Error::InvalidFormat),
         };
+        for _ in 0..len {
+            let mut id = Sha256Hash::zero();
+            let mut balance = Num::zero();
+            match read_struct(reader, &mut id).and_then(|_| read_struct(reader, &mut balance)) {
+                Ok(_) => (),
+                Err(_) => return Err(Error::InvalidFormat),
+            };
+            // a token listed twice is not a valid state
+            if user_data.tokens.insert(id, balance).is_some() {
+                return Err(Error::InvalidFormat);
+            }
+        }
 
         Ok(user_data)
     }
 }
 
+impl LockedFunds {
+    pub fn zero() -> Self {
+        LockedFunds {
+            id: Sha256Hash::zero(),
+            sender: PublicUser::zero(),
+            lock: HashLock::zero(),
+        }
+    }
+
+    // leaf of the state tree
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256Hash::new(&[&self.to_bytes()])
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+impl Writable for LockedFunds {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.id)
+            .and_then(|_| write_struct(writer, &self.sender))
+            .and_then(|_| write_struct(writer, &self.lock))
+    }
+}
+
+impl Readable for LockedFunds {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut locked_funds = LockedFunds::zero();
+
+        match read_struct(reader, &mut locked_funds.id)
+            .and_then(|_| read_struct(reader, &mut locked_funds.sender))
+            .and_then(|_| read_struct(reader, &mut locked_funds.lock))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(locked_funds)
+    }
+}
+
+impl Token {
+    pub fn zero() -> Self {
+        Token {
+            id: Sha256Hash::zero(),
+            issuer: PublicUser::zero(),
+            definition: TokenDefinition::zero(),
+            supply: Num::zero(),
+        }
+    }
+
+    // leaf of the state tree
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256Hash::new(&[&self.to_bytes()])
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+impl Writable for Token {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.id)
+            .and_then(|_| write_struct(writer, &self.issuer))
+            .and_then(|_| write_struct(writer, &self.definition))
+            .and_then(|_| write_struct(writer, &self.supply))
+    }
+}
+
+impl Readable for Token {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut token = Token::zero();
+
+        match read_struct(reader, &mut token.id)
+            .and_then(|_| read_struct(reader, &mut token.issuer))
+            .and_then(|_| read_struct(reader, &mut token.definition))
+            .and_then(|_| read_struct(reader, &mut token.supply))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(token)
+    }
+}
+
 impl PartialLedger {
     pub fn empty() -> Self {
         PartialLedger {
-            users: vec![],
-            nonces_transaction: vec![],
+            users: HashMap::new(),
+            hash_locks: HashMap::new(),
+            tokens: HashMap::new(),
         }
     }
 
+    // whether the sender already used the sequence of the transaction
     pub fn contains(&self, ledger: &Ledger, transaction: &Transaction) -> bool {
-        if self.nonces_transaction.contains(&transaction.content.nonce) {
-            return true;
+        if transaction.content.from.is_coinbase() {
+            return false;
         }
+        match self.get_user_data(ledger, transaction.content.from) {
+            Some(user_data) => transaction.content.sequence <= user_data.sequence,
+            None => false,
+        }
+    }
 
-        if ledger.contains(&transaction) {
-            return true;
+    pub fn get_user_data<'a>(
+        &'a self,
+        ledger: &'a Ledger,
+        public_user: PublicUser,
+    ) -> Option<&'a UserData> {
+        match self.users.get(&public_user) {
+            Some(user_data) => Some(user_data),
+            None => ledger.get_user_data(public_user),
         }
+    }
 
-        return false;
+    // The account of the sender as the transaction finds it. The recipient of
+    // a hash lock may hold nothing yet: its claim opens its account.
+    pub fn get_sender_data(&self, ledger: &Ledger, transaction: &Transaction) -> Option<UserData> {
+        let from = transaction.content.from;
+        match (
+            self.get_user_data(ledger, from),
+            &transaction.content.payment,
+        ) {
+            (Some(user_data), _) => Some(user_data.clone()),
+            (None, Payment::Claim { .. }) => Some(UserData::new(from)),
+            (None, _) => None,
+        }
     }
 
-    pub fn find_user_data(&mut self, ledger: &Ledger, public_user: PublicUser) -> Option<usize> {
-        for (i, elem) in self.users.iter().enumerate() {
-            if elem.user == public_user {
-                return Some(i);
+    // the value a claim or a refund releases to its sender, which pays the fee out of it
+    pub fn released_value(&self, ledger: &Ledger, transaction: &Transaction) -> Num {
+        match &transaction.content.payment {
+            Payment::Claim { lock, .. } | Payment::Refund { lock } => {
+                match self.get_hash_lock(ledger, lock) {
+                    Some(locked_funds) => locked_funds.lock.value.clone(),
+                    None => Num::zero(),
+                }
             }
+            _ => Num::zero(),
         }
+    }
 
-        match ledger.find_user_data(public_user) {
-            Some(i) => {
-                self.users.push(ledger.users[i].clone());
-                return Some(self.users.len() - 1);
+    // copies the account from the ledger the first time it is modified
+    pub fn find_user_data(
+        &mut self,
+        ledger: &Ledger,
+        public_user: PublicUser,
+    ) -> Option<&mut UserData> {
+        match self.users.entry(public_user) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => match ledger.get_user_data(public_user) {
+                Some(user_data) => Some(entry.insert(user_data.clone())),
+                None => None,
+            },
+        }
+    }
+
+    pub fn find_or_create_user_data(
+        &mut self,
+        ledger: &Ledger,
+        public_user: PublicUser,
+    ) -> &mut UserData {
+        self.users
+            .entry(public_user)
+            .or_insert_with(|| match ledger.get_user_data(public_user) {
+                Some(user_data) => user_data.clone(),
+                None => UserData::new(public_user),
+            })
+    }
+
+    pub fn get_hash_lock<'a>(
+        &'a self,
+        ledger: &'a Ledger,
+        id: &Sha256Hash,
+    ) -> Option<&'a LockedFunds> {
+        match self.hash_locks.get(id) {
+            Some(locked_funds) => locked_funds.as_ref(),
+            None => ledger.get_hash_lock(id),
+        }
+    }
+
+    pub fn get_token<'a>(&'a self, ledger: &'a Ledger, id: &Sha256Hash) -> Option<&'a Token> {
+        match self.tokens.get(id) {
+            Some(token) => Some(token),
+            None => ledger.get_token(id),
+        }
+    }
+
+    // copies the token from the ledger the first time it is modified
+    pub fn find_token(&mut self, ledger: &Ledger, id: &Sha256Hash) -> Option<&mut Token> {
+        match self.tokens.entry(*id) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => match ledger.get_token(id) {
+                Some(token) => Some(entry.insert(token.clone())),
+                None => None,
+            },
+        }
+    }
+
+    // Checks a hash lock payment of the block at `index` without modifying
+    // anything. A lock is claimed by its recipient with the preimage up to
+    // its timeout, and refunded to its sender after it.
+    fn check_hash_lock(
+        &self,
+        ledger: &Ledger,
+        transaction: &Transaction,
+        index: u32,
+    ) -> Result<(), Error> {
+        let from = transaction.content.from;
+        match &transaction.content.payment {
+            Payment::Single(_) | Payment::Batch(_) => Ok(()),
+            Payment::CreateToken(_) | Payment::Mint { .. } | Payment::TokenTransfer { .. } => {
+                Ok(())
+            }
+            _ if from.is_coinbase() => Err(Error::HashLockFromCoinbase),
+            Payment::HashLock(lock) => {
+                if self
+                    .get_hash_lock(ledger, &transaction.content_hash())
+                    .is_some()
+                {
+                    return Err(Error::TransactionWasAlreadyDone);
+                }
+                if index > lock.timeout {
+                    return Err(Error::HashLockIsExpired);
+                }
+                Ok(())
+            }
+            Payment::Claim { lock, preimage } => {
+                let locked_funds = match self.get_hash_lock(ledger, lock) {
+                    Some(locked_funds) => locked_funds,
+                    None => return Err(Error::UnknownHashLock),
+                };
+                if from != locked_funds.lock.recipient {
+                    return Err(Error::NotTheHashLockParty);
+                }
+                if index > locked_funds.lock.timeout {
+                    return Err(Error::HashLockIsExpired);
+                }
+                if !locked_funds.lock.is_unlocked_by(preimage) {
+                    return Err(Error::WrongPreimage);
+                }
+                Ok(())
+            }
+            Payment::Refund { lock } => {
+                let locked_funds = match self.get_hash_lock(ledger, lock) {
+                    Some(locked_funds) => locked_funds,
+                    None => return Err(Error::UnknownHashLock),
+                };
+                if from != locked_funds.sender {
+                    return Err(Error::NotTheHashLockParty);
+                }
+                if index <= locked_funds.lock.timeout {
+                    return Err(Error::HashLockIsNotExpired);
+                }
+                Ok(())
             }
-            None => (),
         }
-        return None;
     }
 
-    pub fn find_or_create_user_data(&mut self, ledger: &Ledger, public_user: PublicUser) -> usize {
-        match self.find_user_data(ledger, public_user) {
-            Some(i) => return i,
-            None => match ledger.find_user_data(public_user) {
-                Some(i) => {
-                    self.users.push(ledger.users[i].clone());
-                    return self.users.len() - 1;
+    // Checks a token payment without modifying anything. Only the issuer
+    // mints a token, up to its max supply, and a holder transfers at most
+    // its balance.
+    fn check_token(&self, ledger: &Ledger, transaction: &Transaction) -> Result<(), Error> {
+        let from = transaction.content.from;
+        let (id, output) = match &transaction.content.payment {
+            Payment::CreateToken(_) if from.is_coinbase() => return Err(Error::TokenFromCoinbase),
+            Payment::CreateToken(_) => {
+                if self
+                    .get_token(ledger, &transaction.content_hash())
+                    .is_some()
+                {
+                    return Err(Error::TransactionWasAlreadyDone);
                 }
-                None => {
-                    self.users.push(UserData::new(public_user));
-                    return self.users.len() - 1;
+                return Ok(());
+            }
+            Payment::Mint { token, output } | Payment::TokenTransfer { token, output } => {
+                (token, output)
+            }
+            _ => return Ok(()),
+        };
+        if from.is_coinbase() {
+            return Err(Error::TokenFromCoinbase);
+        }
+        let token = match self.get_token(ledger, id) {
+            Some(token) => token,
+            None => return Err(Error::UnknownToken),
+        };
+
+        match &transaction.content.payment {
+            Payment::Mint { .. } => {
+                if from != token.issuer {
+                    return Err(Error::NotTheTokenIssuer);
                 }
-            },
+                let mut supply = token.supply.clone();
+                supply += output.value.clone();
+                if supply > token.definition.max_supply {
+                    return Err(Error::TokenSupplyExceeded);
+                }
+                Ok(())
+            }
+            _ => {
+                let balance = match self.get_user_data(ledger, from) {
+                    Some(user_data) => user_data.token_balance(id),
+                    None => Num::zero(),
+                };
+                if balance < output.value {
+                    return Err(Error::NotEnoughTokens);
+                }
+                Ok(())
+            }
         }
     }
 
+    // applies the transaction of the block at `index`, nothing is modified
+    // when it fails
     pub fn apply_transaction(
         &mut self,
         ledger: &Ledger,
         transaction: &Transaction,
+        index: u32,
     ) -> Result<(), Error> {
-        if self.contains(ledger, transaction) {
-            return Err(Error::TransactionWasAlreadyDone);
-        }
-        let value = &transaction.content.value;
+        match self
+            .check_hash_lock(ledger, transaction, index)
+            .and_then(|_| self.check_token(ledger, transaction))
+        {
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        };
 
         if !transaction.content.from.is_coinbase() {
-            let user_data_index = match self.find_user_data(ledger, transaction.content.from) {
+            let sender_data = match self.get_sender_data(ledger, transaction) {
                 Some(data) => data,
                 None => return Err(Error::TryingToSendMoneyFromUnknowUser),
             };
 
-            let money = self.users[user_data_index].money.clone();
-            match transaction.verify(money) {
-                Ok(_) => (),
-                Err(e) => return Err(e),
+            let sequence = sender_data.sequence;
+            if transaction.content.sequence <= sequence {
+                return Err(Error::TransactionWasAlreadyDone);
+            }
+            if transaction.content.sequence != sequence + 1 {
+                return Err(Error::TransactionSequenceIsNotNext);
             }
 
-            self.users[user_data_index].money -= value.clone();
-        }
-
-        self.nonces_transaction.push(transaction.content.nonce);
-        let to_user_data_index = self.find_or_create_user_data(ledger, transaction.content.to);
-        self.users[to_user_data_index].money += value.clone();
-
-        return Ok(());
-    }
+            let released = self.released_value(ledger, transaction);
+            let mut money = sender_data.money;
+            money += released;
+            let cost = transaction.cost();
+            if money < cost {
+                return Err(Error::SenderDoNotHaveEnoughMoney);
+            }
 
-    pub fn to_ledger(&self, ledger: &Ledger) -> Ledger {
-        let mut res = Ledger {
-            nonces_transaction: self.nonces_transaction.clone(),
-            users: self.users.clone(),
-        };
+            // the fee is credited to the miner by the coinbase of the block
+            money -= cost;
+            let user_data = self.find_or_create_user_data(ledger, transaction.content.from);
+            user_data.money = money;
+            user_data.sequence = transaction.content.sequence;
+        }
 
-        for elem in &ledger.nonces_transaction {
-            if !res.nonces_transaction.contains(&elem) {
-                res.nonces_transaction.push(elem.clone());
-            }
+        // every check is done above, a batch is credited entirely
+        for output in transaction.outputs() {
+            let to_user_data = self.find_or_create_user_data(ledger, output.to);
+            to_user_data.money += output.value.clone();
         }
 
-        for a in &ledger.users {
-            let mut find = false;
-            for b in &res.users {
-                if b.user == a.user {
-                    find = true;
-                    break;
+        match &transaction.content.payment {
+            Payment::HashLock(lock) => {
+                let id = transaction.content_hash();
+                let locked_funds = LockedFunds {
+                    id: id,
+                    sender: transaction.content.from,
+                    lock: lock.clone(),
+                };
+                self.hash_locks.insert(id, Some(locked_funds));
+            }
+            Payment::Claim { lock, .. } | Payment::Refund { lock } => {
+                // the value was released to the claimant above, with its fee
+                self.hash_locks.insert(*lock, None);
+            }
+            Payment::CreateToken(definition) => {
+                let id = transaction.content_hash();
+                let token = Token {
+                    id: id,
+                    issuer: transaction.content.from,
+                    definition: definition.clone(),
+                    supply: Num::zero(),
+                };
+                self.tokens.insert(id, token);
+            }
+            Payment::Mint { token, output } => {
+                match self.find_token(ledger, token) {
+                    Some(token) => token.supply += output.value.clone(),
+                    None => return Err(Error::UnknownToken),
                 }
+                let to_user_data = self.find_or_create_user_data(ledger, output.to);
+                to_user_data.credit_token(*token, output.value.clone());
             }
-
-            if !find {
-                res.users.push(a.clone());
+            Payment::TokenTransfer { token, output } => {
+                // the sender is known, it paid the fee
+                match self.find_user_data(ledger, transaction.content.from) {
+                    Some(user_data) => user_data.debit_token(*token, output.value.clone()),
+                    None => return Err(Error::TryingToSendMoneyFromUnknowUser),
+                }
+                let to_user_data = self.find_or_create_user_data(ledger, output.to);
+                to_user_data.credit_token(*token, output.value.clone());
             }
+            _ => (),
         }
 
+        return Ok(());
+    }
+
+    // what reverts `to_ledger(ledger)` back to `ledger`
+    pub fn undo(&self, ledger: &Ledger) -> LedgerUndo {
+        LedgerUndo {
+            users: self
+                .users
+                .keys()
+                .map(|user| (*user, ledger.get_user_data(*user).cloned()))
+                .collect(),
+            hash_locks: self
+                .hash_locks
+                .keys()
+                .map(|id| (*id, ledger.get_hash_lock(id).cloned()))
+                .collect(),
+            tokens: self
+                .tokens
+                .keys()
+                .map(|id| (*id, ledger.get_token(id).cloned()))
+                .collect(),
+        }
+    }
+
+    // the accounts, hash locks and tokens of the partial ledger replace the ones of `ledger`
+    pub fn to_ledger(&self, ledger: &Ledger) -> Ledger {
+        let mut res = ledger.clone();
+        for (user, user_data) in &self.users {
+            res.set_user_data(*user, Some(user_data.clone()));
+        }
+        for (id, locked_funds) in &self.hash_locks {
+            res.set_hash_lock(*id, locked_funds.clone());
+        }
+        for (id, token) in &self.tokens {
+            res.set_token(*id, Some(token.clone()));
+        }
         res
     }
 }
@@ -1,16 +1,32 @@
+// the code base favours explicit `match` and `return`, and `field: field` initialisation
+#![allow(
+    clippy::needless_return,
+    clippy::question_mark,
+    clippy::redundant_field_names,
+    clippy::single_match
+)]
+
 pub mod block;
 pub mod blockchain;
+pub mod consensus;
 pub mod error;
 pub mod ledger;
 pub mod merkle;
+pub mod merkle_tree;
 pub mod mining;
+pub mod multisig;
 pub mod network;
 pub mod node;
 pub mod num;
+pub mod orphan;
 pub mod reader;
+pub mod script;
 pub mod sha256;
 pub mod signature;
+pub mod state;
+pub mod target;
 pub mod time;
 pub mod transaction;
 pub mod user;
+pub mod utxo;
 pub mod writer;
@@ -1,133 +1,485 @@
+use std::collections::HashMap;
 use std::fmt::{self, Debug, Formatter};
 use std::io::Read;
 use std::io::Write;
 use std::vec;
 
+use num::{BigUint, Zero};
+
 use crate::reader::{read_u32, read_vec_struct, Readable};
 use crate::writer::{write_u32, write_vec_struct, Writable};
 use crate::{
-    block::Block, blockchain::Blockchain, error::Error, ledger::Ledger, sha256::Sha256Hash,
+    block::Block,
+    blockchain::Blockchain,
+    consensus::{BlockContext, Params, Rejection, RuleSet},
+    error::Error,
+    ledger::{BalanceProof, Ledger},
+    sha256::Sha256Hash,
+    state::{BlockTransaction, StateModel},
+    time::Time,
+    transaction::Transaction,
+    user::PublicUser,
 };
 
+// What adding a block did to the main branch. On a reorganization the
+// blocks are listed from the fork point up to the old and new tip.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockOutcome<T: BlockTransaction = Transaction> {
+    ExtendedMain,
+    SideBranch,
+    Reorganized {
+        from: Sha256Hash,
+        to: Sha256Hash,
+        disconnected: Vec<Block<T>>,
+        connected: Vec<Block<T>>,
+    },
+}
+
+// Where a block is stored in `Merkle.blocks` and how it links to its parent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct BlockNode {
+    position: u32,
+    parent: Option<u32>, // position of the parent, None for the genesis
+    height: u32,
+    chain: Option<u32>, // first branch going through the block, the one it ends if any
+}
+
 #[derive(Clone, PartialEq, Eq)]
-pub struct Merkle {
-    pub blocks: Vec<Block>,
-    pub chains: Vec<Blockchain>, // ordered index of the blockchain
+pub struct Merkle<S: StateModel = Ledger> {
+    pub blocks: Vec<Block<S::Transaction>>,
+    nodes: HashMap<Sha256Hash, BlockNode>, // rebuilt from the blocks and branches
+    undo: HashMap<Sha256Hash, S::Undo>,    // rebuilt from the branches, not serialized
+    pub chains: Vec<Blockchain<S>>,        // ordered index of the blockchain
     pub main: u32,
+    pub rules: RuleSet<S>, // not serialized, each node enforces its own rules
+    pub params: Params,    // not serialized either
+    pub prune_depth: u32,  // side branches further behind the main tip are dropped
 }
 
-impl Merkle {
-    pub const DIFFICULTY: u128 = 0xF1FF;
+impl<S: StateModel> Merkle<S> {
+    pub const PRUNE_DEPTH: u32 = 100;
 
     pub fn zero() -> Self {
         Merkle {
             blocks: vec![],
+            nodes: HashMap::new(),
+            undo: HashMap::new(),
             chains: vec![],
             main: 0,
+            rules: S::standard_rules(),
+            params: Params::default(),
+            prune_depth: Self::PRUNE_DEPTH,
         }
     }
 
-    pub fn new_from_nothingness() -> Self {
-        Merkle {
+    // a tree holding only the genesis, whatever the state model
+    pub fn new_from_genesis() -> Self {
+        let mut merkle = Merkle {
             blocks: vec![Block::new_genesis()],
-            chains: vec![Blockchain::new(vec![0], Ledger::empty())],
+            nodes: HashMap::new(),
+            undo: HashMap::new(),
+            chains: vec![Blockchain::new(vec![0], S::empty(), BigUint::zero())],
             main: 0,
-        }
+            rules: S::standard_rules(),
+            params: Params::default(),
+            prune_depth: Self::PRUNE_DEPTH,
+        };
+        merkle.index_blocks().ok();
+        merkle.replay_undo();
+        merkle
     }
 
-    pub fn main(&self) -> &Blockchain {
+    pub fn main(&self) -> &Blockchain<S> {
         &self.chains[self.main as usize]
     }
 
-    fn contains(&self, block: &Block) -> bool {
-        self.blocks.contains(block)
+    fn contains(&self, block: &Block<S::Transaction>) -> bool {
+        self.nodes.contains_key(&block.hash)
     }
 
-    fn find_block_by_hash(&self, hash: Sha256Hash) -> Result<usize, Error> {
-        for (i, block) in self.blocks.iter().enumerate() {
-            if block.hash == hash {
-                return Ok(i);
-            }
+    pub fn get_block(&self, hash: &Sha256Hash) -> Option<&Block<S::Transaction>> {
+        self.nodes
+            .get(hash)
+            .map(|node| &self.blocks[node.position as usize])
+    }
+
+    // number of blocks between the genesis and the block
+    pub fn height_of(&self, hash: &Sha256Hash) -> Option<u32> {
+        self.nodes.get(hash).map(|node| node.height)
+    }
+
+    fn node_at(&self, position: u32) -> Option<&BlockNode> {
+        match self.blocks.get(position as usize) {
+            Some(block) => self.nodes.get(&block.hash),
+            None => None,
         }
-        return Err(Error::NotFound);
     }
 
-    fn find_or_create_blockchain(
-        &self,
-        block: &Block,
-    ) -> Result<(Blockchain, Option<usize>), Error> {
-        for (i, chain) in self.chains.iter().enumerate() {
-            if chain.last(&self.blocks).hash == block.content.prev_block_hash {
-                return Ok((chain.clone(), Some(i)));
+    // the first branch going through each block of `blocks`
+    fn first_chains(&self) -> Vec<Option<u32>> {
+        let mut first = vec![None; self.blocks.len()];
+        for (c, chain) in self.chains.iter().enumerate() {
+            for i in &chain.index {
+                if first[*i as usize].is_none() {
+                    first[*i as usize] = Some(c as u32);
+                }
             }
         }
-        let index = match self.find_block_by_hash(block.content.prev_block_hash) {
-            Ok(i) => i,
-            Err(_) => return Err(Error::PreviousBlockNotFound),
-        };
+        first
+    }
+
+    // maps every block of `blocks` to its position, a parent is always
+    // stored before its children
+    fn index_blocks(&mut self) -> Result<(), Error> {
+        let first = self.first_chains();
+        self.nodes.clear();
+        for (position, block) in self.blocks.iter().enumerate() {
+            let node = if block.is_genesis() {
+                BlockNode {
+                    position: position as u32,
+                    parent: None,
+                    height: 0,
+                    chain: first[position],
+                }
+            } else {
+                let parent = match self.nodes.get(&block.content.prev_block_hash) {
+                    Some(v) => v,
+                    None => return Err(Error::PreviousBlockNotFound),
+                };
+                BlockNode {
+                    position: position as u32,
+                    parent: Some(parent.position),
+                    height: parent.height + 1,
+                    chain: first[position],
+                }
+            };
+            self.nodes.insert(block.hash, node);
+        }
+        Ok(())
+    }
 
-        for chain in self.chains.iter() {
-            for (i, j) in chain.index.iter().enumerate() {
-                if (*j as usize) == index {
-                    let new_chain = chain.index[0..i + 1].to_vec();
+    // A branch is only ever extended or forked at its end, and new branches
+    // come after the existing ones: a tip is never in an earlier branch.
+    fn find_blockchain_by_tip(&self, hash: &Sha256Hash) -> Option<usize> {
+        let node = match self.nodes.get(hash) {
+            Some(v) => v,
+            None => return None,
+        };
+        match node.chain {
+            Some(c) if self.chains[c as usize].index.last() == Some(&node.position) => {
+                Some(c as usize)
+            }
+            _ => None,
+        }
+    }
 
-                    let new_ledger = Ledger::new_unsafe(&new_chain, &self.blocks);
-                    let new_blockchain = Blockchain::new(new_chain, new_ledger);
-                    return Ok((new_blockchain, None));
+    // the undo records are not serialized, they are rebuilt by replaying the branches
+    fn replay_undo(&mut self) {
+        self.undo.clear();
+        for chain in &self.chains {
+            let mut ledger = S::empty();
+            for i in &chain.index {
+                let block = &self.blocks[*i as usize];
+                let mut partial_ledger = S::empty_changes();
+                for transaction in &block.content.transactions {
+                    ledger
+                        .apply_transaction(&mut partial_ledger, transaction, block.content.index)
+                        .ok();
                 }
+                self.undo
+                    .entry(block.hash)
+                    .or_insert_with(|| ledger.undo(&partial_ledger));
+                ledger = ledger.apply_changes(&partial_ledger);
             }
         }
+    }
+
+    // a new branch ending at the parent of `block`
+    fn create_blockchain(&self, block: &Block<S::Transaction>) -> Result<Blockchain<S>, Error> {
+        match self.nodes.get(&block.content.prev_block_hash) {
+            Some(parent) => self.branch_at(parent),
+            None => Err(Error::PreviousBlockNotFound),
+        }
+    }
+
+    // the state once the block `hash` is applied on the branch it belongs to
+    pub fn ledger_at(&self, hash: &Sha256Hash) -> Option<S> {
+        match self.nodes.get(hash) {
+            Some(node) => self.branch_at(node).ok().map(|chain| chain.ledger),
+            None => None,
+        }
+    }
+
+    // The branch ending at `node`. It starts from the first branch going
+    // through the node, and reverts the blocks after it: the cost depends on
+    // the divergence, not on the state.
+    fn branch_at(&self, node: &BlockNode) -> Result<Blockchain<S>, Error> {
+        let height = node.height as usize;
+
+        let chain = match node.chain {
+            Some(c) => &self.chains[c as usize],
+            None => return Err(Error::BlockExistButIsNotInAnyBranch),
+        };
+        if chain.index.get(height) != Some(&node.position) {
+            return Err(Error::BlockExistButIsNotInAnyBranch); // a forged branch
+        }
+
+        let mut new_ledger = chain.ledger.clone();
+        let mut new_work = chain.work.clone();
+        for i in chain.index[height + 1..].iter().rev() {
+            let reverted = &self.blocks[*i as usize];
+            match self.undo.get(&reverted.hash) {
+                Some(undo) => new_ledger.revert(undo),
+                None => return Err(Error::NotFound),
+            }
+            new_work -= reverted.work();
+        }
 
-        return Err(Error::BlockExistButIsNotInAnyBranch);
+        let new_chain = chain.index[0..height + 1].to_vec();
+        return Ok(Blockchain::new(new_chain, new_ledger, new_work));
     }
 
-    pub fn add_block(&mut self, block: Block) -> Result<(), Error> {
+    pub fn add_block(
+        &mut self,
+        block: Block<S::Transaction>,
+    ) -> Result<BlockOutcome<S::Transaction>, Error> {
+        match self.submit_block(block) {
+            Ok(outcome) => Ok(outcome),
+            Err(rejection) => Err(rejection.error),
+        }
+    }
+
+    // same as add_block but reports which consensus rule rejected the block
+    pub fn submit_block(
+        &mut self,
+        block: Block<S::Transaction>,
+    ) -> Result<BlockOutcome<S::Transaction>, Rejection> {
         if self.contains(&block) {
-            return Err(Error::BlockAlreadyExist); // do nothing
+            return Err(Error::BlockAlreadyExist.into()); // do nothing
         }
 
-        match block.verify(Merkle::DIFFICULTY) {
+        // extending the tip of a branch is the common case, a fork needs a new branch
+        let tip = self.find_blockchain_by_tip(&block.content.prev_block_hash);
+        let fork = match tip {
+            Some(_) => None,
+            None => match self.create_blockchain(&block) {
+                Ok(v) => Some(v),
+                Err(e) => return Err(e.into()),
+            },
+        };
+        let blockchain = match (tip, &fork) {
+            (Some(i), _) => &self.chains[i],
+            (None, Some(chain)) => chain,
+            (None, None) => return Err(Error::PreviousBlockNotFound.into()),
+        };
+
+        let context = BlockContext::new(&self.blocks, blockchain, &self.params, Time::now());
+        match block.verify(&self.rules, &context) {
             Ok(_) => (),
             Err(e) => return Err(e),
         }
 
-        let (blockchain, index) = match self.find_or_create_blockchain(&block) {
-            Ok(lb) => lb,
+        // the ledger of the branch is shared, only the changed accounts are copied
+        let (changes, new_ledger) = match block.verify_changes(&self.rules, &blockchain.ledger) {
+            Ok(l) => l,
             Err(e) => return Err(e),
         };
+        self.undo
+            .insert(block.hash, blockchain.ledger.undo(&changes));
+
+        let chain_index = match (tip, fork) {
+            (Some(i), _) => i,
+            (None, fork) => {
+                self.chains.extend(fork);
+                self.chains.len() - 1
+            }
+        };
+
+        let node = BlockNode {
+            position: self.blocks.len() as u32,
+            parent: self.chains[chain_index].index.last().copied(),
+            height: self.chains[chain_index].len() as u32,
+            chain: Some(chain_index as u32),
+        };
+        self.nodes.insert(block.hash, node);
+        self.blocks.push(block.clone());
+
+        self.chains[chain_index].index.push(node.position);
+        self.chains[chain_index].ledger = new_ledger;
+        self.chains[chain_index].work += block.work();
+
+        let outcome = if chain_index == self.main as usize {
+            BlockOutcome::ExtendedMain
+        } else if !self.chains[chain_index].is_better_than(self.main(), &self.blocks) {
+            BlockOutcome::SideBranch
+        } else {
+            let old_main = self.main as usize;
+            self.main = chain_index as u32;
+            self.reorganization(old_main, chain_index)
+        };
 
-        let last_valid_block = blockchain.last(&self.blocks);
-        if last_valid_block.content.index + 1 != block.content.index {
-            return Err(Error::BlockIndexAreNotContiguous);
+        self.prune();
+        return Ok(outcome);
+    }
+
+    // Drops the branches whose tip is more than `prune_depth` blocks behind
+    // the tip of main, then the blocks that no kept branch goes through.
+    // Returns the number of blocks removed.
+    pub fn prune(&mut self) -> usize {
+        let main_len = self.main().len();
+        let depth = self.prune_depth as usize;
+        if self
+            .chains
+            .iter()
+            .all(|chain| chain.len() + depth >= main_len)
+        {
+            return 0;
         }
-        if last_valid_block.hash != block.content.prev_block_hash {
-            return Err(Error::BlockPrevHashDoesNotMatch);
+
+        let main = self.main as usize;
+        let mut chains = vec![];
+        for (i, chain) in self.chains.drain(..).enumerate() {
+            if i == main {
+                self.main = chains.len() as u32;
+            }
+            if i == main || chain.len() + depth >= main_len {
+                chains.push(chain);
+            }
         }
+        self.chains = chains;
+        let first = self.first_chains();
 
-        let new_ledger = match block.verify_payload(&blockchain.ledger) {
-            Ok(l) => (l),
-            Err(e) => return Err(e),
+        // the kept blocks stay in the same order, only their position shifts
+        let pruned = first.iter().filter(|c| c.is_none()).count();
+        let mut position = vec![0; self.blocks.len()];
+        let mut blocks = Vec::with_capacity(self.blocks.len() - pruned);
+        for (i, block) in self.blocks.drain(..).enumerate() {
+            match first[i] {
+                Some(_) => {
+                    position[i] = blocks.len() as u32;
+                    blocks.push(block);
+                }
+                None => {
+                    self.nodes.remove(&block.hash);
+                    self.undo.remove(&block.hash);
+                }
+            }
+        }
+        self.blocks = blocks;
+
+        for chain in self.chains.iter_mut() {
+            for i in chain.index.iter_mut() {
+                *i = position[*i as usize];
+            }
+        }
+        // the parent of a kept block is kept as well
+        for node in self.nodes.values_mut() {
+            node.chain = first[node.position as usize];
+            node.parent = node.parent.map(|p| position[p as usize]);
+            node.position = position[node.position as usize];
+        }
+
+        pruned
+    }
+
+    // the last block shared by the two branches ending at `a` and `b`,
+    // found by walking up the parent of whichever is higher
+    fn fork_point(&self, a: &BlockNode, b: &BlockNode) -> Option<BlockNode> {
+        let mut a = *a;
+        let mut b = *b;
+        while a.position != b.position {
+            let higher = if a.height >= b.height { &mut a } else { &mut b };
+            *higher = match higher.parent.and_then(|p| self.node_at(p)) {
+                Some(v) => *v,
+                None => return None,
+            };
+        }
+        Some(a)
+    }
+
+    fn reorganization(&self, old_main: usize, new_main: usize) -> BlockOutcome<S::Transaction> {
+        let old_index = &self.chains[old_main].index;
+        let new_index = &self.chains[new_main].index;
+        let old_tip = self.chains[old_main].last(&self.blocks);
+        let new_tip = self.chains[new_main].last(&self.blocks);
+        let fork = match (self.nodes.get(&old_tip.hash), self.nodes.get(&new_tip.hash)) {
+            (Some(a), Some(b)) => match self.fork_point(a, b) {
+                Some(node) => node.height as usize + 1,
+                None => 0,
+            },
+            _ => 0,
         };
 
-        self.blocks.push(block.clone());
-        let block_index = self.blocks.len() - 1;
+        let blocks_of = |index: &[u32]| -> Vec<Block<S::Transaction>> {
+            index
+                .iter()
+                .map(|i| self.blocks[*i as usize].clone())
+                .collect()
+        };
 
-        let chain_index = match index {
-            Some(i) => i,
-            None => {
-                self.chains.push(blockchain.clone());
-                self.chains.len() - 1
+        BlockOutcome::Reorganized {
+            from: old_tip.hash,
+            to: new_tip.hash,
+            disconnected: blocks_of(&old_index[fork..]),
+            connected: blocks_of(&new_index[fork..]),
+        }
+    }
+
+    // Checks a tree received from a peer: every branch is replayed from the
+    // genesis and the main branch must be the one with the most work.
+    pub fn verify_full(&self) -> Result<(), Rejection> {
+        if self.nodes.len() != self.blocks.len() {
+            return Err(Error::DuplicateBlockInTree.into());
+        }
+        if self.main as usize >= self.chains.len() {
+            return Err(Error::MainBranchIsNotTheBest.into());
+        }
+
+        // only the blocks of a branch are replayed, any other block is unchecked
+        let mut referenced = vec![false; self.blocks.len()];
+        for chain in &self.chains {
+            for i in &chain.index {
+                referenced[*i as usize] = true;
             }
-        };
+        }
+        if referenced.contains(&false) {
+            return Err(Error::BlockExistButIsNotInAnyBranch.into());
+        }
 
-        self.chains[chain_index].index.push(block_index as u32);
-        self.chains[chain_index].ledger = new_ledger;
+        for chain in &self.chains {
+            match chain.verify_full(&self.blocks, &self.rules, &self.params) {
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            }
+        }
 
-        if self.chains[chain_index].len() > self.main().len() {
-            self.main = chain_index as u32
+        if self
+            .chains
+            .iter()
+            .any(|chain| chain.is_better_than(self.main(), &self.blocks))
+        {
+            return Err(Error::MainBranchIsNotTheBest.into());
         }
 
-        return Ok(());
+        Ok(())
+    }
+}
+
+impl Merkle {
+    pub fn new_from_nothingness() -> Self {
+        Merkle::new_from_genesis()
+    }
+
+    // proves the account of `public_user` against the state root of the block `hash`
+    pub fn balance_proof(
+        &self,
+        hash: &Sha256Hash,
+        public_user: PublicUser,
+    ) -> Option<BalanceProof> {
+        match self.ledger_at(hash) {
+            Some(ledger) => ledger.balance_proof(public_user),
+            None => None,
+        }
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -143,17 +495,17 @@ impl Merkle {
     }
 }
 
-impl Writable for Merkle {
+impl<S: StateModel> Writable for Merkle<S> {
     fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
         write_vec_struct(writer, &self.blocks)
             .and_then(|_| write_vec_struct(writer, &self.chains))
-            .and_then(|_| write_u32(writer, self.main as u32))
+            .and_then(|_| write_u32(writer, self.main))
     }
 }
 
-impl Readable for Merkle {
+impl<S: StateModel> Readable for Merkle<S> {
     fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
-        let mut merkle = Merkle::zero();
+        let mut merkle = Self::zero();
 
         match read_vec_struct(reader, &mut merkle.blocks)
             .and_then(|_| read_vec_struct(reader, &mut merkle.chains))
@@ -162,12 +514,26 @@ impl Readable for Merkle {
             Ok(_) => (),
             Err(_) => return Err(Error::InvalidFormat),
         };
+        // the branches may only refer to the blocks that were sent
+        let len = merkle.blocks.len();
+        if merkle
+            .chains
+            .iter()
+            .any(|chain| chain.index.iter().any(|i| *i as usize >= len))
+        {
+            return Err(Error::InvalidFormat);
+        }
+        match merkle.index_blocks() {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+        merkle.replay_undo();
 
         Ok(merkle)
     }
 }
 
-impl Debug for Merkle {
+impl<S: StateModel> Debug for Merkle<S> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "<Merkle>",)
     }
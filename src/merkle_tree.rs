@@ -0,0 +1,301 @@
+use std::io::{Read, Write};
+
+use crate::error::Error;
+use crate::reader::{read_struct, read_u32, read_vec_struct, Readable};
+use crate::sha256::Sha256Hash;
+use crate::writer::{write_struct, write_u32, write_vec_struct, Writable};
+
+// Binary hash tree over the transactions of a block (not to be confused with
+// `merkle::Merkle`, the tree of blocks). Leaves and nodes are hashed with a
+// different prefix so that a node can never be passed off as a leaf, and a
+// node without sibling is moved up unchanged instead of being duplicated.
+pub struct MerkleTree {
+    levels: Vec<Vec<Sha256Hash>>, // levels[0] are the leaves, the last level is the root
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProofStep {
+    pub hash: Sha256Hash,
+    pub is_left: bool, // the sibling is on the left of the hashed path
+}
+
+// The siblings needed to recompute the root from one leaf.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub steps: Vec<ProofStep>,
+}
+
+// Hash tree over the 2^256 slots given by a key hash, each entry is a leaf
+// in the slot of its key. Setting a leaf only rehashes the `DEPTH` nodes
+// above it, whatever the number of entries. An empty subtree hashes to zero
+// and is not stored. Like the maps of the states it is persistent: a clone
+// shares every node with the original.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SparseMerkleTree {
+    nodes: im::HashMap<(u32, Sha256Hash), Sha256Hash>, // by depth and key prefix
+}
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Sha256Hash>) -> Self {
+        let mut levels = vec![leaves
+            .iter()
+            .map(MerkleTree::hash_leaf)
+            .collect::<Vec<Sha256Hash>>()];
+
+        while levels[levels.len() - 1].len() > 1 {
+            let level = &levels[levels.len() - 1];
+            let mut next = Vec::with_capacity(level.len().div_ceil(2));
+            for pair in level.chunks(2) {
+                match pair {
+                    [left, right] => next.push(MerkleTree::hash_node(left, right)),
+                    [alone] => next.push(*alone),
+                    _ => (),
+                }
+            }
+            levels.push(next);
+        }
+
+        MerkleTree { levels: levels }
+    }
+
+    // the root of a tree without leaf is the zero hash
+    pub fn root(&self) -> Sha256Hash {
+        match self.levels[self.levels.len() - 1].first() {
+            Some(root) => *root,
+            None => Sha256Hash::zero(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.len() {
+            return None;
+        }
+
+        let mut steps = vec![];
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            if sibling < level.len() {
+                steps.push(ProofStep {
+                    hash: level[sibling],
+                    is_left: sibling < position,
+                });
+            }
+            position /= 2;
+        }
+
+        Some(MerkleProof { steps: steps })
+    }
+
+    fn hash_leaf(leaf: &Sha256Hash) -> Sha256Hash {
+        Sha256Hash::new(&[&vec![LEAF_PREFIX], &leaf.to_bytes().to_vec()])
+    }
+
+    fn hash_node(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+        Sha256Hash::new(&[
+            &vec![NODE_PREFIX],
+            &left.to_bytes().to_vec(),
+            &right.to_bytes().to_vec(),
+        ])
+    }
+}
+
+impl SparseMerkleTree {
+    pub const DEPTH: u32 = Sha256Hash::BITS;
+
+    pub fn new() -> Self {
+        SparseMerkleTree {
+            nodes: im::HashMap::new(),
+        }
+    }
+
+    // the root of a tree without leaf is the zero hash
+    pub fn root(&self) -> Sha256Hash {
+        self.node(0, &Sha256Hash::zero())
+    }
+
+    // puts `leaf` in the slot of `key`, `None` empties it
+    pub fn set(&mut self, key: &Sha256Hash, leaf: Option<&Sha256Hash>) {
+        let mut hash = match leaf {
+            Some(leaf) => MerkleTree::hash_leaf(leaf),
+            None => Sha256Hash::zero(),
+        };
+
+        let mut depth = Self::DEPTH;
+        loop {
+            let prefix = prefix(key, depth);
+            if hash == Sha256Hash::zero() {
+                self.nodes.remove(&(depth, prefix));
+            } else {
+                self.nodes.insert((depth, prefix), hash);
+            }
+            if depth == 0 {
+                return;
+            }
+
+            let sibling = self.node(depth, &flip(&prefix, depth - 1));
+            hash = match bit(key, depth - 1) {
+                false => SparseMerkleTree::hash_node(&hash, &sibling),
+                true => SparseMerkleTree::hash_node(&sibling, &hash),
+            };
+            depth -= 1;
+        }
+    }
+
+    // the siblings of the path from the slot of `key` up to the root
+    pub fn proof(&self, key: &Sha256Hash) -> MerkleProof {
+        let mut steps = Vec::with_capacity(Self::DEPTH as usize);
+        for depth in (1..=Self::DEPTH).rev() {
+            steps.push(ProofStep {
+                hash: self.node(depth, &flip(&prefix(key, depth), depth - 1)),
+                is_left: bit(key, depth - 1),
+            });
+        }
+
+        MerkleProof { steps: steps }
+    }
+
+    // `leaf` is in the slot of `key`: unlike `MerkleProof::verify` the path
+    // must be the one given by the key
+    pub fn verify(
+        proof: &MerkleProof,
+        key: &Sha256Hash,
+        leaf: &Sha256Hash,
+        root: &Sha256Hash,
+    ) -> bool {
+        proof.steps.len() == Self::DEPTH as usize
+            && proof
+                .steps
+                .iter()
+                .enumerate()
+                .all(|(i, step)| step.is_left == bit(key, Self::DEPTH - 1 - i as u32))
+            && proof.verify(leaf, root)
+    }
+
+    fn node(&self, depth: u32, prefix: &Sha256Hash) -> Sha256Hash {
+        match self.nodes.get(&(depth, *prefix)) {
+            Some(hash) => *hash,
+            None => Sha256Hash::zero(),
+        }
+    }
+
+    // two empty subtrees make an empty subtree, a path to a leaf is never empty
+    fn hash_node(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+        if *left == Sha256Hash::zero() && *right == Sha256Hash::zero() {
+            return Sha256Hash::zero();
+        }
+        MerkleTree::hash_node(left, right)
+    }
+}
+
+// the bit `i` of `key`, the first one is the most significant
+fn bit(key: &Sha256Hash, i: u32) -> bool {
+    (key.arr_u128[(i / 128) as usize] >> (127 - i % 128)) & 1 == 1
+}
+
+fn flip(key: &Sha256Hash, i: u32) -> Sha256Hash {
+    let mut arr_u128 = key.arr_u128;
+    arr_u128[(i / 128) as usize] ^= 1 << (127 - i % 128);
+    Sha256Hash { arr_u128: arr_u128 }
+}
+
+// the first `depth` bits of `key`, the others are cleared
+fn prefix(key: &Sha256Hash, depth: u32) -> Sha256Hash {
+    let mut arr_u128 = key.arr_u128;
+    for (i, word) in arr_u128.iter_mut().enumerate() {
+        let kept = depth.saturating_sub(128 * i as u32).min(128);
+        if kept < 128 {
+            *word &= !(u128::MAX >> kept);
+        }
+    }
+    Sha256Hash { arr_u128: arr_u128 }
+}
+
+impl Default for SparseMerkleTree {
+    fn default() -> Self {
+        SparseMerkleTree::new()
+    }
+}
+
+impl MerkleProof {
+    pub fn verify(&self, leaf: &Sha256Hash, root: &Sha256Hash) -> bool {
+        let mut hash = MerkleTree::hash_leaf(leaf);
+        for step in &self.steps {
+            hash = match step.is_left {
+                true => MerkleTree::hash_node(&step.hash, &hash),
+                false => MerkleTree::hash_node(&hash, &step.hash),
+            };
+        }
+        hash == *root
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+impl Writable for ProofStep {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.hash) //
+            .and_then(|_| write_u32(writer, self.is_left as u32))
+    }
+}
+
+impl Readable for ProofStep {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut step = ProofStep {
+            hash: Sha256Hash::zero(),
+            is_left: false,
+        };
+        let mut is_left: u32 = 0;
+
+        match read_struct(reader, &mut step.hash) //
+            .and_then(|_| read_u32(reader, &mut is_left))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+        step.is_left = is_left != 0;
+
+        Ok(step)
+    }
+}
+
+impl Writable for MerkleProof {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_vec_struct(writer, &self.steps)
+    }
+}
+
+impl Readable for MerkleProof {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut proof = MerkleProof { steps: vec![] };
+
+        match read_vec_struct(reader, &mut proof.steps) {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(proof)
+    }
+}
@@ -1,9 +1,11 @@
 use crate::{
     block::{Block, BlockContent},
     error::Error,
+    state::BlockTransaction,
 };
 
 use super::sha256::Sha256Hash;
+use super::target::Target;
 use core::time;
 use rand::Rng;
 use std::{
@@ -14,26 +16,24 @@ type OnMined = fn(BlockContent, u128, Sha256Hash);
 pub struct Miner {
     block_content: BlockContent,
     on_mined: OnMined,
-    difficulty: u128,
 }
 
 impl Miner {
-    pub fn new(block_content: BlockContent, on_mined: OnMined, difficulty: u128) -> Self {
+    pub fn new(block_content: BlockContent, on_mined: OnMined) -> Self {
         Miner {
             block_content: block_content,
             on_mined: on_mined,
-            difficulty: difficulty,
         }
     }
 
-    pub fn mine(block_content: &BlockContent, difficulty: u128) -> (u128, Sha256Hash) {
-        let bytes = block_content.to_bytes();
+    pub fn mine<T: BlockTransaction>(block_content: &BlockContent<T>) -> (u128, Sha256Hash) {
+        let bytes = block_content.header_bytes();
         let mut rng = rand::thread_rng();
 
-        for _ in 0..(u128::max_value()) {
-            let proof_of_work = rng.gen_range(0, u128::max_value());
+        for _ in 0..(u128::MAX) {
+            let proof_of_work = rng.gen_range(0, u128::MAX);
             let hash = Sha256Hash::new(&[&bytes, &proof_of_work.to_be_bytes().to_vec()]);
-            if Miner::check_difficulty(&hash, difficulty) {
+            if Miner::check_difficulty(&hash, &block_content.target) {
                 return (proof_of_work, hash);
             }
         }
@@ -41,19 +41,18 @@ impl Miner {
         panic!("unreachable code!");
     }
 
-    pub fn interuptable_mining(
-        block_content: BlockContent,
-        difficulty: u128,
+    pub fn interuptable_mining<T: BlockTransaction>(
+        block_content: BlockContent<T>,
         stop: Receiver<()>,
     ) -> Result<(u128, Sha256Hash), Error> {
-        let bytes = block_content.to_bytes();
+        let bytes = block_content.header_bytes();
         let mut rng = rand::thread_rng();
 
         loop {
             for _ in 0..2048 {
-                let proof_of_work = rng.gen_range(0, u128::max_value());
+                let proof_of_work = rng.gen_range(0, u128::MAX);
                 let hash = Sha256Hash::new(&[&bytes, &proof_of_work.to_be_bytes().to_vec()]);
-                if Miner::check_difficulty(&hash, difficulty) {
+                if Miner::check_difficulty(&hash, &block_content.target) {
                     return Ok((proof_of_work, hash));
                 }
             }
@@ -64,12 +63,7 @@ impl Miner {
         }
     }
 
-    pub fn async_mine(
-        difficulty: u128,
-        result: Sender<Block>,
-        start: Receiver<BlockContent>,
-        stop: Receiver<()>,
-    ) {
+    pub fn async_mine(result: Sender<Block>, start: Receiver<BlockContent>, stop: Receiver<()>) {
         thread::spawn(move || {
             let (mut stopis, mut stopri) = mpsc::channel();
             let (mut resultis, mut resultir) = mpsc::channel();
@@ -97,14 +91,11 @@ impl Miner {
                         (resultis, resultir) = mpsc::channel();
 
                         thread::spawn(move || {
-                            let (proof_of_work, hash) = match Miner::interuptable_mining(
-                                block_content.clone(),
-                                difficulty,
-                                stopri,
-                            ) {
-                                Ok(v) => v,
-                                Err(_) => return,
-                            };
+                            let (proof_of_work, hash) =
+                                match Miner::interuptable_mining(block_content.clone(), stopri) {
+                                    Ok(v) => v,
+                                    Err(_) => return,
+                                };
                             resultis
                                 .send(Block::new_mined(block_content, proof_of_work, hash))
                                 .ok();
@@ -119,16 +110,15 @@ impl Miner {
 
     pub fn start_mining(&self) -> JoinHandle<()> {
         let on_mined = self.on_mined;
-        let difficulty = self.difficulty;
         let block_content = self.block_content.clone();
 
         thread::spawn(move || {
-            let (proof_of_work, hash) = Miner::mine(&block_content, difficulty);
+            let (proof_of_work, hash) = Miner::mine(&block_content);
             (on_mined)(block_content, proof_of_work, hash);
         })
     }
 
-    pub fn check_difficulty(hash: &Sha256Hash, difficulty: u128) -> bool {
-        (hash.as_u128()[0] & difficulty) == 0
+    pub fn check_difficulty(hash: &Sha256Hash, target: &Target) -> bool {
+        target.is_met_by(hash)
     }
 }
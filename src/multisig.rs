@@ -0,0 +1,225 @@
+use std::io::Read;
+use std::io::Write;
+
+use ed25519_dalek::Signature;
+
+use crate::error::Error;
+use crate::reader::{read_signature, read_struct, read_u32, read_vec_len, Readable};
+use crate::signature::Signable;
+use crate::transaction::{Transaction, TransactionContent, Witness};
+use crate::user::{PublicUser, User};
+use crate::writer::{write_signature, write_struct, write_u32, write_vec_struct, Writable};
+
+// An account controlled by `threshold` of `keys`. Its address is a point
+// derived from the sorted keys and the threshold: nobody knows its secret
+// key, it can only be spent with a `Witness::Multisig`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MultisigAccount {
+    pub threshold: u32,
+    pub keys: Vec<PublicUser>, // sorted, without duplicates
+}
+
+// The signature of one of the keys of a multisig account.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PartialSignature {
+    pub signer: PublicUser,
+    pub signature: Signature,
+}
+
+// Gathers the partial signatures of a multisig spending. Each signer signs
+// the content on their own machine with `PartialSignature::new` and sends
+// it back, the transaction is built once the threshold is reached.
+pub struct MultisigCollector {
+    pub account: MultisigAccount,
+    pub content: TransactionContent,
+    pub signatures: Vec<PartialSignature>,
+}
+
+impl MultisigAccount {
+    pub const MAX_KEYS: usize = 16;
+
+    pub fn new(threshold: u32, mut keys: Vec<PublicUser>) -> Result<Self, Error> {
+        keys.sort();
+        keys.dedup();
+
+        if threshold == 0 || threshold as usize > keys.len() || keys.len() > Self::MAX_KEYS {
+            return Err(Error::InvalidMultisigAccount);
+        }
+
+        Ok(MultisigAccount {
+            threshold: threshold,
+            keys: keys,
+        })
+    }
+
+    pub fn zero() -> Self {
+        MultisigAccount {
+            threshold: 0,
+            keys: vec![],
+        }
+    }
+
+    // The only accepted order of the signatures of a witness: at most one per
+    // key, sorted by signer. Anything else could be relayed under another
+    // encoding, and is refused before any signature is checked.
+    pub fn is_canonical(&self, signatures: &[PartialSignature]) -> bool {
+        signatures.len() <= self.keys.len()
+            && signatures
+                .iter()
+                .all(|s| self.keys.binary_search(&s.signer).is_ok())
+            && signatures.windows(2).all(|w| w[0].signer < w[1].signer)
+    }
+
+    pub fn address(&self) -> PublicUser {
+        PublicUser::derive(&self.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+impl Writable for MultisigAccount {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_u32(writer, self.threshold).and_then(|_| write_vec_struct(writer, &self.keys))
+    }
+}
+
+impl Readable for MultisigAccount {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut threshold: u32 = 0;
+        let mut len: usize = 0;
+
+        match read_u32(reader, &mut threshold).and_then(|_| read_vec_len(reader, &mut len)) {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+        // refused before anything is read
+        if len > MultisigAccount::MAX_KEYS {
+            return Err(Error::InvalidFormat);
+        }
+
+        let mut keys = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut key = PublicUser::zero();
+            match read_struct(reader, &mut key) {
+                Ok(_) => keys.push(key),
+                Err(_) => return Err(Error::InvalidFormat),
+            };
+        }
+
+        // a reordered key set would have another address
+        match MultisigAccount::new(threshold, keys.clone()) {
+            Ok(account) if account.keys == keys => Ok(account),
+            _ => Err(Error::InvalidFormat),
+        }
+    }
+}
+
+impl PartialSignature {
+    pub fn new<S: Signable>(content: &S, signer: &User) -> Self {
+        PartialSignature {
+            signer: signer.as_public(),
+            signature: content.sign(&signer.key_pair),
+        }
+    }
+
+    pub fn zero() -> Self {
+        PartialSignature {
+            signer: PublicUser::zero(),
+            signature: Signature::from_bytes(&[0; 64]).unwrap(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+impl Writable for PartialSignature {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.signer).and_then(|_| write_signature(writer, &self.signature))
+    }
+}
+
+impl Readable for PartialSignature {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut partial_signature = PartialSignature::zero();
+
+        match read_struct(reader, &mut partial_signature.signer)
+            .and_then(|_| read_signature(reader, &mut partial_signature.signature))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(partial_signature)
+    }
+}
+
+impl MultisigCollector {
+    pub fn new(account: MultisigAccount, content: TransactionContent) -> Self {
+        MultisigCollector {
+            account: account,
+            content: content,
+            signatures: vec![],
+        }
+    }
+
+    // refuses the signatures of outsiders and the ones that do not sign the content
+    pub fn add(&mut self, partial_signature: PartialSignature) -> Result<(), Error> {
+        if !self.account.keys.contains(&partial_signature.signer)
+            || !self
+                .content
+                .verify(partial_signature.signer.key, partial_signature.signature)
+        {
+            return Err(Error::WrongTransactionSignature);
+        }
+        if self
+            .signatures
+            .iter()
+            .any(|s| s.signer == partial_signature.signer)
+        {
+            return Ok(());
+        }
+        self.signatures.push(partial_signature);
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.signatures.len() >= self.account.threshold as usize
+    }
+
+    pub fn finish(self) -> Result<Transaction, Error> {
+        if !self.is_complete() {
+            return Err(Error::NotEnoughSignatures);
+        }
+        let mut signatures = self.signatures;
+        signatures.sort_by_key(|s| s.signer);
+        Ok(Transaction {
+            content: self.content,
+            witness: Witness::Multisig {
+                account: self.account,
+                signatures: signatures,
+            },
+        })
+    }
+}
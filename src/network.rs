@@ -15,6 +15,9 @@ use std::sync::mpsc::Sender;
 use std::thread;
 use std::thread::JoinHandle;
 
+// a block and the peer that sent it
+pub type ReceivedBlock = (Block, String);
+
 #[derive(Copy, Clone)]
 pub enum PacketKind {
     None = 0,
@@ -26,7 +29,7 @@ pub enum PacketKind {
 
 pub fn listener(
     addr: String,
-    block_sender: Sender<Block>,
+    block_sender: Sender<ReceivedBlock>,
     transaction_sender: Sender<Transaction>,
     ask_merkle_sender: Sender<String>,
     receive_merkle_sender: Sender<Merkle>,
@@ -67,8 +70,13 @@ pub fn listener(
                         Ok(v) => v,
                         Err(_) => continue,
                     };
+                    // the port of an incoming stream changes, the peer is its ip
+                    let peer = match stream.peer_addr() {
+                        Ok(v) => v.ip().to_string(),
+                        Err(_) => continue,
+                    };
 
-                    match block_sender.send(block) {
+                    match block_sender.send((block, peer)) {
                         Ok(_) => (),
                         Err(_) => continue,
                     };
@@ -114,7 +122,7 @@ pub fn send_transaction_at(transaction: &Transaction, addr: &String) -> Result<(
         .and_then(|_| write_struct(&mut msg, transaction))
         .ok();
 
-    match stream.write(&msg) {
+    match stream.write_all(&msg) {
         Ok(_) => (),
         Err(_) => return Err(Error::TcpFailToSend),
     };
@@ -136,7 +144,7 @@ pub fn send_block_at(block: &Block, addr: &String) -> Result<(), Error> {
         .and_then(|_| write_struct(&mut msg, block))
         .ok();
 
-    match stream.write(&msg) {
+    match stream.write_all(&msg) {
         Ok(_) => (),
         Err(_) => return Err(Error::TcpFailToSend),
     };
@@ -158,7 +166,7 @@ pub fn send_merkle_at(merkle: &Merkle, addr: &String) -> Result<(), Error> {
         .and_then(|_| write_struct(&mut msg, merkle))
         .ok();
 
-    match stream.write(&msg) {
+    match stream.write_all(&msg) {
         Ok(_) => (),
         Err(_) => return Err(Error::TcpFailToSend),
     };
@@ -178,7 +186,7 @@ pub fn ask_merkle_at(addr: &String, callback_addr: String) -> Result<(), Error>
         .and_then(|_| write_string(&mut msg, callback_addr))
         .ok();
 
-    match stream.write(&msg) {
+    match stream.write_all(&msg) {
         Ok(_) => (),
         Err(_) => return Err(Error::TcpFailToSend),
     };
@@ -188,12 +196,12 @@ pub fn ask_merkle_at(addr: &String, callback_addr: String) -> Result<(), Error>
 
 pub fn broadcast_block(block: &Block, connected_addr: &Vec<String>) {
     for addr in connected_addr {
-        send_block_at(&block, addr).ok();
+        send_block_at(block, addr).ok();
     }
 }
 
 pub fn broadcast_transaction(transaction: &Transaction, connected_addr: &Vec<String>) {
     for addr in connected_addr {
-        send_transaction_at(&transaction, addr).ok();
+        send_transaction_at(transaction, addr).ok();
     }
 }
@@ -1,11 +1,19 @@
 use crate::{
     block::{Block, BlockContent},
+    consensus::{
+        KnownSender, Params, Rejection, Rule, SequenceIsNext, SufficientFunds, TransactionContext,
+    },
     error::Error,
-    ledger::PartialLedger,
-    merkle::Merkle,
+    ledger::{Ledger, PartialLedger},
+    merkle::{BlockOutcome, Merkle},
     mining::Miner,
-    network::{ask_merkle_at, broadcast_block, broadcast_transaction, listener, send_merkle_at},
-    transaction::Transaction,
+    network::{
+        ask_merkle_at, broadcast_block, broadcast_transaction, listener, send_merkle_at,
+        ReceivedBlock,
+    },
+    orphan::OrphanPool,
+    time::Time,
+    transaction::{Lock, Transaction},
     user::PublicUser,
 };
 use core::time;
@@ -15,7 +23,7 @@ use std::{
 };
 
 pub struct NodeAsync {
-    network_block_receiver: Receiver<Block>,
+    network_block_receiver: Receiver<ReceivedBlock>,
     ask_merkle_receiver: Receiver<String>,
     receive_merkle_receiver: Receiver<Merkle>,
     transaction_receiver: Receiver<Transaction>,
@@ -26,9 +34,11 @@ pub struct NodeAsync {
 }
 
 impl NodeAsync {
-    pub fn new(addr: Option<String>, difficulty: Option<u128>) -> Self {
-        let (network_block_sender, network_block_receiver): (Sender<Block>, Receiver<Block>) =
-            mpsc::channel();
+    pub fn new(addr: Option<String>, mining: bool) -> Self {
+        let (network_block_sender, network_block_receiver): (
+            Sender<ReceivedBlock>,
+            Receiver<ReceivedBlock>,
+        ) = mpsc::channel();
         let (mine_success_sender, mine_success_receiver): (Sender<Block>, Receiver<Block>) =
             mpsc::channel();
         let (mine_start_sender, mine_start_receiver): (
@@ -59,17 +69,9 @@ impl NodeAsync {
             None => (),
         };
 
-        match difficulty {
-            Some(v) => {
-                Miner::async_mine(
-                    v, //
-                    mine_success_sender,
-                    mine_start_receiver,
-                    mine_stop_receiver,
-                );
-            }
-            None => (),
-        };
+        if mining {
+            Miner::async_mine(mine_success_sender, mine_start_receiver, mine_stop_receiver);
+        }
 
         NodeAsync {
             network_block_receiver: network_block_receiver,
@@ -94,9 +96,14 @@ impl NodeAsync {
             Ok(_) => (),
             Err(_) => return Err(Error::FailToGetMerkle),
         };
-        match self.receive_merkle_receiver.recv() {
-            Ok(v) => Ok(v),
-            Err(_) => Err(Error::FailToGetMerkle),
+        let merkle = match self.receive_merkle_receiver.recv() {
+            Ok(v) => v,
+            Err(_) => return Err(Error::FailToGetMerkle),
+        };
+        // nothing of a received tree is trusted, not even its ledgers
+        match merkle.verify_full() {
+            Ok(_) => Ok(merkle),
+            Err(rejection) => Err(rejection.error),
         }
     }
 }
@@ -104,39 +111,322 @@ impl NodeAsync {
 pub struct NodeCache {
     pub pending_transactions: Vec<Transaction>,
     pub partial_ledger: PartialLedger,
+    // transactions received before the previous transaction of their sender,
+    // they are moved to `pending_transactions` once the gap is filled. A
+    // sender may only have a few of them, close to its next sequence.
+    pub future_transactions: Vec<Transaction>,
+    // transactions waiting for their lock height or time, they are moved to
+    // `pending_transactions` by `release_locked`. A full pool drops the one
+    // released last.
+    pub locked_transactions: Vec<Transaction>,
 }
 
 impl NodeCache {
+    pub const MAX_FUTURE_TRANSACTIONS: usize = 256;
+    pub const MAX_FUTURE_TRANSACTIONS_PER_SENDER: usize = 16;
+    pub const MAX_SEQUENCE_GAP: u64 = 16;
+    pub const MAX_LOCKED_TRANSACTIONS: usize = 256;
+    pub const MAX_LOCKED_TRANSACTIONS_PER_SENDER: usize = 16;
+
     pub fn new() -> Self {
         NodeCache {
             pending_transactions: vec![],
             partial_ledger: PartialLedger::empty(),
+            future_transactions: vec![],
+            locked_transactions: vec![],
         }
     }
 
+    // the future and locked transactions are kept, they may still fill in later
     pub fn clear(&mut self) {
         self.pending_transactions.clear();
         self.partial_ledger = PartialLedger::empty();
     }
 
     pub fn push(&mut self, merkle: &Merkle, transaction: &Transaction) -> Result<(), Error> {
+        match self.submit(merkle, transaction) {
+            Ok(_) => Ok(()),
+            Err(rejection) => Err(rejection.error),
+        }
+    }
+
+    // same as push but reports which consensus rule rejected the transaction
+    pub fn submit(&mut self, merkle: &Merkle, transaction: &Transaction) -> Result<(), Rejection> {
+        let (index, now) = NodeCache::next_block(merkle);
+        if transaction.is_expired_at(index) {
+            return Err(Rejection::new("not-expired", Error::TransactionIsExpired));
+        }
+        if !transaction.is_unlocked_at(index, &now) {
+            return self.hold_locked(merkle, transaction);
+        }
+        match transaction.verify_script(index, &now) {
+            Ok(_) => (),
+            Err(e) => return Err(Rejection::new("script-succeeds", e)),
+        };
+
         let ledger = &merkle.main().ledger;
-        match self.partial_ledger.apply_transaction(ledger, &transaction) {
+        let context = TransactionContext::new(ledger, &self.partial_ledger, None);
+        match merkle.rules.check_transaction(transaction, &context) {
             Ok(_) => (),
+            Err(e) if e.error == Error::TransactionSequenceIsNotNext => {
+                return self.hold(ledger, transaction, e);
+            }
             Err(e) => return Err(e),
         };
+        match self
+            .partial_ledger
+            .apply_transaction(ledger, transaction, index)
+        {
+            Ok(_) => (),
+            Err(e) => return Err(e.into()),
+        };
 
         self.pending_transactions.push(transaction.clone());
+        self.promote(merkle, transaction);
+
+        Ok(())
+    }
+
+    fn hold(
+        &mut self,
+        ledger: &Ledger,
+        transaction: &Transaction,
+        rejection: Rejection,
+    ) -> Result<(), Rejection> {
+        if self.future_transactions.contains(transaction) {
+            return Ok(());
+        }
+        if !self.is_within_sequence_gap(ledger, transaction) {
+            return Err(rejection);
+        }
+        // drop the transactions whose sequence was used in the meantime
+        self.future_transactions.retain(|t| !ledger.contains(t));
+        let from_sender = self
+            .future_transactions
+            .iter()
+            .filter(|t| t.content.from == transaction.content.from)
+            .count();
+        if from_sender >= NodeCache::MAX_FUTURE_TRANSACTIONS_PER_SENDER
+            || self.future_transactions.len() >= NodeCache::MAX_FUTURE_TRANSACTIONS
+        {
+            return Err(rejection);
+        }
+        self.future_transactions.push(transaction.clone());
+        Ok(())
+    }
+
+    // The lock and the script depend on the block, so the transaction is
+    // checked again once released. Until then its sender must exist and be
+    // able to pay it, and its sequence may be ahead as for a future one.
+    fn hold_locked(&mut self, merkle: &Merkle, transaction: &Transaction) -> Result<(), Rejection> {
+        if !transaction.verify_signature() {
+            return Err(Rejection::new(
+                "valid-signature",
+                Error::WrongTransactionSignature,
+            ));
+        }
+        if self.locked_transactions.contains(transaction) {
+            return Ok(());
+        }
 
+        let ledger = &merkle.main().ledger;
+        let context = TransactionContext::new(ledger, &self.partial_ledger, None);
+        let within_gap = self.is_within_sequence_gap(ledger, transaction);
+        let rules: [&dyn Rule; 3] = [&KnownSender, &SequenceIsNext, &SufficientFunds];
+        for rule in rules {
+            match rule.check_transaction(transaction, &context) {
+                Ok(_) => (),
+                Err(Error::TransactionSequenceIsNotNext) if within_gap => (),
+                Err(e) => return Err(Rejection::new(rule.name(), e)),
+            }
+        }
+
+        let locked = Rejection::new("lock-is-released", Error::TransactionIsLocked);
+        let from_sender = self
+            .locked_transactions
+            .iter()
+            .filter(|t| t.content.from == transaction.content.from)
+            .count();
+        if from_sender >= NodeCache::MAX_LOCKED_TRANSACTIONS_PER_SENDER {
+            return Err(locked);
+        }
+        if self.locked_transactions.len() >= NodeCache::MAX_LOCKED_TRANSACTIONS {
+            let (index, now) = NodeCache::next_block(merkle);
+            let release = |t: &Transaction| NodeCache::release_time(t, index, &now, &merkle.params);
+            let last = match self
+                .locked_transactions
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, t)| release(t))
+            {
+                Some((i, t)) if release(t) > release(transaction) => i,
+                _ => return Err(locked),
+            };
+            self.locked_transactions.remove(last);
+        }
+        self.locked_transactions.push(transaction.clone());
         Ok(())
     }
+
+    fn is_within_sequence_gap(&self, ledger: &Ledger, transaction: &Transaction) -> bool {
+        match self
+            .partial_ledger
+            .get_user_data(ledger, transaction.content.from)
+        {
+            Some(user_data) => {
+                transaction.content.sequence
+                    <= user_data.next_sequence() + NodeCache::MAX_SEQUENCE_GAP
+            }
+            None => false,
+        }
+    }
+
+    // when the lock is expected to be released, in milliseconds, so that
+    // height and time locks can be compared
+    fn release_time(transaction: &Transaction, index: u32, now: &Time, params: &Params) -> u128 {
+        match transaction.content.lock {
+            None => now.to_u128(),
+            Some(Lock::Height(height)) => {
+                now.to_u128()
+                    + height.saturating_sub(index) as u128 * params.target_block_time as u128
+            }
+            Some(Lock::Time(time)) => time.to_u128(),
+        }
+    }
+
+    // submits the locked transactions that the next block may include
+    pub fn release_locked(&mut self, merkle: &Merkle) {
+        let (index, now) = NodeCache::next_block(merkle);
+        let (released, locked): (Vec<Transaction>, Vec<Transaction>) = self
+            .locked_transactions
+            .drain(..)
+            .partition(|t| t.is_unlocked_at(index, &now));
+        self.locked_transactions = locked;
+
+        for transaction in &released {
+            self.submit(merkle, transaction).ok();
+        }
+    }
+
+    // Drops the transactions that the next block may no longer include, the
+    // claims of timed out hash locks included. The pending ones are
+    // resubmitted, so that the later transactions of their sender wait again
+    // for the missing sequence, and so are the pending ones whose script no
+    // longer succeeds.
+    pub fn evict_expired(&mut self, merkle: &Merkle) {
+        let (index, now) = NodeCache::next_block(merkle);
+        let ledger = &merkle.main().ledger;
+        let is_stale =
+            |t: &Transaction| t.is_expired_at(index) || ledger.is_past_hash_lock_timeout(t, index);
+        self.future_transactions.retain(|t| !is_stale(t));
+        self.locked_transactions.retain(|t| !is_stale(t));
+
+        if !self
+            .pending_transactions
+            .iter()
+            .any(|t| is_stale(t) || t.verify_script(index, &now).is_err())
+        {
+            return;
+        }
+        let pending = std::mem::take(&mut self.pending_transactions);
+        self.partial_ledger = PartialLedger::empty();
+        for transaction in &pending {
+            self.submit(merkle, transaction).ok();
+        }
+    }
+
+    // the index of the block after the main tip, and a lower bound of its timestamp
+    fn next_block(merkle: &Merkle) -> (u32, Time) {
+        let index = merkle.main().last(&merkle.blocks).content.index + 1;
+        (index, Time::now())
+    }
+
+    // after a reorganization, the transactions of the disconnected blocks
+    // that the new main branch did not include are pending again
+    pub fn reorganize(&mut self, merkle: &Merkle, outcome: &BlockOutcome) {
+        let disconnected = match outcome {
+            BlockOutcome::Reorganized { disconnected, .. } => disconnected,
+            _ => return,
+        };
+        // the pending transactions were checked against the old main branch
+        let pending = std::mem::take(&mut self.pending_transactions);
+        self.partial_ledger = PartialLedger::empty();
+
+        for block in disconnected {
+            for transaction in &block.content.transactions {
+                if !transaction.content.from.is_coinbase() {
+                    self.submit(merkle, transaction).ok();
+                }
+            }
+        }
+        for transaction in &pending {
+            self.submit(merkle, transaction).ok();
+        }
+    }
+
+    // submits the held transaction following `transaction`, if any
+    fn promote(&mut self, merkle: &Merkle, transaction: &Transaction) {
+        let from = transaction.content.from;
+        let next_sequence = transaction.content.sequence + 1;
+
+        match self
+            .future_transactions
+            .iter()
+            .position(|t| t.content.from == from && t.content.sequence == next_sequence)
+        {
+            Some(i) => {
+                let next = self.future_transactions.remove(i);
+                self.submit(merkle, &next).ok();
+            }
+            None => (),
+        }
+    }
+}
+
+impl Default for NodeCache {
+    fn default() -> Self {
+        NodeCache::new()
+    }
+}
+
+// adds a block received from `peer`, or keeps it aside until its parent
+// arrives. Returns the blocks that were connected, its orphans included.
+fn connect_block(
+    merkle: &mut Merkle,
+    orphans: &mut OrphanPool,
+    nc: &mut NodeCache,
+    block: Block,
+    peer: &str,
+) -> Vec<Block> {
+    let outcome = match merkle.add_block(block.clone()) {
+        Ok(v) => v,
+        Err(Error::PreviousBlockNotFound) => {
+            orphans
+                .insert(block, peer, &merkle.params, Time::now())
+                .ok();
+            return vec![];
+        }
+        Err(_) => return vec![],
+    };
+
+    let hash = block.hash;
+    let mut connected = vec![(block, outcome)];
+    connected.append(&mut orphans.connect_children(merkle, hash));
+
+    let mut blocks = vec![];
+    for (block, outcome) in connected {
+        nc.reorganize(merkle, &outcome);
+        blocks.push(block);
+    }
+    blocks
 }
 
 pub fn create_genesis_node(addr: String, connected_addr: Vec<String>, public_user: PublicUser) {
     let mut merkle = Merkle::new_from_nothingness();
 
-    let na = NodeAsync::new(Some(addr), Some(Merkle::DIFFICULTY));
+    let na = NodeAsync::new(Some(addr), true);
     let mut nc = NodeCache::new();
+    let mut orphans = OrphanPool::new();
 
     let block_content =
         BlockContent::new_from_pending_transaction(&nc.pending_transactions, &merkle, &public_user);
@@ -144,13 +434,17 @@ pub fn create_genesis_node(addr: String, connected_addr: Vec<String>, public_use
 
     loop {
         match na.network_block_receiver.try_recv() {
-            Ok(block) => {
-                match merkle.add_block(block.clone()) {
-                    Ok(_) => (),
-                    Err(_) => continue,
+            Ok((block, peer)) => {
+                let connected = connect_block(&mut merkle, &mut orphans, &mut nc, block, &peer);
+                if connected.is_empty() {
+                    continue;
                 }
                 na.mining_stop();
-                broadcast_block(&block, &connected_addr);
+                for block in &connected {
+                    broadcast_block(block, &connected_addr);
+                }
+                nc.evict_expired(&merkle);
+                nc.release_locked(&merkle);
                 let block_content = BlockContent::new_from_pending_transaction(
                     &nc.pending_transactions,
                     &merkle,
@@ -166,9 +460,11 @@ pub fn create_genesis_node(addr: String, connected_addr: Vec<String>, public_use
             Ok(block) => {
                 broadcast_block(&block, &connected_addr);
                 match merkle.add_block(block.clone()) {
-                    Ok(_) => (),
+                    Ok(outcome) => nc.reorganize(&merkle, &outcome),
                     Err(_) => continue,
                 }
+                nc.evict_expired(&merkle);
+                nc.release_locked(&merkle);
                 let block_content = BlockContent::new_from_pending_transaction(
                     &nc.pending_transactions,
                     &merkle,
@@ -207,8 +503,9 @@ pub fn create_genesis_node(addr: String, connected_addr: Vec<String>, public_use
 }
 
 pub fn create_full_node(addr: String, connected_addr: Vec<String>, public_user: PublicUser) {
-    let na = NodeAsync::new(Some(addr.clone()), Some(Merkle::DIFFICULTY));
+    let na = NodeAsync::new(Some(addr.clone()), true);
     let mut nc = NodeCache::new();
+    let mut orphans = OrphanPool::new();
 
     let mut merkle = match na.get_merkle(&connected_addr[0], addr) {
         Ok(v) => v,
@@ -217,13 +514,17 @@ pub fn create_full_node(addr: String, connected_addr: Vec<String>, public_user:
 
     loop {
         match na.network_block_receiver.try_recv() {
-            Ok(block) => {
-                match merkle.add_block(block.clone()) {
-                    Ok(_) => (),
-                    Err(_) => continue,
+            Ok((block, peer)) => {
+                let connected = connect_block(&mut merkle, &mut orphans, &mut nc, block, &peer);
+                if connected.is_empty() {
+                    continue;
                 }
                 na.mining_stop();
-                broadcast_block(&block, &connected_addr);
+                for block in &connected {
+                    broadcast_block(block, &connected_addr);
+                }
+                nc.evict_expired(&merkle);
+                nc.release_locked(&merkle);
                 let block_content = BlockContent::new_from_pending_transaction(
                     &nc.pending_transactions,
                     &merkle,
@@ -239,9 +540,11 @@ pub fn create_full_node(addr: String, connected_addr: Vec<String>, public_user:
             Ok(block) => {
                 broadcast_block(&block, &connected_addr);
                 match merkle.add_block(block.clone()) {
-                    Ok(_) => (),
+                    Ok(outcome) => nc.reorganize(&merkle, &outcome),
                     Err(_) => continue,
                 }
+                nc.evict_expired(&merkle);
+                nc.release_locked(&merkle);
                 let block_content = BlockContent::new_from_pending_transaction(
                     &nc.pending_transactions,
                     &merkle,
@@ -280,8 +583,9 @@ pub fn create_full_node(addr: String, connected_addr: Vec<String>, public_user:
 }
 
 pub fn create_debug_node(addr: String, connected_addr: Vec<String>) {
-    let na = NodeAsync::new(Some(addr.clone()), None);
+    let na = NodeAsync::new(Some(addr.clone()), false);
     let mut nc = NodeCache::new();
+    let mut orphans = OrphanPool::new();
 
     let mut merkle = match na.get_merkle(&connected_addr[0], addr) {
         Ok(v) => v,
@@ -290,17 +594,20 @@ pub fn create_debug_node(addr: String, connected_addr: Vec<String>) {
 
     loop {
         match na.network_block_receiver.try_recv() {
-            Ok(block) => {
-                match merkle.add_block(block.clone()) {
-                    Ok(_) => (),
-                    Err(_) => {
-                        continue;
-                    }
+            Ok((block, peer)) => {
+                let connected = connect_block(&mut merkle, &mut orphans, &mut nc, block, &peer);
+                if connected.is_empty() {
+                    continue;
+                }
+                for block in &connected {
+                    broadcast_block(block, &connected_addr);
                 }
-                broadcast_block(&block, &connected_addr);
                 println!("{:?}", merkle.main().ledger);
                 println!("the main blockchain size is: {}", merkle.main().len());
                 println!("There are {} branch: ", merkle.chains.len());
+                for (i, chain) in merkle.chains.iter().enumerate() {
+                    println!("  branch {} has a cumulative work of {}", i, chain.work);
+                }
             }
             Err(_) => {}
         };
@@ -1,4 +1,4 @@
-use std::fmt::{self, Debug, Formatter};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::io::Read;
 use std::io::Write;
 use std::{cmp::Ordering, ops::SubAssign, str::FromStr};
@@ -28,10 +28,6 @@ impl Num {
         }
     }
 
-    pub fn to_string(&self) -> String {
-        self.value.to_string()
-    }
-
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
 
@@ -89,7 +85,7 @@ impl Ord for Num {
 
 impl PartialOrd for Num {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.value.cmp(&other.value))
+        Some(self.cmp(other))
     }
 }
 
@@ -100,6 +96,12 @@ impl PartialEq for Num {
 }
 impl Eq for Num {}
 
+impl Display for Num {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
 impl Debug for Num {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}", &self.value.to_string())
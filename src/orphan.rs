@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use crate::{
+    block::Block,
+    consensus::Params,
+    error::Error,
+    merkle::{BlockOutcome, Merkle},
+    sha256::Sha256Hash,
+    time::Time,
+};
+
+// A block received before its parent. It is kept until the parent is
+// connected, the pool expires it, or a newer orphan pushes it out.
+struct Orphan {
+    block: Block,
+    peer: String,
+    received: Time,
+    size: usize, // serialized size of the block
+}
+
+pub struct OrphanPool {
+    orphans: HashMap<Sha256Hash, Vec<Orphan>>, // by prev_block_hash
+    pub max_bytes: usize,                      // bound on the size of the pooled blocks
+    pub max_bytes_per_peer: usize,             // same, for the blocks of a single peer
+}
+
+impl OrphanPool {
+    pub const MAX_ORPHANS: usize = 256;
+    pub const MAX_ORPHANS_PER_PEER: usize = 32;
+    pub const MAX_BYTES: usize = 32 * 1_000_000;
+    pub const MAX_BYTES_PER_PEER: usize = 4 * 1_000_000;
+    pub const EXPIRY: u128 = 20 * 60 * 1000; // ms
+
+    pub fn new() -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            max_bytes: Self::MAX_BYTES,
+            max_bytes_per_peer: Self::MAX_BYTES_PER_PEER,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.orphans.values().flatten().map(|o| o.size).sum()
+    }
+
+    pub fn contains(&self, block: &Block) -> bool {
+        match self.orphans.get(&block.content.prev_block_hash) {
+            Some(children) => children.iter().any(|o| o.block.hash == block.hash),
+            None => false,
+        }
+    }
+
+    // The parent is unknown, so only what the block claims about itself is
+    // checked: its hash, its work against its own target and its size. As
+    // orphans are cheap to mine, a peer over its quota of orphans or of bytes
+    // is refused so that it cannot push out the orphans of the other peers.
+    // A full pool drops its oldest orphans.
+    pub fn insert(
+        &mut self,
+        block: Block,
+        peer: &str,
+        params: &Params,
+        now: Time,
+    ) -> Result<(), Error> {
+        if self.contains(&block) {
+            return Ok(());
+        }
+        if block.hash != block.hash() {
+            return Err(Error::BlockHashIsInvalid);
+        }
+        if block.content.target.to_big_uint() > params.pow_limit.to_big_uint() {
+            return Err(Error::BlockTargetIsInvalid);
+        }
+        if !block.content.target.is_met_by(&block.hash) {
+            return Err(Error::BlockProofOfWorkIsNotDone);
+        }
+        let size = block.content.size();
+        if size > params.max_block_size {
+            return Err(Error::BlockIsTooLarge);
+        }
+        self.expire(now);
+
+        let from_peer: Vec<&Orphan> = self
+            .orphans
+            .values()
+            .flatten()
+            .filter(|o| o.peer == peer)
+            .collect();
+        let peer_bytes: usize = from_peer.iter().map(|o| o.size).sum();
+        if from_peer.len() >= OrphanPool::MAX_ORPHANS_PER_PEER
+            || peer_bytes + size > self.max_bytes_per_peer
+        {
+            return Err(Error::TooManyOrphansFromPeer);
+        }
+        while !self.is_empty()
+            && (self.len() >= OrphanPool::MAX_ORPHANS || self.bytes() + size > self.max_bytes)
+        {
+            self.remove_oldest();
+        }
+
+        self.orphans
+            .entry(block.content.prev_block_hash)
+            .or_default()
+            .push(Orphan {
+                block: block,
+                peer: peer.to_string(),
+                received: now,
+                size: size,
+            });
+        Ok(())
+    }
+
+    pub fn expire(&mut self, now: Time) {
+        let now = now.to_u128();
+        for children in self.orphans.values_mut() {
+            children.retain(|o| o.received.to_u128() + OrphanPool::EXPIRY > now);
+        }
+        self.orphans.retain(|_, children| !children.is_empty());
+    }
+
+    // connects the orphans waiting for `parent`, then theirs, and so on.
+    // Returns the blocks that made it into the merkle, in connection order,
+    // with what each of them did to the main branch.
+    pub fn connect_children(
+        &mut self,
+        merkle: &mut Merkle,
+        parent: Sha256Hash,
+    ) -> Vec<(Block, BlockOutcome)> {
+        let mut connected = vec![];
+        let mut parents = vec![parent];
+
+        while let Some(hash) = parents.pop() {
+            let children = match self.orphans.remove(&hash) {
+                Some(v) => v,
+                None => continue,
+            };
+            for orphan in children {
+                match merkle.submit_block(orphan.block.clone()) {
+                    Ok(outcome) => {
+                        parents.push(orphan.block.hash);
+                        connected.push((orphan.block, outcome));
+                    }
+                    Err(_) => (), // an invalid orphan is dropped with its rejection
+                }
+            }
+        }
+
+        connected
+    }
+
+    fn remove_oldest(&mut self) {
+        let oldest = self
+            .orphans
+            .iter()
+            .flat_map(|(parent, children)| children.iter().map(move |o| (*parent, o)))
+            .min_by_key(|(_, o)| o.received.to_u128())
+            .map(|(parent, o)| (parent, o.block.hash));
+
+        match oldest {
+            Some((parent, hash)) => {
+                if let Some(children) = self.orphans.get_mut(&parent) {
+                    children.retain(|o| o.block.hash != hash);
+                    if children.is_empty() {
+                        self.orphans.remove(&parent);
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::new()
+    }
+}
@@ -1,6 +1,7 @@
 use std::io::Read;
 
 use ed25519_dalek::Signature;
+use num::BigUint;
 
 use crate::error::Error;
 
@@ -19,6 +20,32 @@ pub fn read_u32(reader: &mut dyn Read, u: &mut u32) -> Result<(), Error> {
     Ok(())
 }
 
+pub fn read_option_u32(reader: &mut dyn Read, u: &mut Option<u32>) -> Result<(), Error> {
+    let mut presence: [u8; 1] = [0];
+    match reader.read_exact(&mut presence[..]) {
+        Ok(_) => (),
+        Err(_) => return Err(Error::EndOfBuffer),
+    };
+
+    match presence[0] {
+        0 => {
+            *u = None;
+            Ok(())
+        }
+        1 => {
+            let mut value: u32 = 0;
+            match read_u32(reader, &mut value) {
+                Ok(_) => {
+                    *u = Some(value);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            }
+        }
+        _ => Err(Error::InvalidFormat),
+    }
+}
+
 pub fn read_u64(reader: &mut dyn Read, u: &mut u64) -> Result<(), Error> {
     const LEN: usize = (u64::BITS / 8) as usize;
 
@@ -196,6 +223,42 @@ pub fn read_string(reader: &mut dyn Read, str: &mut String) -> Result<(), Error>
     Ok(())
 }
 
+// at most `max` bytes, a longer length is refused before anything is allocated
+pub fn read_bytes(reader: &mut dyn Read, v: &mut Vec<u8>, max: usize) -> Result<(), Error> {
+    let mut len: usize = 0;
+    match read_vec_len(reader, &mut len) {
+        Ok(_) => (),
+        Err(_) => return Err(Error::EndOfBuffer),
+    };
+    if len > max {
+        return Err(Error::InvalidFormat);
+    }
+
+    let mut slice = vec![0; len];
+
+    match reader.read_exact(&mut slice[..]) {
+        Ok(s) => s,
+        Err(_) => return Err(Error::InvalidFormat),
+    };
+
+    *v = slice;
+    Ok(())
+}
+
+pub fn read_big_uint(reader: &mut dyn Read, u: &mut BigUint) -> Result<(), Error> {
+    let mut str = String::new();
+    match read_string(reader, &mut str) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    };
+
+    match BigUint::parse_bytes(str.as_bytes(), 10) {
+        Some(v) => *u = v,
+        None => return Err(Error::InvalidFormat),
+    };
+    Ok(())
+}
+
 pub fn read_signature(reader: &mut dyn Read, sign: &mut Signature) -> Result<(), Error> {
     const LEN: usize = Signature::BYTE_SIZE;
 
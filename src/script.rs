@@ -0,0 +1,367 @@
+use std::io::Read;
+use std::io::Write;
+
+use ed25519_dalek::{PublicKey, Signature};
+
+use crate::error::Error;
+use crate::reader::{read_bytes, read_struct, read_u32, read_vec_len, Readable};
+use crate::sha256::Sha256Hash;
+use crate::signature::Signable;
+use crate::time::Time;
+use crate::transaction::TransactionContent;
+use crate::user::PublicUser;
+use crate::writer::{write_bytes, write_u32, write_vec_struct, Writable};
+
+// An instruction of a spending script. The operands are taken from the top
+// of the stack, `true` is pushed as [1] and `false` as [], any item with a
+// non zero byte is true.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Op {
+    Push(Vec<u8>),
+    Dup,
+    Drop,
+    Swap,
+    // pops two items, pushes whether they are equal
+    Equal,
+    // pops an item, pushes its hash
+    Sha256,
+    // pops a key then a signature, pushes whether the key signed the content
+    CheckSig,
+    // pops a big endian u32, pushes whether the block is at least that high
+    CheckHeight,
+    // pops a big endian u128 time, pushes whether the block is not older
+    CheckTime,
+    Not,
+    And,
+    Or,
+    // pops an item, fails unless it is true
+    Verify,
+}
+
+// Conditions an account is locked under. The ops run once each, in order:
+// there is no jump, so a script always ends after at most `MAX_OPS` steps.
+// It succeeds when the item left on top of the stack is true.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Script {
+    pub ops: Vec<Op>,
+}
+
+// What a script may look at besides its arguments: the signed content of
+// the spending transaction and the block including it.
+pub struct ScriptContext<'a> {
+    pub content: &'a TransactionContent,
+    pub index: u32,
+    pub timestamp: Time,
+}
+
+impl Op {
+    // serialization tags
+    const PUSH: u32 = 0;
+    const DUP: u32 = 1;
+    const DROP: u32 = 2;
+    const SWAP: u32 = 3;
+    const EQUAL: u32 = 4;
+    const SHA256: u32 = 5;
+    const CHECK_SIG: u32 = 6;
+    const CHECK_HEIGHT: u32 = 7;
+    const CHECK_TIME: u32 = 8;
+    const NOT: u32 = 9;
+    const AND: u32 = 10;
+    const OR: u32 = 11;
+    const VERIFY: u32 = 12;
+
+    // the number of items the op takes from the stack
+    fn arity(&self) -> usize {
+        match self {
+            Op::Push(_) => 0,
+            Op::Dup | Op::Drop | Op::Sha256 | Op::CheckHeight | Op::CheckTime => 1,
+            Op::Not | Op::Verify => 1,
+            Op::Swap | Op::Equal | Op::CheckSig | Op::And | Op::Or => 2,
+        }
+    }
+
+    fn tag(&self) -> u32 {
+        match self {
+            Op::Push(_) => Op::PUSH,
+            Op::Dup => Op::DUP,
+            Op::Drop => Op::DROP,
+            Op::Swap => Op::SWAP,
+            Op::Equal => Op::EQUAL,
+            Op::Sha256 => Op::SHA256,
+            Op::CheckSig => Op::CHECK_SIG,
+            Op::CheckHeight => Op::CHECK_HEIGHT,
+            Op::CheckTime => Op::CHECK_TIME,
+            Op::Not => Op::NOT,
+            Op::And => Op::AND,
+            Op::Or => Op::OR,
+            Op::Verify => Op::VERIFY,
+        }
+    }
+}
+
+impl Writable for Op {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        match self {
+            Op::Push(item) => write_u32(writer, Op::PUSH).and_then(|_| write_bytes(writer, item)),
+            _ => write_u32(writer, self.tag()),
+        }
+    }
+}
+
+impl Readable for Op {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut tag: u32 = 0;
+        match read_u32(reader, &mut tag) {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        match tag {
+            Op::PUSH => {
+                let mut item = vec![];
+                match read_bytes(reader, &mut item, Script::MAX_ITEM_SIZE) {
+                    Ok(_) => Ok(Op::Push(item)),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            Op::DUP => Ok(Op::Dup),
+            Op::DROP => Ok(Op::Drop),
+            Op::SWAP => Ok(Op::Swap),
+            Op::EQUAL => Ok(Op::Equal),
+            Op::SHA256 => Ok(Op::Sha256),
+            Op::CHECK_SIG => Ok(Op::CheckSig),
+            Op::CHECK_HEIGHT => Ok(Op::CheckHeight),
+            Op::CHECK_TIME => Ok(Op::CheckTime),
+            Op::NOT => Ok(Op::Not),
+            Op::AND => Ok(Op::And),
+            Op::OR => Ok(Op::Or),
+            Op::VERIFY => Ok(Op::Verify),
+            _ => Err(Error::InvalidFormat),
+        }
+    }
+}
+
+impl Script {
+    // resource limits, a script over them fails whatever its result
+    pub const MAX_OPS: usize = 64;
+    pub const MAX_ITEM_SIZE: usize = 128; // bytes
+    pub const MAX_STACK: usize = 32;
+    pub const MAX_ARGUMENTS: usize = 16;
+    pub const MAX_SIGNATURE_CHECKS: usize = 8;
+
+    pub fn new(ops: Vec<Op>) -> Self {
+        Script { ops: ops }
+    }
+
+    // The account locked under the script. The seed is tagged so that it
+    // cannot be read as a multisig account, whose threshold would be huge.
+    pub fn address(&self) -> PublicUser {
+        let mut seed = b"script".to_vec();
+        seed.extend(self.to_bytes());
+        PublicUser::derive(&seed)
+    }
+
+    // the arguments are the initial stack, the last one on top
+    pub fn run(&self, arguments: &[Vec<u8>], context: &ScriptContext) -> Result<(), Error> {
+        if self.ops.len() > Script::MAX_OPS || arguments.len() > Script::MAX_ARGUMENTS {
+            return Err(Error::ScriptLimitExceeded);
+        }
+        if arguments.iter().any(|a| a.len() > Script::MAX_ITEM_SIZE) {
+            return Err(Error::ScriptLimitExceeded);
+        }
+
+        let mut stack: Vec<Vec<u8>> = arguments.to_vec();
+        let mut signature_checks = 0;
+        for op in &self.ops {
+            if stack.len() < op.arity() {
+                return Err(Error::InvalidScript);
+            }
+            match op {
+                Op::Push(item) => {
+                    if item.len() > Script::MAX_ITEM_SIZE {
+                        return Err(Error::ScriptLimitExceeded);
+                    }
+                    stack.push(item.clone());
+                }
+                Op::Dup => {
+                    let top = pop(&mut stack);
+                    stack.push(top.clone());
+                    stack.push(top);
+                }
+                Op::Drop => {
+                    stack.pop();
+                }
+                Op::Swap => {
+                    let a = pop(&mut stack);
+                    let b = pop(&mut stack);
+                    stack.push(a);
+                    stack.push(b);
+                }
+                Op::Equal => {
+                    let a = pop(&mut stack);
+                    let b = pop(&mut stack);
+                    stack.push(boolean(a == b));
+                }
+                Op::Sha256 => {
+                    let item = pop(&mut stack);
+                    stack.push(Sha256Hash::new(&[&item]).to_bytes().to_vec());
+                }
+                Op::CheckSig => {
+                    signature_checks += 1;
+                    if signature_checks > Script::MAX_SIGNATURE_CHECKS {
+                        return Err(Error::ScriptLimitExceeded);
+                    }
+                    let key = match PublicKey::from_bytes(&pop(&mut stack)) {
+                        Ok(key) => key,
+                        Err(_) => return Err(Error::InvalidScript),
+                    };
+                    let signature = match Signature::from_bytes(&pop(&mut stack)) {
+                        Ok(signature) => signature,
+                        Err(_) => return Err(Error::InvalidScript),
+                    };
+                    stack.push(boolean(context.content.verify(key, signature)));
+                }
+                Op::CheckHeight => {
+                    let height: [u8; 4] = match pop(&mut stack).try_into() {
+                        Ok(height) => height,
+                        Err(_) => return Err(Error::InvalidScript),
+                    };
+                    stack.push(boolean(context.index >= u32::from_be_bytes(height)));
+                }
+                Op::CheckTime => {
+                    let time: [u8; 16] = match pop(&mut stack).try_into() {
+                        Ok(time) => time,
+                        Err(_) => return Err(Error::InvalidScript),
+                    };
+                    let time = Time::from_bytes(time);
+                    stack.push(boolean(context.timestamp.to_u128() >= time.to_u128()));
+                }
+                Op::Not => {
+                    let item = pop(&mut stack);
+                    stack.push(boolean(!is_true(&item)));
+                }
+                Op::And => {
+                    let a = pop(&mut stack);
+                    let b = pop(&mut stack);
+                    stack.push(boolean(is_true(&a) && is_true(&b)));
+                }
+                Op::Or => {
+                    let a = pop(&mut stack);
+                    let b = pop(&mut stack);
+                    stack.push(boolean(is_true(&a) || is_true(&b)));
+                }
+                Op::Verify => {
+                    if !is_true(&pop(&mut stack)) {
+                        return Err(Error::ScriptFailed);
+                    }
+                }
+            }
+            if stack.len() > Script::MAX_STACK {
+                return Err(Error::ScriptLimitExceeded);
+            }
+        }
+
+        match stack.last() {
+            Some(top) if is_true(top) => Ok(()),
+            _ => Err(Error::ScriptFailed),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+// the depth of the stack is checked before each op
+fn pop(stack: &mut Vec<Vec<u8>>) -> Vec<u8> {
+    stack.pop().unwrap_or_default()
+}
+
+fn boolean(b: bool) -> Vec<u8> {
+    match b {
+        true => vec![1],
+        false => vec![],
+    }
+}
+
+fn is_true(item: &[u8]) -> bool {
+    item.iter().any(|b| *b != 0)
+}
+
+impl Writable for Script {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_vec_struct(writer, &self.ops)
+    }
+}
+
+impl Readable for Script {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut len: usize = 0;
+        match read_vec_len(reader, &mut len) {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+        // refused before anything is read
+        if len > Script::MAX_OPS {
+            return Err(Error::InvalidFormat);
+        }
+
+        let mut ops = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut op = Op::Drop;
+            match read_struct(reader, &mut op) {
+                Ok(_) => ops.push(op),
+                Err(_) => return Err(Error::InvalidFormat),
+            };
+        }
+
+        Ok(Script { ops: ops })
+    }
+}
+
+// the arguments of a script witness, as items of at most `MAX_ITEM_SIZE` bytes
+pub fn write_arguments(writer: &mut dyn Write, arguments: &Vec<Vec<u8>>) -> Result<(), Error> {
+    match write_u32(writer, arguments.len() as u32) {
+        Ok(_) => (),
+        Err(e) => return Err(e),
+    };
+    for argument in arguments {
+        match write_bytes(writer, argument) {
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(())
+}
+
+pub fn read_arguments(reader: &mut dyn Read, arguments: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+    let mut len: usize = 0;
+    match read_vec_len(reader, &mut len) {
+        Ok(_) => (),
+        Err(_) => return Err(Error::EndOfBuffer),
+    };
+    if len > Script::MAX_ARGUMENTS {
+        return Err(Error::InvalidFormat);
+    }
+
+    let mut vec = Vec::with_capacity(len);
+    for _ in 0..len {
+        let mut argument = vec![];
+        match read_bytes(reader, &mut argument, Script::MAX_ITEM_SIZE) {
+            Ok(_) => vec.push(argument),
+            Err(e) => return Err(e),
+        };
+    }
+
+    *arguments = vec;
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use std::{fmt::{ self, Debug, Formatter }, io::{Read, Write}};
+use std::{fmt::{ self, Debug, Formatter }, hash::{Hash, Hasher}, io::{Read, Write}};
 use sha2::Sha256;
 use sha2::Digest;
 
@@ -60,6 +60,13 @@ impl PartialEq for Sha256Hash {
 }
 impl Eq for Sha256Hash {}
 
+// consistent with eq, so that hashes can key a map
+impl Hash for Sha256Hash {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_u128().hash(state);
+    }
+}
+
 
 impl Sha256Hash {
     pub const BITS:u32 = 256;
@@ -1,5 +1,7 @@
 use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
 
+use crate::multisig::{MultisigAccount, PartialSignature};
+
 pub trait Signable {
     fn bytes(&self) -> Vec<u8>;
 
@@ -10,4 +12,18 @@ pub trait Signable {
     fn verify(&self, public_key: PublicKey, signature: Signature) -> bool {
         public_key.verify(&self.bytes(), &signature).is_ok()
     }
+
+    // at least `threshold` keys of the account must have signed, each
+    // signature is checked and none is skipped
+    fn verify_multisig(&self, account: &MultisigAccount, signatures: &[PartialSignature]) -> bool {
+        if !account.is_canonical(signatures) {
+            return false;
+        }
+        let bytes = self.bytes();
+        account.threshold > 0
+            && signatures.len() >= account.threshold as usize
+            && signatures
+                .iter()
+                .all(|s| s.signer.key.verify(&bytes, &s.signature).is_ok())
+    }
 }
@@ -0,0 +1,68 @@
+use std::fmt::Debug;
+
+use crate::consensus::RuleSet;
+use crate::error::Error;
+use crate::reader::Readable;
+use crate::sha256::Sha256Hash;
+use crate::writer::Writable;
+
+// What a block carries, whatever the state model of its chain.
+pub trait BlockTransaction:
+    Clone + PartialEq + Eq + Debug + Writable + Readable + Send + Sync + 'static
+{
+    // leaf of the transaction tree of the block
+    fn hash(&self) -> Sha256Hash;
+
+    // whether it pays the miner of the block
+    fn is_coinbase(&self) -> bool;
+}
+
+// How a chain keeps track of who owns what: the accounts of `Ledger` or the
+// unspent outputs of `UtxoSet`. Each branch holds a state, a block is applied
+// to it as a set of changes that is kept to walk the branch back afterwards.
+pub trait StateModel: Clone + PartialEq + Eq + Writable + Readable + Send + Sync + 'static {
+    type Transaction: BlockTransaction;
+    // what the transactions of a block modify, on top of the state
+    type Changes;
+    // the modified entries as they were before the block
+    type Undo: Clone + PartialEq + Eq + Send + Sync;
+
+    fn empty() -> Self;
+
+    // the rules of the chains using this model
+    fn standard_rules() -> RuleSet<Self>;
+
+    fn empty_changes() -> Self::Changes;
+
+    // applies the transaction of the block at `index` on top of `changes`,
+    // nothing is modified when it fails
+    fn apply_transaction(
+        &self,
+        changes: &mut Self::Changes,
+        transaction: &Self::Transaction,
+        index: u32,
+    ) -> Result<(), Error>;
+
+    // the state once `changes` are applied, the unchanged entries are shared
+    fn apply_changes(&self, changes: &Self::Changes) -> Self;
+
+    // what reverts `apply_changes(changes)` back to this state
+    fn undo(&self, changes: &Self::Changes) -> Self::Undo;
+
+    fn revert(&mut self, undo: &Self::Undo);
+
+    // commitment to the whole state, carried by the block headers
+    fn state_root(&self) -> Sha256Hash;
+
+    // the state after the transactions of the block at `index`, the invalid
+    // ones are skipped
+    fn apply_unsafe(&self, transactions: &[Self::Transaction], index: u32) -> Self {
+        let mut changes = Self::empty_changes();
+
+        for transaction in transactions {
+            self.apply_transaction(&mut changes, transaction, index)
+                .ok();
+        }
+        self.apply_changes(&changes)
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Read, Write};
+
+use num::BigUint;
+
+use crate::error::Error;
+use crate::reader::{read_u128, Readable};
+use crate::sha256::Sha256Hash;
+use crate::writer::{write_u128, Writable};
+
+// A block is mined once its hash, read as a 256 bits big endian number, is
+// lower or equal to the target. The lower the target the harder the mining.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub arr_u128: [u128; 2],
+}
+
+impl Target {
+    pub fn zero() -> Self {
+        Target { arr_u128: [0; 2] }
+    }
+
+    pub fn max() -> Self {
+        Target {
+            arr_u128: [u128::MAX; 2],
+        }
+    }
+
+    // the target met by a hash starting with `bits` zero bits
+    pub fn from_leading_zeros(bits: u32) -> Self {
+        if bits >= Sha256Hash::BITS {
+            return Target::zero();
+        }
+        let value = (BigUint::from(1u32) << (Sha256Hash::BITS - bits)) - BigUint::from(1u32);
+        Target::from_big_uint(&value)
+    }
+
+    pub fn initial() -> Self {
+        Target::from_leading_zeros(13)
+    }
+
+    // expected number of hashes needed to meet the target: 2^256 / (target + 1)
+    pub fn work(&self) -> BigUint {
+        (BigUint::from(1u32) << Sha256Hash::BITS) / (self.to_big_uint() + BigUint::from(1u32))
+    }
+
+    pub fn is_met_by(&self, hash: &Sha256Hash) -> bool {
+        hash.as_u128() <= &self.arr_u128
+    }
+
+    pub fn to_big_uint(&self) -> BigUint {
+        BigUint::from_bytes_be(&self.to_bytes())
+    }
+
+    // saturates to `Target::max()` when the value does not fit in 256 bits
+    pub fn from_big_uint(value: &BigUint) -> Self {
+        let bytes = value.to_bytes_be();
+        if bytes.len() > 32 {
+            return Target::max();
+        }
+
+        let mut arr_u8: [u8; 32] = [0; 32];
+        arr_u8[32 - bytes.len()..].copy_from_slice(&bytes);
+        Target::from_bytes(arr_u8)
+    }
+
+    pub fn from_bytes(value: [u8; 32]) -> Self {
+        let hash = Sha256Hash::from_bytes(value);
+        Target {
+            arr_u128: hash.arr_u128,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        Sha256Hash {
+            arr_u128: self.arr_u128,
+        }
+        .to_bytes()
+    }
+}
+
+impl Writable for Target {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_u128(writer, self.arr_u128[0]) //
+            .and_then(|_| write_u128(writer, self.arr_u128[1]))
+    }
+}
+
+impl Readable for Target {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut target = Target::zero();
+
+        match read_u128(reader, &mut target.arr_u128[0]) //
+            .and_then(|_| read_u128(reader, &mut target.arr_u128[1]))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        }
+
+        Ok(target)
+    }
+}
+
+impl Debug for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}{:032x}", self.arr_u128[0], self.arr_u128[1])
+    }
+}
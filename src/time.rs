@@ -1,7 +1,6 @@
 use std::{
     io::{Read, Write},
     time::{SystemTime, UNIX_EPOCH},
-    u128,
 };
 
 use crate::{
@@ -10,7 +9,7 @@ use crate::{
     writer::{write_u128, Writable},
 };
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Time {
     pub secs: u64,
     pub subsec_millis: u32,
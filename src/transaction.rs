@@ -4,25 +4,105 @@ use std::io::Write;
 
 use super::signature::Signable;
 use crate::error::Error;
+use crate::multisig::{MultisigAccount, PartialSignature};
 use crate::num::Num;
-use crate::reader::{read_signature, read_struct, read_u64, Readable};
+use crate::reader::{
+    read_bytes, read_option_u32, read_signature, read_struct, read_u32, read_u64, read_vec_len,
+    read_vec_struct, Readable,
+};
+use crate::script::{read_arguments, write_arguments, Script, ScriptContext};
+use crate::sha256::Sha256Hash;
+use crate::state::BlockTransaction;
+use crate::time::Time;
 use crate::user::{PublicUser, User};
-use crate::writer::{write_signature, write_struct, write_u64, Writable};
+use crate::writer::{
+    write_bytes, write_option_u32, write_signature, write_struct, write_u32, write_u64,
+    write_vec_struct, Writable,
+};
 use ed25519_dalek::Signature;
 use rand::Rng;
 
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Output {
+    pub to: PublicUser,
+    pub value: Num,
+}
+
+// Funds locked for `recipient` until `timeout`: the recipient claims them by
+// revealing the preimage of `hash` at or before that height, after it the
+// sender takes them back.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HashLock {
+    pub recipient: PublicUser,
+    pub value: Num,
+    pub hash: Sha256Hash,
+    pub timeout: u32,
+}
+
+// A token issued by a user, e.g. loyalty points. Its issuer is the sender
+// of the transaction creating it, and only the issuer mints it, up to
+// `max_supply`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TokenDefinition {
+    pub symbol: String,
+    pub max_supply: Num,
+}
+
+// A batch pays several recipients under a single signature and sequence,
+// the ledger applies it entirely or not at all. A hash lock is claimed or
+// refunded, and a token minted and transferred, by the content hash of the
+// transaction that created it.
+// The fee is always paid in the native coin.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[allow(clippy::large_enum_variant)] // single payments are the common case, not boxed
+pub enum Payment {
+    Single(Output),
+    Batch(Vec<Output>),
+    HashLock(HashLock),
+    Claim { lock: Sha256Hash, preimage: Vec<u8> },
+    Refund { lock: Sha256Hash },
+    CreateToken(TokenDefinition),
+    Mint { token: Sha256Hash, output: Output },
+    TokenTransfer { token: Sha256Hash, output: Output },
+}
+
+// A locked transaction is only valid in a block at or above the height, or
+// with a timestamp at or after the time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Lock {
+    Height(u32),
+    Time(Time),
+}
+
 #[derive(Clone)]
 pub struct TransactionContent {
     pub from: PublicUser,
-    pub to: PublicUser,
-    pub value: Num,
-    pub nonce: u64,
+    pub payment: Payment,
+    pub fee: Num,      // paid to the miner of the block including the transaction
+    pub sequence: u64, // must follow the sequence of the last transaction of `from`
+    pub lock: Option<Lock>,
+    pub valid_until: Option<u32>, // last block index that may include the transaction
+    pub memo: Vec<u8>,            // e.g. a deposit reference, empty when there is none
+}
+
+// What proves that the owner of `from` agreed to the transaction.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Witness {
+    Single(Signature),
+    Multisig {
+        account: MultisigAccount, // its address must be `from`
+        signatures: Vec<PartialSignature>,
+    },
+    Script {
+        script: Script, // its address must be `from`
+        arguments: Vec<Vec<u8>>,
+    },
 }
 
 #[derive(Clone)]
 pub struct Transaction {
     pub content: TransactionContent,
-    pub signature: Signature,
+    pub witness: Witness,
 }
 
 impl Transaction {
@@ -30,56 +110,188 @@ impl Transaction {
         Transaction {
             content: TransactionContent {
                 from: PublicUser::new_coinbase(),
-                to: to.clone(),
-                value: value.clone(),
-                nonce: rand::thread_rng().gen_range(0, u64::max_value()),
+                payment: Payment::Single(Output::new(*to, value.clone())),
+                fee: Num::zero(),
+                // the coinbase has no account, the sequence only makes it unique
+                sequence: rand::thread_rng().gen_range(0, u64::MAX),
+                lock: None,
+                valid_until: None,
+                memo: vec![],
             },
-            signature: Signature::from_bytes(&[
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0,
-            ])
-            .unwrap(),
+            witness: Witness::zero(),
         }
     }
     pub fn zero() -> Self {
         Transaction {
             content: TransactionContent::zero(),
-            signature: Signature::from_bytes(&[
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0,
-            ])
-            .unwrap(),
+            witness: Witness::zero(),
         }
     }
-    pub fn new(from: User, to: PublicUser, value: Num, once: u64) -> Self {
-        let content = TransactionContent {
-            from: from.as_public(),
-            to: to,
-            value: value,
-            nonce: once,
+    pub fn new(from: User, to: PublicUser, value: Num, sequence: u64) -> Self {
+        Transaction::new_with_fee(from, to, value, Num::zero(), sequence)
+    }
+
+    pub fn new_with_fee(from: User, to: PublicUser, value: Num, fee: Num, sequence: u64) -> Self {
+        Transaction::new_signed(from, Payment::Single(Output::new(to, value)), fee, sequence)
+    }
+
+    pub fn new_batch(from: User, outputs: Vec<Output>, fee: Num, sequence: u64) -> Self {
+        Transaction::new_signed(from, Payment::Batch(outputs), fee, sequence)
+    }
+
+    pub fn new_hash_lock(from: User, lock: HashLock, fee: Num, sequence: u64) -> Self {
+        Transaction::new_signed(from, Payment::HashLock(lock), fee, sequence)
+    }
+
+    pub fn new_claim(
+        from: User,
+        lock: Sha256Hash,
+        preimage: Vec<u8>,
+        fee: Num,
+        sequence: u64,
+    ) -> Self {
+        let payment = Payment::Claim {
+            lock: lock,
+            preimage: preimage,
         };
+        Transaction::new_signed(from, payment, fee, sequence)
+    }
 
+    pub fn new_refund(from: User, lock: Sha256Hash, fee: Num, sequence: u64) -> Self {
+        Transaction::new_signed(from, Payment::Refund { lock: lock }, fee, sequence)
+    }
+
+    pub fn new_token(from: User, definition: TokenDefinition, fee: Num, sequence: u64) -> Self {
+        Transaction::new_signed(from, Payment::CreateToken(definition), fee, sequence)
+    }
+
+    pub fn new_mint(
+        from: User,
+        token: Sha256Hash,
+        output: Output,
+        fee: Num,
+        sequence: u64,
+    ) -> Self {
+        let payment = Payment::Mint {
+            token: token,
+            output: output,
+        };
+        Transaction::new_signed(from, payment, fee, sequence)
+    }
+
+    pub fn new_token_transfer(
+        from: User,
+        token: Sha256Hash,
+        output: Output,
+        fee: Num,
+        sequence: u64,
+    ) -> Self {
+        let payment = Payment::TokenTransfer {
+            token: token,
+            output: output,
+        };
+        Transaction::new_signed(from, payment, fee, sequence)
+    }
+
+    fn new_signed(from: User, payment: Payment, fee: Num, sequence: u64) -> Self {
+        let content = TransactionContent::new(from.as_public(), payment, fee, sequence);
+        Transaction::from_content(content, &from)
+    }
+
+    // signs a content built by hand, e.g. a locked one
+    pub fn from_content(content: TransactionContent, from: &User) -> Self {
         Transaction {
-            signature: content.sign(&from.key_pair),
+            witness: Witness::Single(content.sign(&from.key_pair)),
             content: content,
         }
     }
 
-    pub fn verify(&self, from_account: Num) -> Result<(), Error> {
-        if self.content.value <= Num::zero() {
-            return Err(Error::TransactionOf0);
+    // whether the transaction may be included in a block at `index` and `timestamp`
+    pub fn is_unlocked_at(&self, index: u32, timestamp: &Time) -> bool {
+        match self.content.lock {
+            None => true,
+            Some(Lock::Height(height)) => index >= height,
+            Some(Lock::Time(time)) => timestamp.to_u128() >= time.to_u128(),
+        }
+    }
+
+    // whether a block at `index` is too late to include the transaction
+    pub fn is_expired_at(&self, index: u32) -> bool {
+        match self.content.valid_until {
+            Some(valid_until) => index > valid_until,
+            None => false,
         }
-        if self.content.from.is_coinbase() {
-            if !self.content.verify(self.content.from.key, self.signature) {
-                return Err(Error::WrongTransactionSignature);
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        match &self.witness {
+            Witness::Single(signature) => self.content.verify(self.content.from.key, *signature),
+            Witness::Multisig {
+                account,
+                signatures,
+            } => {
+                account.address() == self.content.from
+                    && self.content.verify_multisig(account, signatures)
             }
-            if self.content.value > from_account {
-                return Err(Error::SenderDoNotHaveEnoughMoney);
+            // the script itself depends on the block, see `verify_script`
+            Witness::Script { script, .. } => script.address() == self.content.from,
+        }
+    }
+
+    // runs the script of a script witness as if the transaction was in a
+    // block at `index` with `timestamp`, the other witnesses have none
+    pub fn verify_script(&self, index: u32, timestamp: &Time) -> Result<(), Error> {
+        match &self.witness {
+            Witness::Script { script, arguments } => {
+                let context = ScriptContext {
+                    content: &self.content,
+                    index: index,
+                    timestamp: *timestamp,
+                };
+                script.run(arguments, &context)
             }
+            _ => Ok(()),
+        }
+    }
+
+    // the accounts paid right away in the native coin, a hash lock pays
+    // nobody until it is claimed
+    pub fn outputs(&self) -> &[Output] {
+        match &self.content.payment {
+            Payment::Single(output) => std::slice::from_ref(output),
+            Payment::Batch(outputs) => outputs,
+            _ => &[],
+        }
+    }
+
+    // what leaves the account of the sender besides the fee
+    pub fn value(&self) -> Num {
+        let mut value = Num::zero();
+        for output in self.outputs() {
+            value += output.value.clone();
         }
-        return Ok(());
+        if let Payment::HashLock(lock) = &self.content.payment {
+            value += lock.value.clone();
+        }
+        value
+    }
+
+    // what the sender is debited: the value and the fee
+    pub fn cost(&self) -> Num {
+        let mut cost = self.value();
+        cost += self.content.fee.clone();
+        cost
+    }
+
+    // leaf of the transaction tree of the block, the signature is included
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256Hash::new(&[&self.to_bytes()])
+    }
+
+    // hash of the signed content only: unlike `hash` it cannot be changed by
+    // whoever relays the transaction, so it identifies what the payment creates
+    pub fn content_hash(&self) -> Sha256Hash {
+        Sha256Hash::new(&[&self.content.to_bytes()])
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -98,7 +310,7 @@ impl Transaction {
 
 impl Writable for Transaction {
     fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
-        write_signature(writer, &self.signature).and_then(|_| write_struct(writer, &self.content))
+        write_struct(writer, &self.witness).and_then(|_| write_struct(writer, &self.content))
     }
 }
 
@@ -106,7 +318,7 @@ impl Readable for Transaction {
     fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
         let mut transaction = Transaction::zero();
 
-        match read_signature(reader, &mut transaction.signature) //
+        match read_struct(reader, &mut transaction.witness) //
             .and_then(|_| read_struct(reader, &mut transaction.content))
         {
             Ok(_) => (),
@@ -119,23 +331,119 @@ impl Readable for Transaction {
 
 impl Debug for Transaction {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{:?} gives {} to {:?}",
-            &self.content.from,
-            &self.content.value.to_string(),
-            &self.content.to,
-        )
+        let payment = match &self.content.payment {
+            Payment::Single(output) => write!(
+                f,
+                "{:?} gives {} to {:?} (fee {})",
+                &self.content.from,
+                &output.value.to_string(),
+                &output.to,
+                &self.content.fee.to_string(),
+            ),
+            Payment::Batch(outputs) => write!(
+                f,
+                "{:?} gives {} to {} users (fee {})",
+                &self.content.from,
+                &self.value().to_string(),
+                outputs.len(),
+                &self.content.fee.to_string(),
+            ),
+            Payment::HashLock(lock) => write!(
+                f,
+                "{:?} locks {} for {:?} until {} (fee {})",
+                &self.content.from,
+                &lock.value.to_string(),
+                &lock.recipient,
+                lock.timeout,
+                &self.content.fee.to_string(),
+            ),
+            Payment::Claim { lock, .. } => write!(
+                f,
+                "{:?} claims {:?} (fee {})",
+                &self.content.from,
+                lock,
+                &self.content.fee.to_string(),
+            ),
+            Payment::Refund { lock } => write!(
+                f,
+                "{:?} is refunded {:?} (fee {})",
+                &self.content.from,
+                lock,
+                &self.content.fee.to_string(),
+            ),
+            Payment::CreateToken(definition) => write!(
+                f,
+                "{:?} creates {} up to {} (fee {})",
+                &self.content.from,
+                &definition.symbol,
+                &definition.max_supply.to_string(),
+                &self.content.fee.to_string(),
+            ),
+            Payment::Mint { token, output } => write!(
+                f,
+                "{:?} mints {} of {:?} to {:?} (fee {})",
+                &self.content.from,
+                &output.value.to_string(),
+                token,
+                &output.to,
+                &self.content.fee.to_string(),
+            ),
+            Payment::TokenTransfer { token, output } => write!(
+                f,
+                "{:?} gives {} of {:?} to {:?} (fee {})",
+                &self.content.from,
+                &output.value.to_string(),
+                token,
+                &output.to,
+                &self.content.fee.to_string(),
+            ),
+        };
+        if payment.is_err() || self.content.memo.is_empty() {
+            return payment;
+        }
+        write!(f, " memo {:?}", String::from_utf8_lossy(&self.content.memo))
     }
 }
 
 impl TransactionContent {
+    pub const MAX_MEMO: usize = 256; // bytes
+
+    pub fn new(from: PublicUser, payment: Payment, fee: Num, sequence: u64) -> Self {
+        TransactionContent {
+            from: from,
+            payment: payment,
+            fee: fee,
+            sequence: sequence,
+            lock: None,
+            valid_until: None,
+            memo: vec![],
+        }
+    }
+
+    pub fn with_memo(mut self, memo: Vec<u8>) -> Self {
+        self.memo = memo;
+        self
+    }
+
+    pub fn with_expiry(mut self, valid_until: u32) -> Self {
+        self.valid_until = Some(valid_until);
+        self
+    }
+
+    pub fn with_lock(mut self, lock: Lock) -> Self {
+        self.lock = Some(lock);
+        self
+    }
+
     pub fn zero() -> Self {
         TransactionContent {
             from: PublicUser::zero(),
-            to: PublicUser::zero(),
-            value: Num::zero(),
-            nonce: 0,
+            payment: Payment::zero(),
+            fee: Num::zero(),
+            sequence: 0,
+            lock: None,
+            valid_until: None,
+            memo: vec![],
         }
     }
 
@@ -156,9 +464,12 @@ impl TransactionContent {
 impl Writable for TransactionContent {
     fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
         write_struct(writer, &self.from)
-            .and_then(|_| write_struct(writer, &self.to))
-            .and_then(|_| write_u64(writer, self.nonce))
-            .and_then(|_| write_struct(writer, &self.value))
+            .and_then(|_| write_struct(writer, &self.payment))
+            .and_then(|_| write_u64(writer, self.sequence))
+            .and_then(|_| write_struct(writer, &self.fee))
+            .and_then(|_| write_struct(writer, &self.lock))
+            .and_then(|_| write_option_u32(writer, &self.valid_until))
+            .and_then(|_| write_bytes(writer, &self.memo))
     }
 }
 
@@ -167,10 +478,18 @@ impl Readable for TransactionContent {
         let mut transaction_content = TransactionContent::zero();
 
         match read_struct(reader, &mut transaction_content.from)
-            .and_then(|_| read_struct(reader, &mut transaction_content.to))
-            .and_then(|_| read_u64(reader, &mut transaction_content.nonce))
-            .and_then(|_| read_struct(reader, &mut transaction_content.value))
-        {
+            .and_then(|_| read_struct(reader, &mut transaction_content.payment))
+            .and_then(|_| read_u64(reader, &mut transaction_content.sequence))
+            .and_then(|_| read_struct(reader, &mut transaction_content.fee))
+            .and_then(|_| read_struct(reader, &mut transaction_content.lock))
+            .and_then(|_| read_option_u32(reader, &mut transaction_content.valid_until))
+            .and_then(|_| {
+                read_bytes(
+                    reader,
+                    &mut transaction_content.memo,
+                    TransactionContent::MAX_MEMO,
+                )
+            }) {
             Ok(_) => (),
             Err(_) => return Err(Error::InvalidFormat),
         };
@@ -187,17 +506,418 @@ impl Signable for TransactionContent {
 
 impl PartialEq for Transaction {
     fn eq(&self, other: &Self) -> bool {
-        self.content == other.content && self.signature == other.signature
+        self.content == other.content && self.witness == other.witness
     }
 }
 impl Eq for Transaction {}
 
+impl BlockTransaction for Transaction {
+    fn hash(&self) -> Sha256Hash {
+        Transaction::hash(self)
+    }
+
+    fn is_coinbase(&self) -> bool {
+        self.content.from.is_coinbase()
+    }
+}
+
 impl PartialEq for TransactionContent {
     fn eq(&self, other: &Self) -> bool {
         self.from == other.from
-            && self.to == other.to
-            && self.value == other.value
-            && self.nonce == other.nonce
+            && self.payment == other.payment
+            && self.fee == other.fee
+            && self.sequence == other.sequence
+            && self.lock == other.lock
+            && self.valid_until == other.valid_until
+            && self.memo == other.memo
     }
 }
 impl Eq for TransactionContent {}
+
+impl Output {
+    pub fn new(to: PublicUser, value: Num) -> Self {
+        Output {
+            to: to,
+            value: value,
+        }
+    }
+
+    pub fn zero() -> Self {
+        Output::new(PublicUser::zero(), Num::zero())
+    }
+}
+
+impl Writable for Output {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.to).and_then(|_| write_struct(writer, &self.value))
+    }
+}
+
+impl Readable for Output {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut output = Output::zero();
+
+        match read_struct(reader, &mut output.to)
+            .and_then(|_| read_struct(reader, &mut output.value))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(output)
+    }
+}
+
+impl HashLock {
+    pub const MAX_PREIMAGE: usize = 64; // bytes
+
+    pub fn zero() -> Self {
+        HashLock {
+            recipient: PublicUser::zero(),
+            value: Num::zero(),
+            hash: Sha256Hash::zero(),
+            timeout: 0,
+        }
+    }
+
+    pub fn is_unlocked_by(&self, preimage: &Vec<u8>) -> bool {
+        Sha256Hash::new(&[preimage]) == self.hash
+    }
+}
+
+impl Writable for HashLock {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.recipient)
+            .and_then(|_| write_struct(writer, &self.value))
+            .and_then(|_| write_struct(writer, &self.hash))
+            .and_then(|_| write_u32(writer, self.timeout))
+    }
+}
+
+impl Readable for HashLock {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut lock = HashLock::zero();
+
+        match read_struct(reader, &mut lock.recipient)
+            .and_then(|_| read_struct(reader, &mut lock.value))
+            .and_then(|_| read_struct(reader, &mut lock.hash))
+            .and_then(|_| read_u32(reader, &mut lock.timeout))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(lock)
+    }
+}
+
+impl TokenDefinition {
+    pub const MAX_SYMBOL: usize = 12; // bytes
+
+    pub fn zero() -> Self {
+        TokenDefinition {
+            symbol: String::new(),
+            max_supply: Num::zero(),
+        }
+    }
+
+    // a few ascii letters or digits, e.g. "POINTS"
+    pub fn has_valid_symbol(&self) -> bool {
+        !self.symbol.is_empty()
+            && self.symbol.len() <= TokenDefinition::MAX_SYMBOL
+            && self.symbol.bytes().all(|b| b.is_ascii_alphanumeric())
+    }
+}
+
+impl Writable for TokenDefinition {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_bytes(writer, self.symbol.as_bytes())
+            .and_then(|_| write_struct(writer, &self.max_supply))
+    }
+}
+
+impl Readable for TokenDefinition {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut symbol = vec![];
+        let mut definition = TokenDefinition::zero();
+
+        match read_bytes(reader, &mut symbol, TokenDefinition::MAX_SYMBOL)
+            .and_then(|_| read_struct(reader, &mut definition.max_supply))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+        definition.symbol = match String::from_utf8(symbol) {
+            Ok(symbol) => symbol,
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(definition)
+    }
+}
+
+impl Payment {
+    // serialization tags
+    const SINGLE: u32 = 0;
+    const BATCH: u32 = 1;
+    const HASH_LOCK: u32 = 2;
+    const CLAIM: u32 = 3;
+    const REFUND: u32 = 4;
+    const CREATE_TOKEN: u32 = 5;
+    const MINT: u32 = 6;
+    const TOKEN_TRANSFER: u32 = 7;
+
+    pub fn zero() -> Self {
+        Payment::Single(Output::zero())
+    }
+}
+
+impl Writable for Payment {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        match self {
+            Payment::Single(output) => {
+                write_u32(writer, Payment::SINGLE).and_then(|_| write_struct(writer, output))
+            }
+            Payment::Batch(outputs) => {
+                write_u32(writer, Payment::BATCH).and_then(|_| write_vec_struct(writer, outputs))
+            }
+            Payment::HashLock(lock) => {
+                write_u32(writer, Payment::HASH_LOCK).and_then(|_| write_struct(writer, lock))
+            }
+            Payment::Claim { lock, preimage } => write_u32(writer, Payment::CLAIM)
+                .and_then(|_| write_struct(writer, lock))
+                .and_then(|_| write_bytes(writer, preimage)),
+            Payment::Refund { lock } => {
+                write_u32(writer, Payment::REFUND).and_then(|_| write_struct(writer, lock))
+            }
+            Payment::CreateToken(definition) => write_u32(writer, Payment::CREATE_TOKEN)
+                .and_then(|_| write_struct(writer, definition)),
+            Payment::Mint { token, output } => write_u32(writer, Payment::MINT)
+                .and_then(|_| write_struct(writer, token))
+                .and_then(|_| write_struct(writer, output)),
+            Payment::TokenTransfer { token, output } => write_u32(writer, Payment::TOKEN_TRANSFER)
+                .and_then(|_| write_struct(writer, token))
+                .and_then(|_| write_struct(writer, output)),
+        }
+    }
+}
+
+impl Readable for Payment {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut tag: u32 = 0;
+        match read_u32(reader, &mut tag) {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        match tag {
+            Payment::SINGLE => {
+                let mut output = Output::zero();
+                match read_struct(reader, &mut output) {
+                    Ok(_) => Ok(Payment::Single(output)),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            Payment::BATCH => {
+                let mut outputs = vec![];
+                match read_vec_struct(reader, &mut outputs) {
+                    Ok(_) => Ok(Payment::Batch(outputs)),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            Payment::HASH_LOCK => {
+                let mut lock = HashLock::zero();
+                match read_struct(reader, &mut lock) {
+                    Ok(_) => Ok(Payment::HashLock(lock)),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            Payment::CLAIM => {
+                let mut lock = Sha256Hash::zero();
+                let mut preimage = vec![];
+                match read_struct(reader, &mut lock)
+                    .and_then(|_| read_bytes(reader, &mut preimage, HashLock::MAX_PREIMAGE))
+                {
+                    Ok(_) => Ok(Payment::Claim {
+                        lock: lock,
+                        preimage: preimage,
+                    }),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            Payment::REFUND => {
+                let mut lock = Sha256Hash::zero();
+                match read_struct(reader, &mut lock) {
+                    Ok(_) => Ok(Payment::Refund { lock: lock }),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            Payment::CREATE_TOKEN => {
+                let mut definition = TokenDefinition::zero();
+                match read_struct(reader, &mut definition) {
+                    Ok(_) => Ok(Payment::CreateToken(definition)),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            Payment::MINT | Payment::TOKEN_TRANSFER => {
+                let mut token = Sha256Hash::zero();
+                let mut output = Output::zero();
+                match read_struct(reader, &mut token).and_then(|_| read_struct(reader, &mut output))
+                {
+                    Ok(_) if tag == Payment::MINT => Ok(Payment::Mint {
+                        token: token,
+                        output: output,
+                    }),
+                    Ok(_) => Ok(Payment::TokenTransfer {
+                        token: token,
+                        output: output,
+                    }),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            _ => Err(Error::InvalidFormat),
+        }
+    }
+}
+
+impl Witness {
+    // serialization tags
+    const SINGLE: u32 = 0;
+    const MULTISIG: u32 = 1;
+    const SCRIPT: u32 = 2;
+
+    pub fn zero() -> Self {
+        Witness::Single(Signature::from_bytes(&[0; 64]).unwrap())
+    }
+}
+
+impl Writable for Witness {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        match self {
+            Witness::Single(signature) => {
+                write_u32(writer, Witness::SINGLE).and_then(|_| write_signature(writer, signature))
+            }
+            Witness::Multisig {
+                account,
+                signatures,
+            } => write_u32(writer, Witness::MULTISIG)
+                .and_then(|_| write_struct(writer, account))
+                .and_then(|_| write_vec_struct(writer, signatures)),
+            Witness::Script { script, arguments } => write_u32(writer, Witness::SCRIPT)
+                .and_then(|_| write_struct(writer, script))
+                .and_then(|_| write_arguments(writer, arguments)),
+        }
+    }
+}
+
+impl Readable for Witness {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut tag: u32 = 0;
+        match read_u32(reader, &mut tag) {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        match tag {
+            Witness::SINGLE => {
+                let mut signature = Signature::from_bytes(&[0; 64]).unwrap();
+                match read_signature(reader, &mut signature) {
+                    Ok(_) => Ok(Witness::Single(signature)),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            Witness::MULTISIG => {
+                let mut account = MultisigAccount::zero();
+                let mut len: usize = 0;
+                match read_struct(reader, &mut account).and_then(|_| read_vec_len(reader, &mut len))
+                {
+                    Ok(_) => (),
+                    Err(_) => return Err(Error::InvalidFormat),
+                };
+                // refused before anything is read, at most one signature per key
+                if len > account.keys.len() {
+                    return Err(Error::InvalidFormat);
+                }
+
+                let mut signatures = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let mut signature = PartialSignature::zero();
+                    match read_struct(reader, &mut signature) {
+                        Ok(_) => signatures.push(signature),
+                        Err(_) => return Err(Error::InvalidFormat),
+                    };
+                }
+                if !account.is_canonical(&signatures) {
+                    return Err(Error::InvalidFormat);
+                }
+
+                Ok(Witness::Multisig {
+                    account: account,
+                    signatures: signatures,
+                })
+            }
+            Witness::SCRIPT => {
+                let mut script = Script::new(vec![]);
+                let mut arguments = vec![];
+                match read_struct(reader, &mut script)
+                    .and_then(|_| read_arguments(reader, &mut arguments))
+                {
+                    Ok(_) => Ok(Witness::Script {
+                        script: script,
+                        arguments: arguments,
+                    }),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            _ => Err(Error::InvalidFormat),
+        }
+    }
+}
+
+// serialization tags
+const NO_LOCK: u32 = 0;
+const HEIGHT_LOCK: u32 = 1;
+const TIME_LOCK: u32 = 2;
+
+impl Writable for Option<Lock> {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        match self {
+            None => write_u32(writer, NO_LOCK),
+            Some(Lock::Height(height)) => {
+                write_u32(writer, HEIGHT_LOCK).and_then(|_| write_u32(writer, *height))
+            }
+            Some(Lock::Time(time)) => {
+                write_u32(writer, TIME_LOCK).and_then(|_| write_struct(writer, time))
+            }
+        }
+    }
+}
+
+impl Readable for Option<Lock> {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut tag: u32 = 0;
+        match read_u32(reader, &mut tag) {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        match tag {
+            NO_LOCK => Ok(None),
+            HEIGHT_LOCK => {
+                let mut height: u32 = 0;
+                match read_u32(reader, &mut height) {
+                    Ok(_) => Ok(Some(Lock::Height(height))),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            TIME_LOCK => {
+                let mut time = Time::zero();
+                match read_struct(reader, &mut time) {
+                    Ok(_) => Ok(Some(Lock::Time(time))),
+                    Err(_) => Err(Error::InvalidFormat),
+                }
+            }
+            _ => Err(Error::InvalidFormat),
+        }
+    }
+}
@@ -1,8 +1,8 @@
 use ed25519_dalek::{Keypair, PublicKey, PUBLIC_KEY_LENGTH, SecretKey};
-use std::{fmt::{self, Debug, Formatter}, io::{Read, Write}};
+use std::{cmp::Ordering, fmt::{self, Debug, Formatter}, hash::{Hash, Hasher}, io::{Read, Write}};
 use rand::rngs::OsRng;
 
-use crate::{reader::{Readable}, error::Error, writer::Writable};
+use crate::{reader::{Readable}, error::Error, sha256::Sha256Hash, writer::Writable};
 
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct PublicUser {
@@ -73,7 +73,39 @@ impl PublicUser {
 		}
 	}
 	pub fn is_coinbase(&self) -> bool {
-		self.key.as_bytes().into_iter().all(|&b| b == 0)
+		self.key.as_bytes().iter().all(|&b| b == 0)
+	}
+
+	// the first valid point of the hashes of `seed` and a counter, about half
+	// of the hashes are not points. Nobody knows the secret key of the result.
+	pub fn derive(seed: &Vec<u8>) -> Self {
+		let mut counter: u32 = 0;
+		loop {
+			let hash = Sha256Hash::new(&[seed, &counter.to_be_bytes().to_vec()]);
+			match PublicKey::from_bytes(&hash.to_bytes()) {
+				Ok(key) => return PublicUser::new(key),
+				Err(_) => counter += 1,
+			}
+		}
+	}
+}
+
+// ordered by key bytes, it gives the ledger a stable serialization order
+impl Ord for PublicUser {
+	fn cmp(&self, other: &Self) -> Ordering {
+		self.key.as_bytes().cmp(other.key.as_bytes())
+	}
+}
+
+impl PartialOrd for PublicUser {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Hash for PublicUser {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.key.as_bytes().hash(state);
 	}
 }
 
@@ -0,0 +1,699 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Formatter};
+use std::io::Read;
+use std::io::Write;
+
+use ed25519_dalek::Signature;
+
+use crate::block::Block;
+use crate::consensus::{BlockContext, Rule, RuleSet, TransactionContext};
+use crate::error::Error;
+use crate::merkle_tree::SparseMerkleTree;
+use crate::num::Num;
+use crate::reader::{read_signature, read_struct, read_u32, read_vec_struct, Readable};
+use crate::sha256::Sha256Hash;
+use crate::signature::Signable;
+use crate::state::{BlockTransaction, StateModel};
+use crate::transaction::Output;
+use crate::user::{PublicUser, User};
+use crate::writer::{write_signature, write_struct, write_u32, write_vec_struct, Writable};
+
+// An output of a previous transaction, `index` is its position in the outputs.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct OutPoint {
+    pub transaction: Sha256Hash,
+    pub index: u32,
+}
+
+// Spends the output `previous`, signed by the user it was sent to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Input {
+    pub previous: OutPoint,
+    pub signature: Signature,
+}
+
+// A transaction of a UTXO chain: it spends whole outputs and creates new
+// ones, the difference is the fee of the miner. The coinbase has a single
+// input with a zero transaction hash, whose index is the block index so that
+// two coinbases never share a hash.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<Input>,
+    pub outputs: Vec<Output>,
+}
+
+// One leaf of the state tree.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnspentOutput {
+    pub out_point: OutPoint,
+    pub output: Output,
+}
+
+// The state of a UTXO chain: the outputs not spent yet. Like the accounts of
+// `Ledger` the map is persistent and serialized sorted, and modified through
+// `set_output` to keep the state tree in step with it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UtxoSet {
+    pub outputs: im::HashMap<OutPoint, Output>,
+    state_tree: SparseMerkleTree, // not serialized, rebuilt from the outputs
+}
+
+// `None` is an output spent by the block.
+pub struct UtxoChanges {
+    pub outputs: HashMap<OutPoint, Option<Output>>,
+}
+
+// The outputs a block modified as they were before it, `None` for the ones
+// it created.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UtxoUndo {
+    pub outputs: Vec<(OutPoint, Option<Output>)>,
+}
+
+impl OutPoint {
+    pub fn new(transaction: Sha256Hash, index: u32) -> Self {
+        OutPoint {
+            transaction: transaction,
+            index: index,
+        }
+    }
+
+    pub fn zero() -> Self {
+        OutPoint::new(Sha256Hash::zero(), 0)
+    }
+
+    fn sort_key(&self) -> ([u8; 32], u32) {
+        (self.transaction.to_bytes(), self.index)
+    }
+    // key of the output in the state tree
+    fn slot(&self) -> Sha256Hash {
+        let mut bytes = vec![];
+        self.to_writer(&mut bytes).ok();
+        Sha256Hash::new(&[&bytes])
+    }
+}
+
+impl Writable for OutPoint {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.transaction).and_then(|_| write_u32(writer, self.index))
+    }
+}
+
+impl Readable for OutPoint {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut out_point = OutPoint::zero();
+
+        match read_struct(reader, &mut out_point.transaction)
+            .and_then(|_| read_u32(reader, &mut out_point.index))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(out_point)
+    }
+}
+
+impl Input {
+    pub fn zero() -> Self {
+        Input {
+            previous: OutPoint::zero(),
+            signature: Signature::from_bytes(&[0; 64]).unwrap(),
+        }
+    }
+}
+
+impl Writable for Input {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.previous).and_then(|_| write_signature(writer, &self.signature))
+    }
+}
+
+impl Readable for Input {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut input = Input::zero();
+
+        match read_struct(reader, &mut input.previous)
+            .and_then(|_| read_signature(reader, &mut input.signature))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(input)
+    }
+}
+
+impl UtxoTransaction {
+    // each spent output is signed by the user it was sent to
+    pub fn new(spent: Vec<(OutPoint, &User)>, outputs: Vec<Output>) -> Self {
+        let mut transaction = UtxoTransaction {
+            inputs: spent
+                .iter()
+                .map(|(previous, _)| Input {
+                    previous: *previous,
+                    signature: Input::zero().signature,
+                })
+                .collect(),
+            outputs: outputs,
+        };
+        // the signed bytes do not include the signatures
+        for (i, (_, user)) in spent.iter().enumerate() {
+            transaction.inputs[i].signature = transaction.sign(&user.key_pair);
+        }
+        transaction
+    }
+
+    pub fn new_coinbase(index: u32, outputs: Vec<Output>) -> Self {
+        UtxoTransaction {
+            inputs: vec![Input {
+                previous: OutPoint::new(Sha256Hash::zero(), index),
+                signature: Input::zero().signature,
+            }],
+            outputs: outputs,
+        }
+    }
+
+    pub fn zero() -> Self {
+        UtxoTransaction {
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+
+    // the new outputs are referenced by this hash, the signatures included
+    pub fn hash(&self) -> Sha256Hash {
+        Sha256Hash::new(&[&self.to_bytes()])
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].previous.transaction == Sha256Hash::zero()
+    }
+
+    pub fn output_value(&self) -> Num {
+        let mut value = Num::zero();
+        for output in &self.outputs {
+            value += output.value.clone();
+        }
+        value
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+// every input signs the spent outputs and the new ones, not the signatures
+impl Signable for UtxoTransaction {
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        let previous: Vec<OutPoint> = self.inputs.iter().map(|i| i.previous).collect();
+
+        write_vec_struct(&mut bytes, &previous)
+            .and_then(|_| write_vec_struct(&mut bytes, &self.outputs))
+            .ok();
+
+        bytes
+    }
+}
+
+impl BlockTransaction for UtxoTransaction {
+    fn hash(&self) -> Sha256Hash {
+        UtxoTransaction::hash(self)
+    }
+
+    fn is_coinbase(&self) -> bool {
+        UtxoTransaction::is_coinbase(self)
+    }
+}
+
+impl Writable for UtxoTransaction {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_vec_struct(writer, &self.inputs).and_then(|_| write_vec_struct(writer, &self.outputs))
+    }
+}
+
+impl Readable for UtxoTransaction {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut transaction = UtxoTransaction::zero();
+
+        match read_vec_struct(reader, &mut transaction.inputs)
+            .and_then(|_| read_vec_struct(reader, &mut transaction.outputs))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(transaction)
+    }
+}
+
+impl UnspentOutput {
+    pub fn zero() -> Self {
+        UnspentOutput {
+            out_point: OutPoint::zero(),
+            output: Output::zero(),
+        }
+    }
+
+    // leaf of the state tree
+    pub fn hash(&self) -> Sha256Hash {
+        let mut bytes = vec![];
+        self.to_writer(&mut bytes).ok();
+        Sha256Hash::new(&[&bytes])
+    }
+}
+
+impl Writable for UnspentOutput {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_struct(writer, &self.out_point).and_then(|_| write_struct(writer, &self.output))
+    }
+}
+
+impl Readable for UnspentOutput {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut unspent = UnspentOutput::zero();
+
+        match read_struct(reader, &mut unspent.out_point)
+            .and_then(|_| read_struct(reader, &mut unspent.output))
+        {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+
+        Ok(unspent)
+    }
+}
+
+impl UtxoSet {
+    pub fn empty() -> Self {
+        UtxoSet {
+            outputs: im::HashMap::new(),
+            state_tree: SparseMerkleTree::new(),
+        }
+    }
+
+    // `None` spends the output
+    pub fn set_output(&mut self, out_point: OutPoint, output: Option<Output>) {
+        let leaf = output.as_ref().map(|output| {
+            UnspentOutput {
+                out_point: out_point,
+                output: output.clone(),
+            }
+            .hash()
+        });
+        self.state_tree.set(&out_point.slot(), leaf.as_ref());
+        match output {
+            Some(output) => self.outputs.insert(out_point, output),
+            None => self.outputs.remove(&out_point),
+        };
+    }
+
+    pub fn get(&self, out_point: &OutPoint) -> Option<&Output> {
+        self.outputs.get(out_point)
+    }
+
+    // sum of the unspent outputs sent to the user
+    pub fn balance_of(&self, public_user: PublicUser) -> Num {
+        let mut balance = Num::zero();
+        for output in self.outputs.values() {
+            if output.to == public_user {
+                balance += output.value.clone();
+            }
+        }
+        balance
+    }
+
+    pub fn sorted_outputs(&self) -> Vec<UnspentOutput> {
+        let mut outputs: Vec<UnspentOutput> = self
+            .outputs
+            .iter()
+            .map(|(out_point, output)| UnspentOutput {
+                out_point: *out_point,
+                output: output.clone(),
+            })
+            .collect();
+        outputs.sort_by_key(|unspent| unspent.out_point.sort_key());
+        outputs
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        self.to_writer(&mut bytes).ok();
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, Error> {
+        let mut slice: &[u8] = bytes;
+        Self::from_reader(&mut slice)
+    }
+}
+
+impl StateModel for UtxoSet {
+    type Transaction = UtxoTransaction;
+    type Changes = UtxoChanges;
+    type Undo = UtxoUndo;
+
+    fn empty() -> Self {
+        UtxoSet::empty()
+    }
+
+    fn standard_rules() -> RuleSet<UtxoSet> {
+        RuleSet::utxo()
+    }
+
+    fn empty_changes() -> UtxoChanges {
+        UtxoChanges {
+            outputs: HashMap::new(),
+        }
+    }
+
+    fn apply_transaction(
+        &self,
+        changes: &mut UtxoChanges,
+        transaction: &UtxoTransaction,
+        index: u32,
+    ) -> Result<(), Error> {
+        // checked before anything is modified
+        if transaction.is_coinbase() {
+            if transaction.inputs[0].previous.index != index {
+                return Err(Error::CoinbaseHeightIsInvalid);
+            }
+        } else {
+            let input_value = match changes.input_value(self, transaction) {
+                Ok(v) => v,
+                Err(e) => return Err(e),
+            };
+            if transaction.output_value() > input_value {
+                return Err(Error::SenderDoNotHaveEnoughMoney);
+            }
+            for input in &transaction.inputs {
+                changes.outputs.insert(input.previous, None);
+            }
+        }
+
+        let hash = transaction.hash();
+        for (i, output) in transaction.outputs.iter().enumerate() {
+            changes
+                .outputs
+                .insert(OutPoint::new(hash, i as u32), Some(output.clone()));
+        }
+        Ok(())
+    }
+
+    fn apply_changes(&self, changes: &UtxoChanges) -> Self {
+        let mut utxo_set = self.clone();
+        for (out_point, output) in &changes.outputs {
+            utxo_set.set_output(*out_point, output.clone());
+        }
+        utxo_set
+    }
+
+    fn undo(&self, changes: &UtxoChanges) -> UtxoUndo {
+        UtxoUndo {
+            outputs: changes
+                .outputs
+                .keys()
+                .map(|out_point| (*out_point, self.outputs.get(out_point).cloned()))
+                .collect(),
+        }
+    }
+
+    fn revert(&mut self, undo: &UtxoUndo) {
+        for (out_point, output) in &undo.outputs {
+            self.set_output(*out_point, output.clone());
+        }
+    }
+
+    // root of a sparse Merkle tree holding each unspent output in the slot
+    // of its out point, the empty set has the zero root
+    fn state_root(&self) -> Sha256Hash {
+        self.state_tree.root()
+    }
+}
+
+impl UtxoChanges {
+    // the output as it is once the changes are applied
+    pub fn get_output<'a>(
+        &'a self,
+        utxo_set: &'a UtxoSet,
+        out_point: &OutPoint,
+    ) -> Option<&'a Output> {
+        match self.outputs.get(out_point) {
+            Some(output) => output.as_ref(),
+            None => utxo_set.get(out_point),
+        }
+    }
+
+    // sum of the outputs spent by the transaction, each must be unspent
+    // and spent only once
+    pub fn input_value(
+        &self,
+        utxo_set: &UtxoSet,
+        transaction: &UtxoTransaction,
+    ) -> Result<Num, Error> {
+        let mut value = Num::zero();
+        let mut spent = HashSet::new();
+        for input in &transaction.inputs {
+            if !spent.insert(input.previous) {
+                return Err(Error::UnknownOutput);
+            }
+            match self.get_output(utxo_set, &input.previous) {
+                Some(output) => value += output.value.clone(),
+                None => return Err(Error::UnknownOutput),
+            }
+        }
+        Ok(value)
+    }
+}
+
+impl Debug for UtxoSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "________________________\nUtxoSet\n________________________\n"
+        )
+        .ok();
+        for unspent in self.sorted_outputs() {
+            writeln!(
+                f,
+                "{:?}:{} -> {:?}: {:?} coins",
+                unspent.out_point.transaction,
+                unspent.out_point.index,
+                unspent.output.to,
+                unspent.output.value
+            )
+            .ok();
+        }
+        writeln!(f, "________________________")
+    }
+}
+
+impl Writable for UtxoSet {
+    fn to_writer(&self, writer: &mut dyn Write) -> Result<(), Error> {
+        write_vec_struct(writer, &self.sorted_outputs())
+    }
+}
+
+impl Readable for UtxoSet {
+    fn from_reader(reader: &mut dyn Read) -> Result<Self, Error> {
+        let mut utxo_set = UtxoSet::empty();
+        let mut outputs: Vec<UnspentOutput> = vec![];
+
+        match read_vec_struct(reader, &mut outputs) {
+            Ok(_) => (),
+            Err(_) => return Err(Error::InvalidFormat),
+        };
+        for unspent in outputs {
+            // an output listed twice is not a valid state
+            if utxo_set.outputs.contains_key(&unspent.out_point) {
+                return Err(Error::InvalidFormat);
+            }
+            utxo_set.set_output(unspent.out_point, Some(unspent.output));
+        }
+
+        Ok(utxo_set)
+    }
+}
+
+impl RuleSet<UtxoSet> {
+    // The rules enforced by every node of a UTXO chain, in the order they
+    // are checked.
+    pub fn utxo() -> Self {
+        let mut rules = RuleSet::headers();
+
+        rules.push(CoinbaseMatchesFees);
+        rules.push(PositiveOutputs);
+        rules.push(InputsUnspent);
+        rules.push(InputsSigned);
+        rules.push(InputsCoverOutputs);
+
+        rules
+    }
+}
+
+// The coinbase pays the subsidy and the fees, the inputs of the block
+// minus its outputs. The fees are computed on the parent state plus the
+// outputs created earlier in the block.
+pub struct CoinbaseMatchesFees;
+
+impl Rule<UtxoSet> for CoinbaseMatchesFees {
+    fn name(&self) -> &'static str {
+        "coinbase-value"
+    }
+
+    fn check_block(
+        &self,
+        block: &Block<UtxoTransaction>,
+        context: &BlockContext<UtxoSet>,
+    ) -> Result<(), Error> {
+        let coinbase = match block.content.transactions.first() {
+            Some(transaction) => transaction,
+            None => return Err(Error::CoinbaseIsNotFirst),
+        };
+
+        let utxo_set = &context.chain.ledger;
+        let mut changes = UtxoSet::empty_changes();
+        let mut expected = context.params.halving.subsidy(block.content.index);
+        for transaction in &block.content.transactions[1..] {
+            // an invalid transaction is left to the transaction rules
+            let input_value = match changes.input_value(utxo_set, transaction) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            match utxo_set.apply_transaction(&mut changes, transaction, block.content.index) {
+                Ok(_) => (),
+                Err(_) => continue,
+            }
+            expected += input_value;
+            expected -= transaction.output_value();
+        }
+        if coinbase.output_value() != expected {
+            return Err(Error::CoinbaseValueIsInvalid);
+        }
+        Ok(())
+    }
+}
+
+// Every output moves some money, an empty transaction only burns its fee.
+pub struct PositiveOutputs;
+
+impl Rule<UtxoSet> for PositiveOutputs {
+    fn name(&self) -> &'static str {
+        "positive-value"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &UtxoTransaction,
+        _context: &TransactionContext<UtxoSet>,
+    ) -> Result<(), Error> {
+        if transaction.outputs.is_empty() && !transaction.is_coinbase() {
+            return Err(Error::TransactionOf0);
+        }
+        if transaction.outputs.iter().any(|o| o.value <= Num::zero()) {
+            return Err(Error::TransactionOf0);
+        }
+        Ok(())
+    }
+}
+
+// Each input spends an output not spent yet on the branch or in the block.
+pub struct InputsUnspent;
+
+impl Rule<UtxoSet> for InputsUnspent {
+    fn name(&self) -> &'static str {
+        "inputs-unspent"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &UtxoTransaction,
+        context: &TransactionContext<UtxoSet>,
+    ) -> Result<(), Error> {
+        if transaction.is_coinbase() {
+            return Ok(());
+        }
+        match context
+            .partial_ledger
+            .input_value(context.ledger, transaction)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Each input is signed by the user its output was sent to.
+pub struct InputsSigned;
+
+impl Rule<UtxoSet> for InputsSigned {
+    fn name(&self) -> &'static str {
+        "valid-signature"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &UtxoTransaction,
+        context: &TransactionContext<UtxoSet>,
+    ) -> Result<(), Error> {
+        if transaction.is_coinbase() {
+            return Ok(());
+        }
+        for input in &transaction.inputs {
+            let output = match context
+                .partial_ledger
+                .get_output(context.ledger, &input.previous)
+            {
+                Some(output) => output,
+                None => return Err(Error::UnknownOutput),
+            };
+            if !transaction.verify(output.to.key, input.signature) {
+                return Err(Error::WrongTransactionSignature);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Money is neither created nor destroyed outside of the coinbase, the
+// outputs may not be worth more than the inputs.
+pub struct InputsCoverOutputs;
+
+impl Rule<UtxoSet> for InputsCoverOutputs {
+    fn name(&self) -> &'static str {
+        "sufficient-funds"
+    }
+
+    fn check_transaction(
+        &self,
+        transaction: &UtxoTransaction,
+        context: &TransactionContext<UtxoSet>,
+    ) -> Result<(), Error> {
+        if transaction.is_coinbase() {
+            return Ok(());
+        }
+        let input_value = match context
+            .partial_ledger
+            .input_value(context.ledger, transaction)
+        {
+            Ok(v) => v,
+            Err(e) => return Err(e),
+        };
+        if transaction.output_value() > input_value {
+            return Err(Error::SenderDoNotHaveEnoughMoney);
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::io::Write;
 
 use ed25519_dalek::Signature;
+use num::BigUint;
 
 use crate::error::Error;
 
@@ -25,6 +26,20 @@ pub fn write_u128(writer: &mut dyn Write, u: u128) -> Result<(), Error> {
     }
 }
 
+// a presence byte, then the value
+pub fn write_option_u32(writer: &mut dyn Write, u: &Option<u32>) -> Result<(), Error> {
+    match u {
+        None => match writer.write_all(&[0]) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::EndOfBuffer),
+        },
+        Some(u) => match writer.write_all(&[1]) {
+            Ok(_) => write_u32(writer, *u),
+            Err(_) => Err(Error::EndOfBuffer),
+        },
+    }
+}
+
 pub fn write_struct<U>(writer: &mut dyn Write, s: &U) -> Result<(), Error>
 where
     U: Writable,
@@ -114,6 +129,25 @@ pub fn write_string(writer: &mut dyn Write, s: String) -> Result<(), Error> {
     Ok(())
 }
 
+// length prefixed, like `write_string`
+pub fn write_bytes(writer: &mut dyn Write, v: &[u8]) -> Result<(), Error> {
+    match write_u32(writer, v.len() as u32) {
+        Ok(_) => (),
+        Err(_) => return Err(Error::EndOfBuffer),
+    };
+
+    match writer.write_all(v) {
+        Ok(_) => (),
+        Err(_) => return Err(Error::InvalidFormat),
+    };
+
+    Ok(())
+}
+
+pub fn write_big_uint(writer: &mut dyn Write, u: &BigUint) -> Result<(), Error> {
+    write_string(writer, u.to_str_radix(10))
+}
+
 pub fn write_signature(writer: &mut dyn Write, s: &Signature) -> Result<(), Error> {
     match writer.write_all(&s.to_bytes()) {
         Ok(_) => (),
@@ -0,0 +1,32 @@
+use blockchain::block::{Block, BlockContent};
+use blockchain::ledger::Ledger;
+use blockchain::mining::Miner;
+use blockchain::sha256::Sha256Hash;
+use blockchain::state::StateModel;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    // the ledger after each block built by mine_on_parent_ledger, so that a
+    // block can commit to its state root before its parent is in a merkle
+    static LEDGERS: RefCell<HashMap<Sha256Hash, Ledger>> = RefCell::new(HashMap::new());
+}
+
+// the ledger after the block `hash`, empty for a block not built here (e.g. the genesis)
+fn ledger_after(hash: &Sha256Hash) -> Ledger {
+    LEDGERS.with(|ledgers| match ledgers.borrow().get(hash) {
+        Some(ledger) => ledger.clone(),
+        None => Ledger::empty(),
+    })
+}
+
+// sets the state root from the ledger after the parent block and mines
+pub fn mine_on_parent_ledger(mut block_content: BlockContent) -> Block {
+    let parent_ledger = ledger_after(&block_content.prev_block_hash);
+    block_content.set_state_root(&parent_ledger);
+
+    let (nonce, hash) = Miner::mine(&block_content);
+    let ledger = parent_ledger.apply_unsafe(&block_content.transactions, block_content.index);
+    LEDGERS.with(|ledgers| ledgers.borrow_mut().insert(hash, ledger));
+    Block::new_mined(block_content, nonce, hash)
+}
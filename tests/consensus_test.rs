@@ -0,0 +1,2110 @@
+mod common;
+
+use common::mine_on_parent_ledger;
+
+use blockchain::block::{Block, BlockContent};
+use blockchain::consensus::{BlockContext, HalvingSchedule, Rule, RuleSet};
+use blockchain::error::Error;
+use blockchain::merkle::{BlockOutcome, Merkle};
+use blockchain::mining::Miner;
+use blockchain::multisig::{MultisigAccount, MultisigCollector, PartialSignature};
+use blockchain::node::NodeCache;
+use blockchain::script::{Op, Script, ScriptContext};
+use blockchain::sha256::Sha256Hash;
+use blockchain::signature::Signable;
+use blockchain::target::Target;
+use blockchain::time::Time;
+use blockchain::user::{PublicUser, User};
+use blockchain::utxo::{OutPoint, UtxoSet, UtxoTransaction};
+use blockchain::{
+    num::Num,
+    transaction::{
+        HashLock, Lock, Output, Payment, TokenDefinition, Transaction, TransactionContent, Witness,
+    },
+};
+use ed25519_dalek::Keypair;
+
+fn mined_block(index: u32, prev_block_hash: Sha256Hash, payload: Vec<Transaction>) -> Block {
+    mined_block_at(
+        index,
+        Time::from_second(index as u64),
+        prev_block_hash,
+        Target::initial(),
+        payload,
+    )
+}
+
+fn mined_block_at(
+    index: u32,
+    timestamp: Time,
+    prev_block_hash: Sha256Hash,
+    target: Target,
+    payload: Vec<Transaction>,
+) -> Block {
+    let block_content = BlockContent::new(index, timestamp, prev_block_hash, target, payload);
+    mine_on_parent_ledger(block_content)
+}
+
+fn coinbase(to: &User, value: u64, nonce: u64) -> Transaction {
+    Transaction::new(
+        User::new_coinbase(),
+        to.as_public(),
+        Num::from_u64(value),
+        nonce,
+    )
+}
+
+fn expect_rejection(merkle: &mut Merkle, block: Block, rule: &str, error: Error) {
+    match merkle.submit_block(block) {
+        Ok(_) => panic!("block was accepted but should be rejected by {}", rule),
+        Err(rejection) => {
+            assert_eq!(rejection.rule, Some(rule));
+            assert_eq!(rejection.error, error);
+        }
+    }
+}
+
+#[test]
+fn transfer_with_forged_signature_is_rejected() {
+    let mut merkle = Merkle::new_from_nothingness();
+
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let mallory = User::from_nothingness();
+
+    let b1 = mined_block(1, Sha256Hash::zero(), vec![coinbase(&alice, 10, 1)]);
+    let b1_hash = b1.hash;
+    match merkle.add_block(b1) {
+        Ok(_) => (),
+        Err(e) => panic!("failed to add block b1 to merkle tree: error: {:?}", e),
+    }
+
+    // mallory signs a transfer from alice's account
+    let mut transaction = Transaction::new(mallory, bob.as_public(), Num::from_u64(5), 1);
+    transaction.content.from = alice.as_public();
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&bob, 10, 3), transaction]);
+
+    expect_rejection(
+        &mut merkle,
+        b2,
+        "valid-signature",
+        Error::WrongTransactionSignature,
+    );
+}
+
+struct NoBlockAfterGenesis;
+
+impl Rule for NoBlockAfterGenesis {
+    fn name(&self) -> &'static str {
+        "no-block-after-genesis"
+    }
+
+    fn check_block(&self, block: &Block, _context: &BlockContext) -> Result<(), Error> {
+        if block.content.index > 0 {
+            return Err(Error::BlockIndexAreNotContiguous);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_rule_is_reported_by_name() {
+    let mut merkle = Merkle::new_from_nothingness();
+    merkle.rules.push(NoBlockAfterGenesis);
+
+    let to = User::from_nothingness();
+    let block = mined_block(1, Sha256Hash::zero(), vec![coinbase(&to, 10, 1)]);
+
+    match merkle.submit_block(block.clone()) {
+        Ok(_) => panic!("block was accepted despite the custom rule"),
+        Err(rejection) => assert_eq!(rejection.rule, Some("no-block-after-genesis")),
+    }
+
+    merkle.rules.remove("no-block-after-genesis");
+    match merkle.submit_block(block) {
+        Ok(_) => (),
+        Err(e) => panic!("failed to add block once the rule is removed: {:?}", e),
+    }
+}
+
+#[test]
+fn rules_are_checked_in_order() {
+    let rules = RuleSet::standard();
+    let names = rules.names();
+
+    let position = |name: &str| names.iter().position(|n| *n == name).unwrap();
+    assert!(position("known-sender") < position("valid-signature"));
+    assert!(position("valid-signature") < position("sufficient-funds"));
+}
+
+#[test]
+fn coinbase_paying_more_than_the_subsidy_is_rejected() {
+    let mut merkle = Merkle::new_from_nothingness();
+    let miner = User::from_nothingness();
+
+    let block = mined_block(1, Sha256Hash::zero(), vec![coinbase(&miner, 1_000, 1)]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "coinbase-value",
+        Error::CoinbaseValueIsInvalid,
+    );
+}
+
+#[test]
+fn block_with_two_coinbase_is_rejected() {
+    let mut merkle = Merkle::new_from_nothingness();
+    let miner = User::from_nothingness();
+
+    let payload = vec![coinbase(&miner, 10, 1), coinbase(&miner, 10, 2)];
+    let block = mined_block(1, Sha256Hash::zero(), payload);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "single-coinbase",
+        Error::BlockHasSeveralCoinbase,
+    );
+}
+
+#[test]
+fn coinbase_not_in_first_position_is_rejected() {
+    let mut merkle = Merkle::new_from_nothingness();
+    let miner = User::from_nothingness();
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+
+    let b1 = mined_block(1, Sha256Hash::zero(), vec![coinbase(&alice, 10, 1)]);
+    let b1_hash = b1.hash;
+    merkle.add_block(b1).unwrap();
+
+    let transfer = Transaction::new(alice, bob.as_public(), Num::from_u64(5), 1);
+    let block = mined_block(2, b1_hash, vec![transfer, coinbase(&miner, 10, 3)]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "coinbase-first",
+        Error::CoinbaseIsNotFirst,
+    );
+}
+
+#[test]
+fn coinbase_subsidy_is_halved() {
+    let mut merkle = Merkle::new_from_nothingness();
+    merkle.params.halving = HalvingSchedule {
+        initial_subsidy: 8,
+        interval: 2,
+    };
+    let miner = User::from_nothingness();
+
+    let b1 = mined_block(1, Sha256Hash::zero(), vec![coinbase(&miner, 8, 1)]);
+    let b1_hash = b1.hash;
+    merkle.add_block(b1).unwrap();
+
+    // the first halving happens at height 2
+    let over_issued = mined_block(2, b1_hash, vec![coinbase(&miner, 8, 2)]);
+    expect_rejection(
+        &mut merkle,
+        over_issued,
+        "coinbase-value",
+        Error::CoinbaseValueIsInvalid,
+    );
+
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 4, 3)]);
+    match merkle.add_block(b2) {
+        Ok(_) => (),
+        Err(e) => panic!("failed to add halved block to merkle tree: error: {:?}", e),
+    }
+
+    assert_eq!(merkle.params.halving.subsidy(4), Num::from_u64(2));
+    assert_eq!(merkle.params.halving.subsidy(200), Num::zero());
+}
+
+#[test]
+fn coinbase_is_refused_by_the_mempool() {
+    let merkle = Merkle::new_from_nothingness();
+    let mut node_cache = NodeCache::new();
+    let miner = User::from_nothingness();
+
+    match node_cache.submit(&merkle, &coinbase(&miner, 10, 1)) {
+        Ok(_) => panic!("coinbase transaction was accepted in the mempool"),
+        Err(rejection) => {
+            assert_eq!(rejection.rule, Some("coinbase-only-in-block"));
+            assert_eq!(rejection.error, Error::CoinbaseOutsideOfBlock);
+        }
+    }
+}
+
+#[test]
+fn fees_are_paid_to_the_miner() {
+    let mut merkle = Merkle::new_from_nothingness();
+    let miner = User::from_nothingness();
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let (alice_public, bob_public, miner_public) =
+        (alice.as_public(), bob.as_public(), miner.as_public());
+
+    let b1 = mined_block(1, Sha256Hash::zero(), vec![coinbase(&alice, 10, 1)]);
+    let b1_hash = b1.hash;
+    merkle.add_block(b1).unwrap();
+
+    let transfer =
+        Transaction::new_with_fee(alice, bob_public, Num::from_u64(5), Num::from_u64(2), 1);
+
+    // the coinbase must claim the fee on top of the subsidy
+    let without_fee = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 3), transfer.clone()]);
+    expect_rejection(
+        &mut merkle,
+        without_fee,
+        "coinbase-value",
+        Error::CoinbaseValueIsInvalid,
+    );
+
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 12, 4), transfer]);
+    match merkle.add_block(b2) {
+        Ok(_) => (),
+        Err(e) => panic!(
+            "failed to add block with fee to merkle tree: error: {:?}",
+            e
+        ),
+    }
+
+    let ledger = &merkle.main().ledger;
+    let money = |user| ledger.get_user_data(user).unwrap().money.clone();
+    assert_eq!(money(alice_public), Num::from_u64(3));
+    assert_eq!(money(bob_public), Num::from_u64(5));
+    assert_eq!(money(miner_public), Num::from_u64(12));
+}
+
+#[test]
+fn sender_must_afford_value_and_fee() {
+    let mut merkle = Merkle::new_from_nothingness();
+    let miner = User::from_nothingness();
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+
+    let b1 = mined_block(1, Sha256Hash::zero(), vec![coinbase(&alice, 10, 1)]);
+    let b1_hash = b1.hash;
+    merkle.add_block(b1).unwrap();
+
+    let transfer = Transaction::new_with_fee(
+        alice,
+        bob.as_public(),
+        Num::from_u64(9),
+        Num::from_u64(2),
+        1,
+    );
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 12, 3), transfer]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "sufficient-funds",
+        Error::SenderDoNotHaveEnoughMoney,
+    );
+}
+
+#[test]
+fn target_is_retargeted_from_timestamps() {
+    let mut merkle = Merkle::new_from_nothingness();
+    merkle.params.retarget_interval = 2;
+    merkle.params.target_block_time = 1_000;
+    let miner = User::from_nothingness();
+    let initial = merkle.params.initial_target;
+
+    let b1 = mined_block_at(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        initial,
+        vec![coinbase(&miner, 10, 1)],
+    );
+    let b2 = mined_block_at(
+        2,
+        Time::from_second(2),
+        b1.hash,
+        initial,
+        vec![coinbase(&miner, 10, 2)],
+    );
+    // 8 seconds instead of 1, the next target is 4 times easier (clamped)
+    let b3 = mined_block_at(
+        3,
+        Time::from_second(10),
+        b2.hash,
+        initial,
+        vec![coinbase(&miner, 10, 3)],
+    );
+    let b3_hash = b3.hash;
+    for block in [b1, b2, b3] {
+        match merkle.add_block(block) {
+            Ok(_) => (),
+            Err(e) => panic!("failed to add block to merkle tree: error: {:?}", e),
+        }
+    }
+
+    let easier = merkle.main().next_target(&merkle.blocks, &merkle.params);
+    assert_eq!(
+        easier.to_big_uint(),
+        initial.to_big_uint() * 4u32,
+        "target was not retargeted"
+    );
+
+    let stale_target = mined_block_at(
+        4,
+        Time::from_second(11),
+        b3_hash,
+        initial,
+        vec![coinbase(&miner, 10, 4)],
+    );
+    expect_rejection(
+        &mut merkle,
+        stale_target,
+        "target-matches",
+        Error::BlockTargetIsInvalid,
+    );
+
+    let b4 = mined_block_at(
+        4,
+        Time::from_second(11),
+        b3_hash,
+        easier,
+        vec![coinbase(&miner, 10, 5)],
+    );
+    match merkle.add_block(b4) {
+        Ok(_) => (),
+        Err(e) => panic!(
+            "failed to add retargeted block to merkle tree: error: {:?}",
+            e
+        ),
+    }
+
+    match merkle.main().verify(&merkle.blocks, &merkle.params) {
+        Ok(_) => (),
+        Err(e) => panic!("retargeted blockchain failed to verify: error: {:?}", e),
+    }
+    let mut default_params = merkle.params;
+    default_params.retarget_interval = 16;
+    assert_eq!(
+        merkle.main().verify(&merkle.blocks, &default_params),
+        Err(Error::BlockTargetIsInvalid)
+    );
+}
+
+#[test]
+fn slow_blocks_do_not_ease_the_target_past_the_pow_limit() {
+    let mut merkle = Merkle::new_from_nothingness();
+    merkle.params.retarget_interval = 2;
+    merkle.params.target_block_time = 1_000;
+    merkle.params.pow_limit = Target::from_leading_zeros(12);
+    let miner = User::from_nothingness();
+    let initial = merkle.params.initial_target;
+    let limit = merkle.params.pow_limit;
+
+    let b1 = mined_block_at(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        initial,
+        vec![coinbase(&miner, 10, 1)],
+    );
+    let b2 = mined_block_at(
+        2,
+        Time::from_second(2),
+        b1.hash,
+        initial,
+        vec![coinbase(&miner, 10, 2)],
+    );
+    // 8 seconds instead of 1 would make the target 4 times easier
+    let b3 = mined_block_at(
+        3,
+        Time::from_second(10),
+        b2.hash,
+        initial,
+        vec![coinbase(&miner, 10, 3)],
+    );
+    let b3_hash = b3.hash;
+    for block in [b1, b2, b3] {
+        match merkle.add_block(block) {
+            Ok(_) => (),
+            Err(e) => panic!("failed to add block to merkle tree: error: {:?}", e),
+        }
+    }
+    assert!(initial.to_big_uint() * 4u32 > limit.to_big_uint());
+    assert_eq!(
+        merkle.main().next_target(&merkle.blocks, &merkle.params),
+        limit,
+        "target was not capped"
+    );
+
+    // another slow interval at the limit stays at the limit
+    let b4 = mined_block_at(
+        4,
+        Time::from_second(11),
+        b3_hash,
+        limit,
+        vec![coinbase(&miner, 10, 4)],
+    );
+    let b5 = mined_block_at(
+        5,
+        Time::from_second(60),
+        b4.hash,
+        limit,
+        vec![coinbase(&miner, 10, 5)],
+    );
+    for block in [b4, b5] {
+        match merkle.add_block(block) {
+            Ok(_) => (),
+            Err(e) => panic!("failed to add block to merkle tree: error: {:?}", e),
+        }
+    }
+    assert_eq!(
+        merkle.main().next_target(&merkle.blocks, &merkle.params),
+        limit,
+        "target went past the limit"
+    );
+}
+
+#[test]
+fn main_branch_is_the_one_with_the_most_work() {
+    let mut merkle = Merkle::new_from_nothingness();
+    merkle.params.retarget_interval = 2;
+    merkle.params.target_block_time = 1_000;
+    let miner = User::from_nothingness();
+    let initial = merkle.params.initial_target;
+
+    // a short branch whose last block was mined with a 4 times harder target
+    let a1 = mined_block_at(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        initial,
+        vec![coinbase(&miner, 10, 1)],
+    );
+    let a2 = mined_block_at(
+        2,
+        Time::from_second(2),
+        a1.hash,
+        initial,
+        vec![coinbase(&miner, 10, 2)],
+    );
+    let a3 = mined_block_at(
+        3,
+        Time::from_u128(2_001),
+        a2.hash,
+        initial,
+        vec![coinbase(&miner, 10, 3)],
+    );
+
+    // a longer branch of easy blocks
+    let b1 = mined_block_at(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        initial,
+        vec![coinbase(&miner, 10, 4)],
+    );
+    let b2 = mined_block_at(
+        2,
+        Time::from_second(2),
+        b1.hash,
+        initial,
+        vec![coinbase(&miner, 10, 5)],
+    );
+    let b3 = mined_block_at(
+        3,
+        Time::from_second(10),
+        b2.hash,
+        initial,
+        vec![coinbase(&miner, 10, 6)],
+    );
+
+    for block in [a1, a2, a3, b1, b2, b3] {
+        match merkle.add_block(block) {
+            Ok(_) => (),
+            Err(e) => panic!("failed to add block to merkle tree: error: {:?}", e),
+        }
+    }
+    let hard_target = merkle.chains[0].next_target(&merkle.blocks, &merkle.params);
+    let easy_target = merkle.chains[1].next_target(&merkle.blocks, &merkle.params);
+    assert_eq!(hard_target.to_big_uint(), initial.to_big_uint() / 4u32);
+
+    let a4 = mined_block_at(
+        4,
+        Time::from_u128(2_002),
+        merkle.chains[0].last(&merkle.blocks).hash,
+        hard_target,
+        vec![coinbase(&miner, 10, 7)],
+    );
+    let b4 = mined_block_at(
+        4,
+        Time::from_second(11),
+        merkle.chains[1].last(&merkle.blocks).hash,
+        easy_target,
+        vec![coinbase(&miner, 10, 8)],
+    );
+    let b5 = mined_block_at(
+        5,
+        Time::from_second(12),
+        b4.hash,
+        easy_target,
+        vec![coinbase(&miner, 10, 9)],
+    );
+    merkle.add_block(a4).unwrap();
+    merkle.add_block(b4).unwrap();
+    merkle.add_block(b5).unwrap();
+
+    assert!(merkle.chains[1].len() > merkle.chains[0].len());
+    assert!(merkle.chains[0].work > merkle.chains[1].work);
+    assert_eq!(merkle.main, 0, "the heaviest branch should be the main one");
+}
+
+#[test]
+fn block_older_than_the_median_time_past_is_rejected() {
+    let mut merkle = Merkle::new_from_nothingness();
+    let miner = User::from_nothingness();
+
+    let b1 = mined_block(1, Sha256Hash::zero(), vec![coinbase(&miner, 10, 1)]);
+    let b2 = mined_block(2, b1.hash, vec![coinbase(&miner, 10, 2)]);
+    let b2_hash = b2.hash;
+    merkle.add_block(b1).unwrap();
+    merkle.add_block(b2).unwrap();
+
+    // the median of the timestamps 0, 1 and 2 seconds is 1 second
+    let at_median = mined_block_at(
+        3,
+        Time::from_second(1),
+        b2_hash,
+        Target::initial(),
+        vec![coinbase(&miner, 10, 3)],
+    );
+    expect_rejection(
+        &mut merkle,
+        at_median,
+        "after-median-time-past",
+        Error::BlockTimestampIsTooOld,
+    );
+
+    // older than its parent but still after the median
+    let b3 = mined_block_at(
+        3,
+        Time::from_u128(1_500),
+        b2_hash,
+        Target::initial(),
+        vec![coinbase(&miner, 10, 4)],
+    );
+    match merkle.add_block(b3) {
+        Ok(_) => (),
+        Err(e) => panic!("failed to add block after the median time past: {:?}", e),
+    }
+}
+
+#[test]
+fn block_too_far_in_the_future_is_rejected() {
+    let mut merkle = Merkle::new_from_nothingness();
+    merkle.params.max_future_drift = 60 * 1000;
+    let miner = User::from_nothingness();
+
+    let now = Time::now().to_u128();
+    let too_late = mined_block_at(
+        1,
+        Time::from_u128(now + 10 * 60 * 1000),
+        Sha256Hash::zero(),
+        Target::initial(),
+        vec![coinbase(&miner, 10, 1)],
+    );
+    expect_rejection(
+        &mut merkle,
+        too_late,
+        "not-too-far-in-the-future",
+        Error::BlockTimestampIsTooFarInTheFuture,
+    );
+
+    let in_drift = mined_block_at(
+        1,
+        Time::from_u128(now + 30 * 1000),
+        Sha256Hash::zero(),
+        Target::initial(),
+        vec![coinbase(&miner, 10, 2)],
+    );
+    match merkle.add_block(in_drift) {
+        Ok(_) => (),
+        Err(e) => panic!("failed to add block within the allowed drift: {:?}", e),
+    }
+}
+
+// `Transaction::new` consumes the user signing it
+fn copy(user: &User) -> User {
+    User::new(Keypair::from_bytes(&user.key_pair.to_bytes()).unwrap())
+}
+
+fn funded_merkle(user: &User) -> (Merkle, Sha256Hash) {
+    let mut merkle = Merkle::new_from_nothingness();
+
+    let b1 = mined_block(1, Sha256Hash::zero(), vec![coinbase(user, 10, 1)]);
+    let b1_hash = b1.hash;
+    merkle.add_block(b1).unwrap();
+
+    (merkle, b1_hash)
+}
+
+#[test]
+fn sequences_of_a_sender_must_follow_each_other() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let first = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 1);
+    let second = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 2);
+    let skipped = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 4);
+
+    let gap = mined_block(
+        2,
+        b1_hash,
+        vec![coinbase(&miner, 10, 2), first.clone(), skipped],
+    );
+    expect_rejection(
+        &mut merkle,
+        gap,
+        "sequence-is-next",
+        Error::TransactionSequenceIsNotNext,
+    );
+
+    // two transactions of the same sender in one block
+    let b2 = mined_block(
+        2,
+        b1_hash,
+        vec![coinbase(&miner, 10, 3), first.clone(), second],
+    );
+    let b2_hash = b2.hash;
+    match merkle.add_block(b2) {
+        Ok(_) => (),
+        Err(e) => panic!("failed to add block with consecutive sequences: {:?}", e),
+    }
+    let ledger = &merkle.main().ledger;
+    assert_eq!(ledger.get_user_data(alice.as_public()).unwrap().sequence, 2);
+
+    let replay = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 4), first]);
+    expect_rejection(
+        &mut merkle,
+        replay,
+        "sequence-is-next",
+        Error::TransactionWasAlreadyDone,
+    );
+}
+
+#[test]
+fn mempool_orders_the_transactions_of_a_sender() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let (merkle, _) = funded_merkle(&alice);
+    let mut node_cache = NodeCache::new();
+
+    let first = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 1);
+    let second = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 2);
+    let third = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 3);
+
+    // received out of order, they wait for the first one
+    node_cache.submit(&merkle, &third).unwrap();
+    node_cache.submit(&merkle, &second).unwrap();
+    assert!(node_cache.pending_transactions.is_empty());
+    assert_eq!(node_cache.future_transactions.len(), 2);
+
+    node_cache.submit(&merkle, &first).unwrap();
+    assert_eq!(node_cache.pending_transactions, vec![first, second, third]);
+    assert!(node_cache.future_transactions.is_empty());
+}
+
+#[test]
+fn mempool_bounds_the_future_transactions_of_a_sender() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let (merkle, _) = funded_merkle(&alice);
+    let mut node_cache = NodeCache::new();
+    let transfer = |value, sequence| {
+        Transaction::new(
+            copy(&alice),
+            bob.as_public(),
+            Num::from_u64(value),
+            sequence,
+        )
+    };
+
+    // the next sequence of alice is 1
+    let too_far = transfer(1, 2 + NodeCache::MAX_SEQUENCE_GAP);
+    let rejection = node_cache.submit(&merkle, &too_far).unwrap_err();
+    assert_eq!(rejection.rule, Some("sequence-is-next"));
+    assert_eq!(rejection.error, Error::TransactionSequenceIsNotNext);
+
+    let held = NodeCache::MAX_FUTURE_TRANSACTIONS_PER_SENDER as u64;
+    for sequence in 2..2 + held {
+        node_cache.submit(&merkle, &transfer(1, sequence)).unwrap();
+    }
+    // another transaction at a held sequence does not fit either
+    let rejection = node_cache.submit(&merkle, &transfer(2, 3)).unwrap_err();
+    assert_eq!(rejection.error, Error::TransactionSequenceIsNotNext);
+    assert_eq!(node_cache.future_transactions.len(), held as usize);
+}
+
+#[test]
+fn transactions_must_match_the_root_of_the_header() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let transfer = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 1);
+    let mut b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), transfer]);
+
+    // the hash still matches the header, but the payload was swapped
+    b2.content.transactions = vec![coinbase(&miner, 10, 3)];
+    assert_eq!(b2.hash, b2.hash());
+    expect_rejection(
+        &mut merkle,
+        b2,
+        "transactions-root-matches",
+        Error::BlockTransactionsRootIsInvalid,
+    );
+}
+
+#[test]
+fn transaction_inclusion_is_proven_against_the_header() {
+    let miner = User::from_nothingness();
+    let payload: Vec<Transaction> = (0..5).map(|i| coinbase(&miner, 10, i)).collect();
+    let block = mined_block(1, Sha256Hash::zero(), payload.clone());
+    let root = block.content.transactions_root;
+
+    for (i, transaction) in payload.iter().enumerate() {
+        let proof = block.transaction_proof(i).unwrap();
+        assert!(proof.verify(&transaction.hash(), &root));
+        // a proof is bound to the position of its leaf
+        assert!(!proof.verify(&payload[(i + 1) % payload.len()].hash(), &root));
+    }
+    assert!(block.transaction_proof(payload.len()).is_none());
+
+    let outsider = coinbase(&miner, 10, 5);
+    let proof = block.transaction_proof(0).unwrap();
+    assert!(!proof.verify(&outsider.hash(), &root));
+}
+
+#[test]
+fn reorganization_returns_disconnected_transactions_to_the_mempool() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let transfer = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 1);
+    let b2a = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), transfer.clone()]);
+    assert_eq!(
+        merkle.add_block(b2a.clone()),
+        Ok(BlockOutcome::ExtendedMain)
+    );
+
+    // the competing block must lose the tie on equal work
+    let mut sequence = 3;
+    let b2b = loop {
+        let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, sequence)]);
+        if block.hash.as_u128() > b2a.hash.as_u128() {
+            break block;
+        }
+        sequence += 1;
+    };
+    assert_eq!(merkle.add_block(b2b.clone()), Ok(BlockOutcome::SideBranch));
+
+    let b3b = mined_block(3, b2b.hash, vec![coinbase(&miner, 10, sequence + 1)]);
+    let outcome = merkle.add_block(b3b.clone()).unwrap();
+    assert_eq!(
+        outcome,
+        BlockOutcome::Reorganized {
+            from: b2a.hash,
+            to: b3b.hash,
+            disconnected: vec![b2a],
+            connected: vec![b2b, b3b],
+        }
+    );
+
+    let mut node_cache = NodeCache::new();
+    node_cache.reorganize(&merkle, &outcome);
+    assert_eq!(node_cache.pending_transactions, vec![transfer]);
+}
+
+#[test]
+fn state_root_must_match_the_ledger_after_the_block() {
+    let alice = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    // commits to the state before the coinbase is paid
+    let payload = vec![coinbase(&miner, 10, 2)];
+    let mut block_content =
+        BlockContent::new(2, Time::from_second(2), b1_hash, Target::initial(), payload);
+    block_content.state_root = merkle.main().ledger.state_root();
+    let (nonce, hash) = Miner::mine(&block_content);
+    let b2 = Block::new_mined(block_content, nonce, hash);
+
+    expect_rejection(
+        &mut merkle,
+        b2,
+        "state-root-matches",
+        Error::BlockStateRootIsInvalid,
+    );
+}
+
+#[test]
+fn balance_is_proven_against_the_state_root() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let transfer = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(4), 1);
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), transfer]);
+    let b2_hash = b2.hash;
+    let b2_root = b2.content.state_root;
+    merkle.add_block(b2).unwrap();
+
+    let proof = merkle.balance_proof(&b2_hash, bob.as_public()).unwrap();
+    assert_eq!(proof.user_data.money, Num::from_u64(4));
+    assert!(proof.verify(&b2_root));
+
+    // the proof of a past block is checked against that block's header
+    let old_proof = merkle.balance_proof(&b1_hash, alice.as_public()).unwrap();
+    assert_eq!(old_proof.user_data.money, Num::from_u64(10));
+    assert!(!old_proof.verify(&b2_root));
+    assert!(merkle.balance_proof(&b1_hash, bob.as_public()).is_none());
+
+    let mut forged = proof.clone();
+    forged.user_data.money = Num::from_u64(400);
+    assert!(!forged.verify(&b2_root));
+}
+
+#[test]
+fn batch_payment_is_applied_atomically() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let carol = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    // more than alice owns, none of the outputs is paid
+    let too_much = Transaction::new_batch(
+        copy(&alice),
+        vec![
+            Output::new(bob.as_public(), Num::from_u64(2)),
+            Output::new(carol.as_public(), Num::from_u64(9)),
+        ],
+        Num::zero(),
+        1,
+    );
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), too_much]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "sufficient-funds",
+        Error::SenderDoNotHaveEnoughMoney,
+    );
+
+    let batch = Transaction::new_batch(
+        copy(&alice),
+        vec![
+            Output::new(bob.as_public(), Num::from_u64(3)),
+            Output::new(carol.as_public(), Num::from_u64(4)),
+            Output::new(bob.as_public(), Num::from_u64(1)),
+        ],
+        Num::from_u64(1),
+        1,
+    );
+    assert_eq!(batch.value(), Num::from_u64(8));
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 11, 3), batch]);
+    merkle.add_block(block).unwrap();
+
+    let ledger = &merkle.main().ledger;
+    let money = |user: &User| {
+        ledger
+            .get_user_data(user.as_public())
+            .unwrap()
+            .money
+            .clone()
+    };
+    assert_eq!(money(&alice), Num::from_u64(1));
+    assert_eq!(money(&bob), Num::from_u64(4));
+    assert_eq!(money(&carol), Num::from_u64(4));
+    assert_eq!(money(&miner), Num::from_u64(11));
+}
+
+#[test]
+fn batch_payment_needs_positive_outputs() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let empty = Transaction::new_batch(copy(&alice), vec![], Num::zero(), 1);
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), empty]);
+    expect_rejection(&mut merkle, block, "positive-value", Error::TransactionOf0);
+
+    let with_zero = Transaction::new_batch(
+        copy(&alice),
+        vec![
+            Output::new(bob.as_public(), Num::from_u64(3)),
+            Output::new(miner.as_public(), Num::zero()),
+        ],
+        Num::zero(),
+        1,
+    );
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 3), with_zero]);
+    expect_rejection(&mut merkle, block, "positive-value", Error::TransactionOf0);
+}
+
+#[test]
+fn multisig_account_is_spent_with_enough_signatures() {
+    let keys: Vec<User> = (0..3).map(|_| User::from_nothingness()).collect();
+    let publics: Vec<_> = keys.iter().map(|user| user.as_public()).collect();
+    let account = MultisigAccount::new(2, publics.clone()).unwrap();
+    let reversed = MultisigAccount::new(2, publics.iter().rev().copied().collect()).unwrap();
+    assert_eq!(account.address(), reversed.address());
+    assert_ne!(
+        account.address(),
+        MultisigAccount::new(3, publics.clone()).unwrap().address()
+    );
+    assert_eq!(
+        MultisigAccount::new(4, publics.clone()),
+        Err(Error::InvalidMultisigAccount)
+    );
+
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let address = account.address();
+    let funding = Transaction::new(User::new_coinbase(), address, Num::from_u64(10), 1);
+    let b1 = mined_block(1, Sha256Hash::zero(), vec![funding]);
+    let b1_hash = b1.hash;
+    let mut merkle = Merkle::new_from_nothingness();
+    merkle.add_block(b1).unwrap();
+
+    let content = TransactionContent::new(
+        address,
+        Payment::Single(Output::new(bob.as_public(), Num::from_u64(4))),
+        Num::zero(),
+        1,
+    );
+    let mut collector = MultisigCollector::new(account.clone(), content.clone());
+
+    // the signers sign offline and send their partial signature back
+    let first = PartialSignature::new(&content, &keys[0]);
+    collector
+        .add(PartialSignature::from_bytes(&first.to_bytes()).unwrap())
+        .unwrap();
+    let outsider = PartialSignature::new(&content, &bob);
+    assert_eq!(
+        collector.add(outsider),
+        Err(Error::WrongTransactionSignature)
+    );
+    assert!(!collector.is_complete());
+
+    // a single signature does not reach the threshold
+    let lonely = Transaction {
+        content: content.clone(),
+        witness: Witness::Multisig {
+            account: account.clone(),
+            signatures: vec![first.clone(), first],
+        },
+    };
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), lonely]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "valid-signature",
+        Error::WrongTransactionSignature,
+    );
+
+    collector
+        .add(PartialSignature::new(&content, &keys[2]))
+        .unwrap();
+    let spending = collector.finish().unwrap();
+    assert!(spending.verify_signature());
+
+    // the signatures are sorted by signer, a relay cannot reorder them
+    let mut reordered = spending.clone();
+    if let Witness::Multisig { signatures, .. } = &mut reordered.witness {
+        signatures.reverse();
+    }
+    assert!(!reordered.verify_signature());
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 3), spending]);
+    merkle.add_block(block).unwrap();
+
+    let ledger = &merkle.main().ledger;
+    assert_eq!(
+        ledger.get_user_data(address).unwrap().money,
+        Num::from_u64(6)
+    );
+    assert_eq!(
+        ledger.get_user_data(bob.as_public()).unwrap().money,
+        Num::from_u64(4)
+    );
+}
+
+#[test]
+fn multisig_witness_must_match_the_address() {
+    let alice = User::from_nothingness();
+    let mallory = User::from_nothingness();
+    let account = MultisigAccount::new(1, vec![alice.as_public()]).unwrap();
+    let address = account.address();
+
+    // mallory signs with a 1-of-1 account of their own
+    let content = TransactionContent::new(
+        address,
+        Payment::Single(Output::new(mallory.as_public(), Num::from_u64(4))),
+        Num::zero(),
+        1,
+    );
+    let mut collector = MultisigCollector::new(
+        MultisigAccount::new(1, vec![mallory.as_public()]).unwrap(),
+        content.clone(),
+    );
+    collector
+        .add(PartialSignature::new(&content, &mallory))
+        .unwrap();
+    assert!(!collector.finish().unwrap().verify_signature());
+
+    let mut collector = MultisigCollector::new(account, content.clone());
+    collector
+        .add(PartialSignature::new(&content, &alice))
+        .unwrap();
+    assert!(collector.finish().unwrap().verify_signature());
+}
+
+fn locked_transfer(from: &User, to: &User, value: u64, sequence: u64, lock: Lock) -> Transaction {
+    let payment = Payment::Single(Output::new(to.as_public(), Num::from_u64(value)));
+    let content = TransactionContent::new(from.as_public(), payment, Num::zero(), sequence);
+    Transaction::from_content(content.with_lock(lock), from)
+}
+
+#[test]
+fn height_locked_transaction_waits_for_its_block() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let vesting = locked_transfer(&alice, &bob, 4, 1, Lock::Height(3));
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), vesting.clone()]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "lock-is-released",
+        Error::TransactionIsLocked,
+    );
+
+    // the mempool holds it instead of rejecting it
+    let mut node_cache = NodeCache::new();
+    node_cache.submit(&merkle, &vesting).unwrap();
+    assert!(node_cache.pending_transactions.is_empty());
+    assert_eq!(node_cache.locked_transactions, vec![vesting.clone()]);
+
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 3)]);
+    let b2_hash = b2.hash;
+    merkle.add_block(b2).unwrap();
+    node_cache.release_locked(&merkle);
+    assert_eq!(node_cache.pending_transactions, vec![vesting.clone()]);
+    assert!(node_cache.locked_transactions.is_empty());
+
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 4), vesting]);
+    merkle.add_block(b3).unwrap();
+    assert_eq!(
+        merkle
+            .main()
+            .ledger
+            .get_user_data(bob.as_public())
+            .unwrap()
+            .money,
+        Num::from_u64(4)
+    );
+}
+
+#[test]
+fn time_locked_transaction_waits_for_its_timestamp() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let payout = locked_transfer(&alice, &bob, 4, 1, Lock::Time(Time::from_second(100)));
+    let early = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), payout.clone()]);
+    expect_rejection(
+        &mut merkle,
+        early,
+        "lock-is-released",
+        Error::TransactionIsLocked,
+    );
+    let on_time = mined_block_at(
+        2,
+        Time::from_second(100),
+        b1_hash,
+        Target::initial(),
+        vec![coinbase(&miner, 10, 3), payout.clone()],
+    );
+    merkle.add_block(on_time).unwrap();
+
+    // the clock of the node is long past the first lock, not the second
+    let mut node_cache = NodeCache::new();
+    let released = locked_transfer(&alice, &bob, 1, 2, Lock::Time(Time::from_second(100)));
+    let far = Time::from_second(u64::MAX / 1000);
+    let held = locked_transfer(&alice, &bob, 1, 3, Lock::Time(far));
+    node_cache.submit(&merkle, &released).unwrap();
+    node_cache.submit(&merkle, &held).unwrap();
+    assert_eq!(node_cache.pending_transactions, vec![released]);
+    assert_eq!(node_cache.locked_transactions, vec![held]);
+}
+
+#[test]
+fn mempool_bounds_the_locked_transactions() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    // enough senders to fill the pool
+    let per_sender = NodeCache::MAX_LOCKED_TRANSACTIONS_PER_SENDER;
+    let senders: Vec<User> = (0..NodeCache::MAX_LOCKED_TRANSACTIONS / per_sender + 1)
+        .map(|_| User::from_nothingness())
+        .collect();
+    let outputs = senders
+        .iter()
+        .map(|sender| Output::new(sender.as_public(), Num::from_u64(1)))
+        .collect();
+    let content =
+        TransactionContent::new(alice.as_public(), Payment::Batch(outputs), Num::zero(), 1);
+    let funding = Transaction::from_content(content, &alice);
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&alice, 10, 2)]);
+    let b2_hash = b2.hash;
+    merkle.add_block(b2).unwrap();
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 3), funding]);
+    merkle.add_block(b3).unwrap();
+
+    let mut node_cache = NodeCache::new();
+    let unknown = locked_transfer(&bob, &alice, 1, 1, Lock::Height(10));
+    let rejection = node_cache.submit(&merkle, &unknown).unwrap_err();
+    assert_eq!(rejection.rule, Some("known-sender"));
+    let unfunded = locked_transfer(&senders[0], &bob, 2, 1, Lock::Height(10));
+    let rejection = node_cache.submit(&merkle, &unfunded).unwrap_err();
+    assert_eq!(rejection.rule, Some("sufficient-funds"));
+
+    let mut height = 10;
+    for sender in &senders[1..] {
+        for _ in 0..per_sender {
+            height += 1;
+            let transfer = locked_transfer(sender, &bob, 1, 1, Lock::Height(height));
+            node_cache.submit(&merkle, &transfer).unwrap();
+        }
+    }
+    assert_eq!(
+        node_cache.locked_transactions.len(),
+        NodeCache::MAX_LOCKED_TRANSACTIONS
+    );
+    let rejection = node_cache
+        .submit(
+            &merkle,
+            &locked_transfer(&senders[1], &bob, 1, 2, Lock::Height(5)),
+        )
+        .unwrap_err();
+    assert_eq!(rejection.error, Error::TransactionIsLocked);
+
+    // a full pool drops the transaction released last, unless it is the new one
+    let furthest = locked_transfer(&senders[0], &bob, 1, 1, Lock::Height(u32::MAX));
+    assert!(node_cache.submit(&merkle, &furthest).is_err());
+    let nearest = locked_transfer(&senders[0], &bob, 1, 1, Lock::Height(5));
+    node_cache.submit(&merkle, &nearest).unwrap();
+    assert!(node_cache.locked_transactions.contains(&nearest));
+    let last = locked_transfer(senders.last().unwrap(), &bob, 1, 1, Lock::Height(height));
+    assert!(!node_cache.locked_transactions.contains(&last));
+    assert_eq!(
+        node_cache.locked_transactions.len(),
+        NodeCache::MAX_LOCKED_TRANSACTIONS
+    );
+}
+
+fn expiring_transfer(from: &User, to: &User, sequence: u64, valid_until: u32) -> Transaction {
+    let payment = Payment::Single(Output::new(to.as_public(), Num::from_u64(1)));
+    let content = TransactionContent::new(from.as_public(), payment, Num::zero(), sequence);
+    Transaction::from_content(content.with_expiry(valid_until), from)
+}
+
+#[test]
+fn expired_transaction_is_rejected() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let stale = expiring_transfer(&alice, &bob, 1, 1);
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), stale]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "not-expired",
+        Error::TransactionIsExpired,
+    );
+
+    // the expiry height itself is still valid
+    let last_call = expiring_transfer(&alice, &bob, 1, 2);
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 3), last_call]);
+    merkle.add_block(block).unwrap();
+}
+
+#[test]
+fn mempool_evicts_expired_transactions() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let expiring = expiring_transfer(&alice, &bob, 1, 2);
+    let next = Transaction::new(copy(&alice), bob.as_public(), Num::from_u64(1), 2);
+    let mut node_cache = NodeCache::new();
+    node_cache.submit(&merkle, &expiring).unwrap();
+    node_cache.submit(&merkle, &next).unwrap();
+    assert_eq!(node_cache.pending_transactions.len(), 2);
+
+    // b2 does not include it, the next block is too late
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2)]);
+    merkle.add_block(b2).unwrap();
+    node_cache.evict_expired(&merkle);
+    assert!(node_cache.pending_transactions.is_empty());
+    assert_eq!(node_cache.future_transactions, vec![next]);
+
+    let rejection = node_cache
+        .submit(&merkle, &expiring_transfer(&alice, &bob, 1, 2))
+        .unwrap_err();
+    assert_eq!(rejection.rule, Some("not-expired"));
+    assert_eq!(rejection.error, Error::TransactionIsExpired);
+}
+
+fn transfer_with_memo(from: &User, to: &User, sequence: u64, memo: Vec<u8>) -> Transaction {
+    let payment = Payment::Single(Output::new(to.as_public(), Num::from_u64(1)));
+    let content = TransactionContent::new(from.as_public(), payment, Num::zero(), sequence);
+    Transaction::from_content(content.with_memo(memo), from)
+}
+
+#[test]
+fn memo_is_bounded_and_signed() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let deposit = transfer_with_memo(&alice, &bob, 1, b"deposit 4417".to_vec());
+    assert!(format!("{:?}", deposit).ends_with(" memo \"deposit 4417\""));
+    let mut tampered = deposit.clone();
+    tampered.content.memo = b"deposit 4418".to_vec();
+    assert!(!tampered.verify_signature());
+
+    let long = vec![b'x'; TransactionContent::MAX_MEMO + 1];
+    let essay = transfer_with_memo(&alice, &bob, 1, long);
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), essay]);
+    expect_rejection(&mut merkle, block, "memo-size", Error::MemoIsTooLong);
+
+    let block = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 3), deposit]);
+    merkle.add_block(block).unwrap();
+}
+
+#[test]
+fn memos_count_against_the_block_size() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let memo = vec![b'x'; TransactionContent::MAX_MEMO];
+    let transfers: Vec<Transaction> = (1..5)
+        .map(|sequence| transfer_with_memo(&alice, &bob, sequence, memo.clone()))
+        .collect();
+
+    let mut payload = vec![coinbase(&miner, 10, 2)];
+    payload.extend(transfers.clone());
+    let block = mined_block(2, b1_hash, payload);
+    merkle.params.max_block_size = block.content.size() - 1;
+    expect_rejection(&mut merkle, block, "block-size", Error::BlockIsTooLarge);
+
+    // a miner only takes the transactions that fit
+    let block_content =
+        BlockContent::new_from_pending_transaction(&transfers, &merkle, &miner.as_public());
+    assert!(block_content.size() <= merkle.params.max_block_size);
+    assert_eq!(block_content.transactions[1..], transfers[..3]);
+}
+
+fn expect_ledger_rejection(merkle: &mut Merkle, block: Block, error: Error) {
+    match merkle.submit_block(block) {
+        Ok(_) => panic!("block was accepted but should be rejected by the ledger"),
+        Err(rejection) => {
+            assert_eq!(rejection.rule, None);
+            assert_eq!(rejection.error, error);
+        }
+    }
+}
+
+fn hash_lock(recipient: &User, value: u64, hash: Sha256Hash, timeout: u32) -> HashLock {
+    HashLock {
+        recipient: recipient.as_public(),
+        value: Num::from_u64(value),
+        hash,
+        timeout,
+    }
+}
+
+fn money(merkle: &Merkle, user: &User) -> Num {
+    match merkle.main().ledger.get_user_data(user.as_public()) {
+        Some(user_data) => user_data.money.clone(),
+        None => Num::zero(),
+    }
+}
+
+#[test]
+fn atomic_swap_between_two_chains() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let secret = b"only alice knows".to_vec();
+    let hash = Sha256Hash::new(&[&secret]);
+
+    // both own coins on both chains
+    let (mut chain_a, a1) = funded_merkle(&alice);
+    let a2 = mined_block(2, a1, vec![coinbase(&bob, 10, 2)]);
+    let a2_hash = a2.hash;
+    chain_a.add_block(a2).unwrap();
+    let (mut chain_b, b1) = funded_merkle(&bob);
+    let b2 = mined_block(2, b1, vec![coinbase(&alice, 10, 3)]);
+    let b2_hash = b2.hash;
+    chain_b.add_block(b2).unwrap();
+
+    // alice locks first with the longer timeout, so that she cannot claim on
+    // B at the last moment and leave bob no time to claim on A
+    let lock_a =
+        Transaction::new_hash_lock(copy(&alice), hash_lock(&bob, 5, hash, 8), Num::zero(), 1);
+    let a3 = mined_block(3, a2_hash, vec![coinbase(&miner, 10, 4), lock_a.clone()]);
+    let a3_hash = a3.hash;
+    chain_a.add_block(a3).unwrap();
+    assert_eq!(money(&chain_a, &alice), Num::from_u64(5));
+
+    let lock_b =
+        Transaction::new_hash_lock(copy(&bob), hash_lock(&alice, 7, hash, 5), Num::zero(), 1);
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 5), lock_b.clone()]);
+    let b3_hash = b3.hash;
+    chain_b.add_block(b3).unwrap();
+    assert_eq!(money(&chain_b, &bob), Num::from_u64(3));
+
+    // only the recipient may claim, and only with the preimage
+    let guess = Transaction::new_claim(
+        copy(&alice),
+        lock_b.content_hash(),
+        b"guess".to_vec(),
+        Num::zero(),
+        1,
+    );
+    let block = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 6), guess]);
+    expect_ledger_rejection(&mut chain_b, block, Error::WrongPreimage);
+    let theft = Transaction::new_claim(
+        copy(&miner),
+        lock_b.content_hash(),
+        secret.clone(),
+        Num::zero(),
+        1,
+    );
+    let block = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 7), theft]);
+    expect_ledger_rejection(&mut chain_b, block, Error::NotTheHashLockParty);
+
+    // alice claims on B, which reveals the secret to bob
+    let claim_b = Transaction::new_claim(
+        copy(&alice),
+        lock_b.content_hash(),
+        secret.clone(),
+        Num::zero(),
+        1,
+    );
+    let b4 = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 8), claim_b]);
+    chain_b.add_block(b4).unwrap();
+
+    let revealed = match &chain_b.main().last(&chain_b.blocks).content.transactions[1]
+        .content
+        .payment
+    {
+        Payment::Claim { preimage, .. } => preimage.clone(),
+        _ => panic!("the claim is not in the last block of chain B"),
+    };
+    let claim_a =
+        Transaction::new_claim(copy(&bob), lock_a.content_hash(), revealed, Num::zero(), 1);
+    let a4 = mined_block(4, a3_hash, vec![coinbase(&miner, 10, 9), claim_a]);
+    chain_a.add_block(a4).unwrap();
+
+    assert_eq!(money(&chain_a, &alice), Num::from_u64(5));
+    assert_eq!(money(&chain_a, &bob), Num::from_u64(15));
+    assert_eq!(money(&chain_b, &alice), Num::from_u64(17));
+    assert_eq!(money(&chain_b, &bob), Num::from_u64(3));
+    assert!(chain_a.main().ledger.hash_locks.is_empty());
+    assert!(chain_b.main().ledger.hash_locks.is_empty());
+}
+
+#[test]
+fn swap_recipients_need_no_account_to_claim() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let secret = b"only alice knows".to_vec();
+    let hash = Sha256Hash::new(&[&secret]);
+
+    // alice only owns coins on A, bob only on B
+    let (mut chain_a, a1) = funded_merkle(&alice);
+    let (mut chain_b, b1) = funded_merkle(&bob);
+    let lock_a =
+        Transaction::new_hash_lock(copy(&alice), hash_lock(&bob, 5, hash, 8), Num::zero(), 1);
+    let a2 = mined_block(2, a1, vec![coinbase(&miner, 10, 2), lock_a.clone()]);
+    let a2_hash = a2.hash;
+    chain_a.add_block(a2).unwrap();
+    let lock_b =
+        Transaction::new_hash_lock(copy(&bob), hash_lock(&alice, 7, hash, 5), Num::zero(), 1);
+    let b2 = mined_block(2, b1, vec![coinbase(&miner, 10, 3), lock_b.clone()]);
+    let b2_hash = b2.hash;
+    chain_b.add_block(b2).unwrap();
+
+    // the fee is paid out of the locked value, which must cover it
+    let greedy = Transaction::new_claim(
+        copy(&alice),
+        lock_b.content_hash(),
+        secret.clone(),
+        Num::from_u64(8),
+        1,
+    );
+    let block = mined_block(3, b2_hash, vec![coinbase(&miner, 18, 4), greedy]);
+    expect_rejection(
+        &mut chain_b,
+        block,
+        "sufficient-funds",
+        Error::SenderDoNotHaveEnoughMoney,
+    );
+
+    let claim_b = Transaction::new_claim(
+        copy(&alice),
+        lock_b.content_hash(),
+        secret.clone(),
+        Num::from_u64(1),
+        1,
+    );
+    let mut node_cache = NodeCache::new();
+    node_cache.submit(&chain_b, &claim_b).unwrap();
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 11, 5), claim_b]);
+    chain_b.add_block(b3).unwrap();
+
+    let claim_a = Transaction::new_claim(
+        copy(&bob),
+        lock_a.content_hash(),
+        secret,
+        Num::from_u64(1),
+        1,
+    );
+    let a3 = mined_block(3, a2_hash, vec![coinbase(&miner, 11, 6), claim_a]);
+    chain_a.add_block(a3).unwrap();
+
+    assert_eq!(money(&chain_a, &alice), Num::from_u64(5));
+    assert_eq!(money(&chain_a, &bob), Num::from_u64(4));
+    assert_eq!(money(&chain_b, &alice), Num::from_u64(6));
+    assert_eq!(money(&chain_b, &bob), Num::from_u64(3));
+}
+
+#[test]
+fn hash_lock_is_refunded_after_its_timeout() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let secret = b"never revealed in time".to_vec();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let lock = Transaction::new_hash_lock(
+        copy(&alice),
+        hash_lock(&bob, 6, Sha256Hash::new(&[&secret]), 3),
+        Num::zero(),
+        1,
+    );
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&bob, 10, 2), lock.clone()]);
+    let b2_hash = b2.hash;
+    merkle.add_block(b2).unwrap();
+    assert_eq!(money(&merkle, &alice), Num::from_u64(4));
+
+    let early = Transaction::new_refund(copy(&alice), lock.content_hash(), Num::zero(), 2);
+    let block = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 3), early]);
+    expect_ledger_rejection(&mut merkle, block, Error::HashLockIsNotExpired);
+
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 4)]);
+    let b3_hash = b3.hash;
+    merkle.add_block(b3).unwrap();
+
+    // too late for bob, even with the secret
+    let late = Transaction::new_claim(copy(&bob), lock.content_hash(), secret, Num::zero(), 1);
+    let block = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 5), late]);
+    expect_ledger_rejection(&mut merkle, block, Error::HashLockIsExpired);
+
+    let refund = Transaction::new_refund(copy(&alice), lock.content_hash(), Num::zero(), 2);
+    let b4 = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 6), refund.clone()]);
+    merkle.add_block(b4).unwrap();
+    assert_eq!(money(&merkle, &alice), Num::from_u64(10));
+
+    // the lock is spent, it cannot be refunded twice
+    let again = Transaction::new_refund(copy(&alice), lock.content_hash(), Num::zero(), 3);
+    let block = mined_block(
+        5,
+        merkle.main().last(&merkle.blocks).hash,
+        vec![coinbase(&miner, 10, 7), again],
+    );
+    expect_ledger_rejection(&mut merkle, block, Error::UnknownHashLock);
+}
+
+fn scripted(content: TransactionContent, script: &Script, arguments: Vec<Vec<u8>>) -> Transaction {
+    Transaction {
+        content,
+        witness: Witness::Script {
+            script: script.clone(),
+            arguments,
+        },
+    }
+}
+
+// a merkle whose main branch ends with `value` sent by alice to the script
+fn funded_script(alice: &User, script: &Script, value: u64) -> (Merkle, Sha256Hash) {
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(alice);
+
+    let funding = Transaction::new(copy(alice), script.address(), Num::from_u64(value), 1);
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), funding]);
+    let b2_hash = b2.hash;
+    merkle.add_block(b2).unwrap();
+
+    (merkle, b2_hash)
+}
+
+#[test]
+fn script_account_is_spent_when_its_script_succeeds() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let secret = b"open sesame".to_vec();
+
+    // bob's signature and the preimage of the hash
+    let script = Script::new(vec![
+        Op::Push(bob.as_public().to_bytes().to_vec()),
+        Op::CheckSig,
+        Op::Verify,
+        Op::Sha256,
+        Op::Push(Sha256Hash::new(&[&secret]).to_bytes().to_vec()),
+        Op::Equal,
+    ]);
+    let (mut merkle, b2_hash) = funded_script(&alice, &script, 6);
+
+    let payment = Payment::Single(Output::new(bob.as_public(), Num::from_u64(6)));
+    let content = TransactionContent::new(script.address(), payment, Num::zero(), 1);
+    let signature = content.sign(&bob.key_pair).to_bytes().to_vec();
+
+    let guess = scripted(
+        content.clone(),
+        &script,
+        vec![b"guess".to_vec(), signature.clone()],
+    );
+    let block = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 3), guess]);
+    expect_rejection(&mut merkle, block, "script-succeeds", Error::ScriptFailed);
+
+    let forged = content.sign(&alice.key_pair).to_bytes().to_vec();
+    let stolen = scripted(content.clone(), &script, vec![secret.clone(), forged]);
+    let block = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 4), stolen]);
+    expect_rejection(&mut merkle, block, "script-succeeds", Error::ScriptFailed);
+
+    // another script does not unlock the account, even if it succeeds
+    let anyone = Script::new(vec![Op::Push(vec![1])]);
+    let replaced = scripted(content.clone(), &anyone, vec![]);
+    let block = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 5), replaced]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "valid-signature",
+        Error::WrongTransactionSignature,
+    );
+
+    let spending = scripted(content, &script, vec![secret, signature]);
+    let block = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 6), spending]);
+    merkle.add_block(block).unwrap();
+    let ledger = &merkle.main().ledger;
+    assert_eq!(
+        ledger.get_user_data(bob.as_public()).unwrap().money,
+        Num::from_u64(6)
+    );
+    assert_eq!(
+        ledger.get_user_data(script.address()).unwrap().money,
+        Num::zero()
+    );
+}
+
+#[test]
+fn script_height_condition_is_checked_against_the_block() {
+    let alice = User::from_nothingness();
+    let miner = User::from_nothingness();
+
+    // alice's signature, from the block 4 on
+    let script = Script::new(vec![
+        Op::Push(4u32.to_be_bytes().to_vec()),
+        Op::CheckHeight,
+        Op::Verify,
+        Op::Push(alice.as_public().to_bytes().to_vec()),
+        Op::CheckSig,
+    ]);
+    let (mut merkle, b2_hash) = funded_script(&alice, &script, 6);
+
+    let payment = Payment::Single(Output::new(miner.as_public(), Num::from_u64(6)));
+    let content = TransactionContent::new(script.address(), payment, Num::zero(), 1);
+    let signature = content.sign(&alice.key_pair).to_bytes().to_vec();
+    let spending = scripted(content, &script, vec![signature]);
+
+    // the mempool runs the script against the next block
+    let mut node_cache = NodeCache::new();
+    let rejection = node_cache.submit(&merkle, &spending).unwrap_err();
+    assert_eq!(rejection.rule, Some("script-succeeds"));
+    assert_eq!(rejection.error, Error::ScriptFailed);
+
+    let block = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 3), spending.clone()]);
+    expect_rejection(&mut merkle, block, "script-succeeds", Error::ScriptFailed);
+
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 4)]);
+    let b3_hash = b3.hash;
+    merkle.add_block(b3).unwrap();
+    node_cache.submit(&merkle, &spending).unwrap();
+    let b4 = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 5), spending]);
+    merkle.add_block(b4).unwrap();
+}
+
+#[test]
+fn hash_lock_id_does_not_depend_on_the_witness() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let secret = b"claimed with the original id".to_vec();
+
+    let script = Script::new(vec![
+        Op::Push(alice.as_public().to_bytes().to_vec()),
+        Op::CheckSig,
+    ]);
+    let (mut merkle, b2_hash) = funded_script(&alice, &script, 6);
+
+    let payment = Payment::HashLock(hash_lock(&bob, 5, Sha256Hash::new(&[&secret]), 8));
+    let content = TransactionContent::new(script.address(), payment, Num::zero(), 1);
+    let signature = content.sign(&alice.key_pair).to_bytes().to_vec();
+    let lock = scripted(content.clone(), &script, vec![signature.clone()]);
+
+    // a relay pads the arguments, the script still succeeds
+    let relayed = scripted(content, &script, vec![b"padding".to_vec(), signature]);
+    assert_ne!(lock.hash(), relayed.hash());
+    assert_eq!(lock.content_hash(), relayed.content_hash());
+    // bob gets coins to pay for his claim
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&bob, 10, 3), relayed]);
+    let b3_hash = b3.hash;
+    merkle.add_block(b3).unwrap();
+
+    let claim = Transaction::new_claim(copy(&bob), lock.content_hash(), secret, Num::zero(), 1);
+    let b4 = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 4), claim]);
+    merkle.add_block(b4).unwrap();
+    assert_eq!(money(&merkle, &bob), Num::from_u64(15));
+}
+
+#[test]
+fn script_resource_limits_are_enforced() {
+    let content = TransactionContent::new(
+        PublicUser::zero(),
+        Payment::Single(Output::zero()),
+        Num::zero(),
+        1,
+    );
+    let context = ScriptContext {
+        content: &content,
+        index: 1,
+        timestamp: Time::zero(),
+    };
+    let run = |ops: Vec<Op>, arguments: Vec<Vec<u8>>| Script::new(ops).run(&arguments, &context);
+
+    assert_eq!(run(vec![Op::Push(vec![1])], vec![]), Ok(()));
+    assert_eq!(run(vec![Op::Not], vec![vec![1]]), Err(Error::ScriptFailed));
+    assert_eq!(run(vec![Op::And], vec![vec![1]]), Err(Error::InvalidScript));
+    assert_eq!(
+        run(vec![Op::CheckHeight], vec![vec![1]]),
+        Err(Error::InvalidScript)
+    );
+
+    let too_long = vec![Op::Push(vec![1]); Script::MAX_OPS + 1];
+    assert_eq!(run(too_long, vec![]), Err(Error::ScriptLimitExceeded));
+    let too_deep = vec![Op::Dup; Script::MAX_STACK];
+    assert_eq!(
+        run(too_deep, vec![vec![1]]),
+        Err(Error::ScriptLimitExceeded)
+    );
+    let too_big = vec![0; Script::MAX_ITEM_SIZE + 1];
+    assert_eq!(
+        run(vec![Op::Push(too_big)], vec![]),
+        Err(Error::ScriptLimitExceeded)
+    );
+
+    let mut signature_checks = vec![];
+    for _ in 0..Script::MAX_SIGNATURE_CHECKS + 1 {
+        signature_checks.push(Op::Push(vec![0; 64]));
+        signature_checks.push(Op::Push(PublicUser::zero().to_bytes().to_vec()));
+        signature_checks.push(Op::CheckSig);
+        signature_checks.push(Op::Drop);
+    }
+    signature_checks.push(Op::Push(vec![1]));
+    assert_eq!(
+        run(signature_checks, vec![]),
+        Err(Error::ScriptLimitExceeded)
+    );
+}
+
+fn utxo_block(
+    merkle: &Merkle<UtxoSet>,
+    parent: Sha256Hash,
+    payload: Vec<UtxoTransaction>,
+) -> Block<UtxoTransaction> {
+    let index = merkle.get_block(&parent).unwrap().content.index + 1;
+    let mut block_content = BlockContent::new(
+        index,
+        Time::from_second(index as u64),
+        parent,
+        Target::initial(),
+        payload,
+    );
+    block_content.set_state_root(&merkle.ledger_at(&parent).unwrap());
+
+    let (nonce, hash) = Miner::mine(&block_content);
+    Block::new_mined(block_content, nonce, hash)
+}
+
+fn utxo_coinbase(index: u32, to: &User, value: u64) -> UtxoTransaction {
+    UtxoTransaction::new_coinbase(
+        index,
+        vec![Output::new(to.as_public(), Num::from_u64(value))],
+    )
+}
+
+fn expect_utxo_rejection(
+    merkle: &mut Merkle<UtxoSet>,
+    block: Block<UtxoTransaction>,
+    rule: &str,
+    error: Error,
+) {
+    match merkle.submit_block(block) {
+        Ok(_) => panic!("block was accepted but should be rejected by {}", rule),
+        Err(rejection) => {
+            assert_eq!(rejection.rule, Some(rule));
+            assert_eq!(rejection.error, error);
+        }
+    }
+}
+
+#[test]
+fn utxo_chain_spends_each_output_once() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let mut merkle: Merkle<UtxoSet> = Merkle::new_from_genesis();
+    let genesis = merkle.blocks[0].hash;
+
+    let coinbase = utxo_coinbase(1, &alice, 10);
+    let funds = OutPoint::new(coinbase.hash(), 0);
+    let b1 = utxo_block(&merkle, genesis, vec![coinbase]);
+    let b1_hash = b1.hash;
+    assert_eq!(merkle.add_block(b1), Ok(BlockOutcome::ExtendedMain));
+
+    // 7 to bob, 2 back to alice and 1 of fee for the miner
+    let payment = UtxoTransaction::new(
+        vec![(funds, &alice)],
+        vec![
+            Output::new(bob.as_public(), Num::from_u64(7)),
+            Output::new(alice.as_public(), Num::from_u64(2)),
+        ],
+    );
+    let change = OutPoint::new(payment.hash(), 1);
+    let b2 = utxo_block(
+        &merkle,
+        b1_hash,
+        vec![utxo_coinbase(2, &miner, 11), payment],
+    );
+    let b2_hash = b2.hash;
+    assert_eq!(merkle.add_block(b2), Ok(BlockOutcome::ExtendedMain));
+
+    let utxo_set = &merkle.main().ledger;
+    assert_eq!(utxo_set.balance_of(alice.as_public()), Num::from_u64(2));
+    assert_eq!(utxo_set.balance_of(bob.as_public()), Num::from_u64(7));
+    assert_eq!(utxo_set.balance_of(miner.as_public()), Num::from_u64(11));
+    assert_eq!(utxo_set.get(&funds), None);
+
+    let double_spend = UtxoTransaction::new(
+        vec![(funds, &alice)],
+        vec![Output::new(alice.as_public(), Num::from_u64(10))],
+    );
+    let b3 = utxo_block(
+        &merkle,
+        b2_hash,
+        vec![utxo_coinbase(3, &miner, 10), double_spend],
+    );
+    expect_utxo_rejection(&mut merkle, b3, "inputs-unspent", Error::UnknownOutput);
+
+    let stolen = UtxoTransaction::new(
+        vec![(change, &bob)],
+        vec![Output::new(bob.as_public(), Num::from_u64(2))],
+    );
+    let b3 = utxo_block(&merkle, b2_hash, vec![utxo_coinbase(3, &miner, 10), stolen]);
+    expect_utxo_rejection(
+        &mut merkle,
+        b3,
+        "valid-signature",
+        Error::WrongTransactionSignature,
+    );
+
+    // the fee of 1 is not claimed, or claimed twice
+    let spend = UtxoTransaction::new(
+        vec![(change, &alice)],
+        vec![Output::new(bob.as_public(), Num::from_u64(1))],
+    );
+    let b3 = utxo_block(&merkle, b2_hash, vec![utxo_coinbase(3, &miner, 12), spend]);
+    expect_utxo_rejection(
+        &mut merkle,
+        b3,
+        "coinbase-value",
+        Error::CoinbaseValueIsInvalid,
+    );
+}
+
+#[test]
+fn utxo_reorganization_restores_spent_outputs() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let carol = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let mut merkle: Merkle<UtxoSet> = Merkle::new_from_genesis();
+    let genesis = merkle.blocks[0].hash;
+
+    let coinbase = utxo_coinbase(1, &alice, 10);
+    let funds = OutPoint::new(coinbase.hash(), 0);
+    let b1 = utxo_block(&merkle, genesis, vec![coinbase]);
+    let b1_hash = b1.hash;
+    merkle.add_block(b1).unwrap();
+
+    let to_bob = UtxoTransaction::new(
+        vec![(funds, &alice)],
+        vec![Output::new(bob.as_public(), Num::from_u64(10))],
+    );
+    let b2 = utxo_block(&merkle, b1_hash, vec![utxo_coinbase(2, &miner, 10), to_bob]);
+    merkle.add_block(b2).unwrap();
+    assert_eq!(
+        merkle.main().ledger.balance_of(bob.as_public()),
+        Num::from_u64(10)
+    );
+
+    // a longer branch spends the same output to carol instead
+    let to_carol = UtxoTransaction::new(
+        vec![(funds, &alice)],
+        vec![Output::new(carol.as_public(), Num::from_u64(10))],
+    );
+    let c2 = utxo_block(
+        &merkle,
+        b1_hash,
+        vec![utxo_coinbase(2, &miner, 10), to_carol],
+    );
+    let c2_hash = c2.hash;
+    merkle.add_block(c2).unwrap();
+    let c3 = utxo_block(&merkle, c2_hash, vec![utxo_coinbase(3, &miner, 10)]);
+    let c3_hash = c3.hash;
+    merkle.add_block(c3).unwrap();
+    assert_eq!(merkle.main().last(&merkle.blocks).hash, c3_hash);
+
+    let utxo_set = &merkle.main().ledger;
+    assert_eq!(utxo_set.balance_of(bob.as_public()), Num::zero());
+    assert_eq!(utxo_set.balance_of(carol.as_public()), Num::from_u64(10));
+    assert_eq!(utxo_set.balance_of(miner.as_public()), Num::from_u64(20));
+}
+
+fn points(max_supply: u64) -> TokenDefinition {
+    TokenDefinition {
+        symbol: "POINTS".to_string(),
+        max_supply: Num::from_u64(max_supply),
+    }
+}
+
+fn tokens(merkle: &Merkle, user: &User, token: &Sha256Hash) -> Num {
+    match merkle.main().ledger.get_user_data(user.as_public()) {
+        Some(user_data) => user_data.token_balance(token),
+        None => Num::zero(),
+    }
+}
+
+#[test]
+fn issuer_mints_loyalty_points_that_holders_transfer() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let carol = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let creation = Transaction::new_token(copy(&alice), points(100), Num::from_u64(1), 1);
+    let token = creation.content_hash();
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 11, 2), creation]);
+    let b2_hash = b2.hash;
+    merkle.add_block(b2).unwrap();
+    assert_eq!(money(&merkle, &alice), Num::from_u64(9));
+
+    let mint = Transaction::new_mint(
+        copy(&alice),
+        token,
+        Output::new(bob.as_public(), Num::from_u64(60)),
+        Num::zero(),
+        2,
+    );
+    let transfer = Transaction::new_token_transfer(
+        copy(&bob),
+        token,
+        Output::new(carol.as_public(), Num::from_u64(25)),
+        Num::zero(),
+        1,
+    );
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 3), mint, transfer]);
+    let b3_hash = b3.hash;
+    merkle.add_block(b3).unwrap();
+
+    assert_eq!(tokens(&merkle, &bob, &token), Num::from_u64(35));
+    assert_eq!(tokens(&merkle, &carol, &token), Num::from_u64(25));
+    assert_eq!(tokens(&merkle, &alice, &token), Num::zero());
+    // the native balances are untouched
+    assert_eq!(money(&merkle, &bob), Num::zero());
+    let issued = merkle.main().ledger.get_token(&token).unwrap();
+    assert_eq!(issued.issuer, alice.as_public());
+    assert_eq!(issued.supply, Num::from_u64(60));
+
+    let mint = |from: &User, value: u64, sequence: u64| {
+        let output = Output::new(bob.as_public(), Num::from_u64(value));
+        Transaction::new_mint(copy(from), token, output, Num::zero(), sequence)
+    };
+    let block = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 4), mint(&bob, 1, 2)]);
+    expect_ledger_rejection(&mut merkle, block, Error::NotTheTokenIssuer);
+    let block = mined_block(
+        4,
+        b3_hash,
+        vec![coinbase(&miner, 10, 4), mint(&alice, 41, 3)],
+    );
+    expect_ledger_rejection(&mut merkle, block, Error::TokenSupplyExceeded);
+
+    let overdraft = Transaction::new_token_transfer(
+        copy(&bob),
+        token,
+        Output::new(carol.as_public(), Num::from_u64(36)),
+        Num::zero(),
+        2,
+    );
+    let block = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 4), overdraft]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "sufficient-funds",
+        Error::NotEnoughTokens,
+    );
+}
+
+#[test]
+fn token_transfers_are_signed_and_not_replayed() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+    let (mut merkle, b1_hash) = funded_merkle(&alice);
+
+    let creation = Transaction::new_token(copy(&alice), points(100), Num::zero(), 1);
+    let token = creation.content_hash();
+    let mint = Transaction::new_mint(
+        copy(&alice),
+        token,
+        Output::new(alice.as_public(), Num::from_u64(50)),
+        Num::zero(),
+        2,
+    );
+    let b2 = mined_block(2, b1_hash, vec![coinbase(&miner, 10, 2), creation, mint]);
+    let b2_hash = b2.hash;
+    merkle.add_block(b2).unwrap();
+
+    let transfer = Transaction::new_token_transfer(
+        copy(&alice),
+        token,
+        Output::new(bob.as_public(), Num::from_u64(20)),
+        Num::zero(),
+        3,
+    );
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 3), transfer.clone()]);
+    let b3_hash = b3.hash;
+    merkle.add_block(b3).unwrap();
+
+    let block = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 4), transfer]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "sequence-is-next",
+        Error::TransactionWasAlreadyDone,
+    );
+
+    // bob signs a transfer of the points of alice
+    let payment = Payment::TokenTransfer {
+        token,
+        output: Output::new(bob.as_public(), Num::from_u64(30)),
+    };
+    let content = TransactionContent::new(alice.as_public(), payment, Num::zero(), 4);
+    let forged = Transaction::from_content(content, &bob);
+    let block = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 4), forged]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "valid-signature",
+        Error::WrongTransactionSignature,
+    );
+
+    let mut definition = points(100);
+    definition.symbol = "NO SPACE".to_string();
+    let invalid = Transaction::new_token(copy(&alice), definition, Num::zero(), 4);
+    let block = mined_block(4, b3_hash, vec![coinbase(&miner, 10, 4), invalid]);
+    expect_rejection(
+        &mut merkle,
+        block,
+        "token-symbol",
+        Error::InvalidTokenSymbol,
+    );
+}
+
+#[test]
+fn token_id_does_not_depend_on_the_witness() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+    let miner = User::from_nothingness();
+
+    let script = Script::new(vec![
+        Op::Push(alice.as_public().to_bytes().to_vec()),
+        Op::CheckSig,
+    ]);
+    let (mut merkle, b2_hash) = funded_script(&alice, &script, 6);
+    let sign = |payment: Payment, sequence: u64, padding: Vec<Vec<u8>>| {
+        let content = TransactionContent::new(script.address(), payment, Num::zero(), sequence);
+        let mut arguments = padding;
+        arguments.push(content.sign(&alice.key_pair).to_bytes().to_vec());
+        scripted(content, &script, arguments)
+    };
+
+    // the issuer prepares the mint before the creation is relayed
+    let creation = sign(Payment::CreateToken(points(100)), 1, vec![]);
+    let token = creation.content_hash();
+    let output = Output::new(bob.as_public(), Num::from_u64(30));
+    let mint = sign(Payment::Mint { token, output }, 2, vec![]);
+
+    // a relay pads the arguments, the script still succeeds
+    let relayed = sign(
+        Payment::CreateToken(points(100)),
+        1,
+        vec![b"padding".to_vec()],
+    );
+    assert_ne!(creation.hash(), relayed.hash());
+    let b3 = mined_block(3, b2_hash, vec![coinbase(&miner, 10, 3), relayed, mint]);
+    merkle.add_block(b3).unwrap();
+    assert_eq!(tokens(&merkle, &bob, &token), Num::from_u64(30));
+}
@@ -1,20 +1,26 @@
+mod common;
+
+use common::mine_on_parent_ledger;
+
 use blockchain::block::{Block, BlockContent};
+use blockchain::consensus::Params;
 use blockchain::error::Error;
-use blockchain::merkle::Merkle;
+use blockchain::ledger::{Ledger, UserData};
+use blockchain::merkle::{BlockOutcome, Merkle};
 use blockchain::mining::Miner;
+use blockchain::orphan::OrphanPool;
 use blockchain::sha256::Sha256Hash;
+use blockchain::target::Target;
 use blockchain::time::Time;
 use blockchain::user::User;
 use blockchain::{num::Num, transaction::Transaction};
 
 fn block_mining_on_mined_event(block_content: BlockContent, nonce: u128, sha: Sha256Hash) {
-    let hash = Sha256Hash::new(&[&block_content.to_bytes(), &nonce.to_be_bytes().to_vec()]);
+    let hash = Sha256Hash::new(&[&block_content.header_bytes(), &nonce.to_be_bytes().to_vec()]);
     assert_eq!(sha, hash, "mining failed, incorrect hash");
 
-    let difficulty: u128 = Merkle::DIFFICULTY;
-
     assert!(
-        Miner::check_difficulty(&hash, difficulty),
+        Miner::check_difficulty(&hash, &block_content.target),
         "mining failed, difficulty not meet {:?}",
         hash
     )
@@ -27,10 +33,15 @@ fn block_mining() {
 
     let transaction = Transaction::new(from, to.as_public(), Num::from_u64(10), 1);
     let payload: Vec<Transaction> = vec![transaction];
-    let block_content = BlockContent::new(1, Time::from_second(0), Sha256Hash::zero(), payload);
+    let block_content = BlockContent::new(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        Target::initial(),
+        payload,
+    );
 
-    let difficulty: u128 = Merkle::DIFFICULTY;
-    let miner = Miner::new(block_content, block_mining_on_mined_event, difficulty);
+    let miner = Miner::new(block_content, block_mining_on_mined_event);
 
     let handle = miner.start_mining();
     handle.join().unwrap();
@@ -45,10 +56,16 @@ fn merkle_add_block() {
 
     let transaction = Transaction::new(from, to.as_public(), Num::from_u64(10), 1);
     let payload: Vec<Transaction> = vec![transaction];
-    let block_content = BlockContent::new(1, Time::from_second(0), Sha256Hash::zero(), payload);
+    let mut block_content = BlockContent::new(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        Target::initial(),
+        payload,
+    );
+    block_content.set_state_root(&merkle.main().ledger);
 
-    let difficulty: u128 = Merkle::DIFFICULTY;
-    let (nonce, hash) = Miner::mine(&block_content, difficulty);
+    let (nonce, hash) = Miner::mine(&block_content);
     let block = Block::new_mined(block_content, nonce, hash);
 
     match merkle.add_block(block) {
@@ -70,12 +87,18 @@ fn merkle_add_block_from_invalid_user() {
     let from = User::from_nothingness();
     let to: User = User::from_nothingness();
 
+    let coinbase = Transaction::new(User::new_coinbase(), to.as_public(), Num::from_u64(10), 0);
     let transaction = Transaction::new(from, to.as_public(), Num::from_u64(10), 1);
-    let payload: Vec<Transaction> = vec![transaction];
-    let block_content = BlockContent::new(1, Time::from_second(0), Sha256Hash::zero(), payload);
+    let payload: Vec<Transaction> = vec![coinbase, transaction];
+    let block_content = BlockContent::new(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        Target::initial(),
+        payload,
+    );
 
-    let difficulty: u128 = Merkle::DIFFICULTY;
-    let (nonce, hash) = Miner::mine(&block_content, difficulty);
+    let (nonce, hash) = Miner::mine(&block_content);
     let block = Block::new_mined(block_content, nonce, hash);
 
     match merkle.add_block(block) {
@@ -93,10 +116,15 @@ fn merkle_add_block_no_transaction() {
     let mut merkle = Merkle::new_from_nothingness();
 
     let payload: Vec<Transaction> = vec![];
-    let block_content = BlockContent::new(1, Time::from_second(0), Sha256Hash::zero(), payload);
+    let block_content = BlockContent::new(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        Target::initial(),
+        payload,
+    );
 
-    let difficulty: u128 = Merkle::DIFFICULTY;
-    let (nonce, hash) = Miner::mine(&block_content, difficulty);
+    let (nonce, hash) = Miner::mine(&block_content);
     let block = Block::new_mined(block_content, nonce, hash);
 
     match merkle.add_block(block) {
@@ -119,13 +147,14 @@ fn block_with_transaction(
 
     let transaction = Transaction::new(from, to.as_public(), Num::from_u64(10), transaction_nonce);
     let payload: Vec<Transaction> = vec![transaction];
-    let block_content = BlockContent::new(index, Time::from_second(0), prev_block_hash, payload);
-
-    let difficulty: u128 = Merkle::DIFFICULTY;
-    let (nonce, hash) = Miner::mine(&block_content, difficulty);
-    let block = Block::new_mined(block_content, nonce, hash);
-
-    block
+    let block_content = BlockContent::new(
+        index,
+        Time::from_second(index as u64),
+        prev_block_hash,
+        Target::initial(),
+        payload,
+    );
+    mine_on_parent_ledger(block_content)
 }
 
 #[test]
@@ -140,6 +169,11 @@ fn merkle_branch() {
     let c2_b2 = block_with_transaction(5, 2, c2_b1.hash);
     let c2_b3 = block_with_transaction(6, 3, c2_b2.hash);
     let c2_b4 = block_with_transaction(7, 4, c2_b3.hash);
+    let tie_winner = if c2_b3.hash.as_u128() < c1_b3.hash.as_u128() {
+        1
+    } else {
+        0
+    };
 
     match merkle.add_block(c1_b1) {
         Ok(_) => (),
@@ -197,8 +231,13 @@ fn merkle_branch() {
         Err(e) => panic!("failed to add block c2 b3 to merkle tree: error: {:?}", e),
     }
     assert!(
-        merkle.main == 0,
-        "Error invalid main branch, should be still be 0 but is {}",
+        merkle.chains[0].work == merkle.chains[1].work,
+        "Error both branch should have the same work",
+    );
+    assert!(
+        merkle.main == tie_winner,
+        "Error invalid main branch, the lowest tip hash should win a tie, expected {} but is {}",
+        tie_winner,
         merkle.main,
     );
     match merkle.add_block(c2_b4) {
@@ -242,3 +281,327 @@ fn invalid_blockchain() {
         ),
     }
 }
+
+#[test]
+fn orphans_are_connected_once_their_parent_arrives() {
+    let mut merkle = Merkle::new_from_nothingness();
+    let mut orphans = OrphanPool::new();
+
+    let b1 = block_with_transaction(1, 1, Sha256Hash::zero());
+    let b2 = block_with_transaction(2, 2, b1.hash);
+    let b3 = block_with_transaction(3, 3, b2.hash);
+
+    // received from the tip down
+    for block in [&b3, &b2] {
+        assert_eq!(
+            merkle.add_block(block.clone()),
+            Err(Error::PreviousBlockNotFound)
+        );
+        orphans
+            .insert(block.clone(), "peer", &merkle.params, Time::from_second(1))
+            .unwrap();
+    }
+    assert_eq!(orphans.len(), 2);
+
+    merkle.add_block(b1.clone()).unwrap();
+    let connected = orphans.connect_children(&mut merkle, b1.hash);
+
+    assert_eq!(
+        connected,
+        vec![
+            (b2, BlockOutcome::ExtendedMain),
+            (b3.clone(), BlockOutcome::ExtendedMain)
+        ]
+    );
+    assert_eq!(orphans.len(), 0);
+    assert_eq!(merkle.main().last(&merkle.blocks).hash, b3.hash);
+}
+
+// a mined block with no parent around, as cheap to mine as allowed
+fn orphan(index: u32, params: &Params) -> Block {
+    let parent = Sha256Hash::new(&[&vec![1]]);
+    let block = Block::new(
+        index,
+        Time::from_second(1),
+        parent,
+        params.pow_limit,
+        vec![],
+    );
+    let (nonce, hash) = Miner::mine(&block.content);
+    Block::new_mined(block.content, nonce, hash)
+}
+
+#[test]
+fn orphan_pool_is_bounded() {
+    let params = Params::default();
+    let mut orphans = OrphanPool::new();
+
+    for i in 0..OrphanPool::MAX_ORPHANS_PER_PEER as u32 {
+        orphans
+            .insert(orphan(i, &params), "spammer", &params, Time::from_second(1))
+            .unwrap();
+    }
+    assert_eq!(
+        orphans.insert(
+            orphan(1000, &params),
+            "spammer",
+            &params,
+            Time::from_second(1)
+        ),
+        Err(Error::TooManyOrphansFromPeer)
+    );
+    // other peers are not affected
+    orphans
+        .insert(
+            orphan(1001, &params),
+            "honest",
+            &params,
+            Time::from_second(1),
+        )
+        .unwrap();
+
+    // once expired, the spammer's orphans no longer count
+    let later = Time::from_u128(Time::from_second(1).to_u128() + OrphanPool::EXPIRY);
+    orphans
+        .insert(orphan(1002, &params), "spammer", &params, later)
+        .unwrap();
+    assert_eq!(orphans.len(), 1);
+}
+
+#[test]
+fn orphan_pool_is_bounded_in_bytes() {
+    let params = Params::default();
+    let mut orphans = OrphanPool::new();
+    let first = orphan(1, &params);
+    orphans.max_bytes = 3 * first.content.size();
+
+    // each from another peer, the oldest is dropped to make room
+    orphans
+        .insert(first.clone(), "peer 1", &params, Time::from_second(1))
+        .unwrap();
+    for i in 2..5 {
+        let peer = format!("peer {}", i);
+        orphans
+            .insert(
+                orphan(i, &params),
+                &peer,
+                &params,
+                Time::from_second(i as u64),
+            )
+            .unwrap();
+    }
+    assert_eq!(orphans.len(), 3);
+    assert!(orphans.bytes() <= orphans.max_bytes);
+    assert!(!orphans.contains(&first));
+}
+
+#[test]
+fn orphan_bytes_are_bounded_per_peer() {
+    let params = Params::default();
+    let mut orphans = OrphanPool::new();
+    let honest = orphan(1, &params);
+    orphans.max_bytes = 3 * honest.content.size();
+    orphans.max_bytes_per_peer = 2 * honest.content.size();
+    let now = Time::from_second(1);
+
+    orphans
+        .insert(honest.clone(), "honest", &params, now)
+        .unwrap();
+    for i in 2..4 {
+        orphans
+            .insert(orphan(i, &params), "spammer", &params, now)
+            .unwrap();
+    }
+    // the spammer cannot push the orphan of another peer out of the pool
+    assert_eq!(
+        orphans.insert(orphan(4, &params), "spammer", &params, now),
+        Err(Error::TooManyOrphansFromPeer)
+    );
+    assert!(orphans.contains(&honest));
+    assert_eq!(orphans.len(), 3);
+}
+
+#[test]
+fn orphans_are_checked_before_being_pooled() {
+    let params = Params::default();
+    let mut orphans = OrphanPool::new();
+    let now = Time::from_second(1);
+
+    let mut forged_hash = orphan(1, &params);
+    forged_hash.hash = Sha256Hash::new(&[&vec![2]]);
+    assert_eq!(
+        orphans.insert(forged_hash, "peer", &params, now),
+        Err(Error::BlockHashIsInvalid)
+    );
+
+    // a target easier than the limit would make the work meaningless
+    let mut easy = Block::new(1, now, Sha256Hash::zero(), Target::max(), vec![]);
+    easy.hash = easy.hash();
+    assert_eq!(
+        orphans.insert(easy, "peer", &params, now),
+        Err(Error::BlockTargetIsInvalid)
+    );
+
+    let mut not_mined = Block::new(1, now, Sha256Hash::zero(), Target::zero(), vec![]);
+    not_mined.hash = not_mined.hash();
+    assert_eq!(
+        orphans.insert(not_mined, "peer", &params, now),
+        Err(Error::BlockProofOfWorkIsNotDone)
+    );
+
+    let mut small = params;
+    small.max_block_size = 10;
+    assert_eq!(
+        orphans.insert(orphan(1, &params), "peer", &small, now),
+        Err(Error::BlockIsTooLarge)
+    );
+    assert_eq!(orphans.len(), 0);
+}
+
+#[test]
+fn stale_branches_are_pruned() {
+    let mut merkle = Merkle::new_from_nothingness();
+    merkle.prune_depth = 2;
+
+    let b1 = block_with_transaction(1, 1, Sha256Hash::zero());
+    let b1_hash = b1.hash;
+    merkle.add_block(b1).unwrap();
+
+    // a side branch forking from b1, and one from the genesis
+    let side = block_with_transaction(2, 2, b1_hash);
+    let side_hash = side.hash;
+    let mut prev_hash = b1_hash;
+    let mut main = vec![];
+    for index in 2..5 {
+        let block = block_with_transaction(10 + index as u64, index, prev_hash);
+        prev_hash = block.hash;
+        main.push(block);
+    }
+    merkle.add_block(main[0].clone()).unwrap();
+    merkle.add_block(side).unwrap();
+    merkle
+        .add_block(block_with_transaction(30, 1, Sha256Hash::zero()))
+        .unwrap();
+    assert_eq!(merkle.chains.len(), 3);
+
+    // a branch exactly `prune_depth` blocks behind is kept
+    merkle.add_block(main[1].clone()).unwrap();
+    assert_eq!(merkle.chains.len(), 3);
+
+    // the genesis fork falls 3 blocks behind, the b1 fork only 2
+    merkle.add_block(main[2].clone()).unwrap();
+    assert_eq!(merkle.chains.len(), 2);
+    assert_eq!(merkle.blocks.len(), 6);
+
+    // the index kept up to date by the pruning is the one a reload rebuilds
+    let mut reloaded = Merkle::from_bytes(&merkle.to_bytes()).unwrap();
+    reloaded.prune_depth = merkle.prune_depth;
+    assert_eq!(reloaded, merkle);
+
+    let b5 = block_with_transaction(40, 5, prev_hash);
+    let b5_hash = b5.hash;
+    merkle.add_block(b5).unwrap();
+    assert_eq!(merkle.chains.len(), 1);
+    assert_eq!(merkle.blocks.len(), 6);
+
+    // b1 is still reachable from main and was kept
+    assert!(merkle.blocks.iter().any(|block| block.hash == b1_hash));
+    assert!(merkle.blocks.iter().all(|block| block.hash != side_hash));
+    assert!(merkle.get_block(&side_hash).is_none());
+    assert_eq!(merkle.height_of(&b5_hash), Some(5));
+    assert_eq!(merkle.main().last(&merkle.blocks).hash, b5_hash);
+    assert_eq!(merkle.main().verify(&merkle.blocks, &merkle.params), Ok(()));
+}
+
+#[test]
+fn fork_ledger_is_reverted_from_its_branch() {
+    let mut merkle = Merkle::new_from_nothingness();
+
+    let mut prev_hash = Sha256Hash::zero();
+    let mut main = vec![];
+    for index in 1..6 {
+        let block = block_with_transaction(index as u64, index, prev_hash);
+        prev_hash = block.hash;
+        main.push(block.hash);
+        merkle.add_block(block).unwrap();
+    }
+
+    // forks at b2 and at the genesis, they revert the accounts created after
+    let fork = block_with_transaction(20, 3, main[1]);
+    merkle.add_block(fork).unwrap();
+    let genesis_fork = block_with_transaction(21, 1, Sha256Hash::zero());
+    merkle.add_block(genesis_fork).unwrap();
+
+    assert_eq!(merkle.chains.len(), 3);
+    for chain in &merkle.chains {
+        assert!(
+            chain.ledger == Ledger::new_unsafe(&chain.index, &merkle.blocks),
+            "the ledger of a fork differs from a replay of its blocks"
+        );
+        assert_eq!(chain.verify(&merkle.blocks, &merkle.params), Ok(()));
+    }
+}
+
+#[test]
+fn received_merkle_is_fully_verified() {
+    let mut merkle = Merkle::new_from_nothingness();
+
+    let mut prev_hash = Sha256Hash::zero();
+    let mut main = vec![];
+    for index in 1..5 {
+        let block = block_with_transaction(index as u64, index, prev_hash);
+        prev_hash = block.hash;
+        main.push(block.hash);
+        merkle.add_block(block).unwrap();
+    }
+    let fork = block_with_transaction(30, 2, main[0]);
+    merkle.add_block(fork).unwrap();
+
+    let received = Merkle::from_bytes(&merkle.to_bytes()).unwrap();
+    assert_eq!(received.verify_full(), Ok(()));
+
+    // a ledger that does not come from its blocks
+    let mut forged = Merkle::from_bytes(&merkle.to_bytes()).unwrap();
+    let thief = User::from_nothingness();
+    let mut loot = UserData::new(thief.as_public());
+    loot.money = Num::from_u64(1000);
+    forged.chains[0]
+        .ledger
+        .set_user_data(thief.as_public(), Some(loot));
+    assert_eq!(
+        forged.verify_full().map_err(|rejection| rejection.error),
+        Err(Error::LedgerDoesNotMatch)
+    );
+
+    // a block whose payload was swapped, the stored ledger is left untouched
+    let mut forged = Merkle::from_bytes(&merkle.to_bytes()).unwrap();
+    forged.blocks[2].content.transactions = vec![];
+    assert!(forged.verify_full().is_err());
+
+    // a block stored twice
+    let mut forged = merkle.clone();
+    forged.blocks.push(forged.blocks[1].clone());
+    let forged = Merkle::from_bytes(&forged.to_bytes()).unwrap();
+    assert_eq!(
+        forged.verify_full().map_err(|rejection| rejection.error),
+        Err(Error::DuplicateBlockInTree)
+    );
+
+    // a block that no branch goes through is never replayed
+    let mut forged = merkle.clone();
+    let extra = block_with_transaction(40, 5, main[3]);
+    forged.blocks.push(extra);
+    let forged = Merkle::from_bytes(&forged.to_bytes()).unwrap();
+    assert_eq!(
+        forged.verify_full().map_err(|rejection| rejection.error),
+        Err(Error::BlockExistButIsNotInAnyBranch)
+    );
+
+    // the main branch must be the one with the most work
+    let mut forged = Merkle::from_bytes(&merkle.to_bytes()).unwrap();
+    forged.main = 1;
+    assert_eq!(
+        forged.verify_full().map_err(|rejection| rejection.error),
+        Err(Error::MainBranchIsNotTheBest)
+    );
+}
@@ -1,10 +1,25 @@
 use blockchain::block::{Block, BlockContent};
+use blockchain::error::Error;
+use blockchain::ledger::{BalanceProof, Ledger, UserData};
 use blockchain::merkle::Merkle;
+use blockchain::merkle_tree::{MerkleProof, MerkleTree};
 use blockchain::mining::Miner;
+use blockchain::multisig::{MultisigAccount, MultisigCollector, PartialSignature};
+use blockchain::script::{Op, Script};
 use blockchain::sha256::Sha256Hash;
+use blockchain::signature::Signable;
+use blockchain::state::StateModel;
+use blockchain::target::Target;
 use blockchain::time::Time;
-use blockchain::user::User;
-use blockchain::{num::Num, transaction::Transaction};
+use blockchain::user::{PublicUser, User};
+use blockchain::utxo::{OutPoint, UtxoSet, UtxoTransaction};
+use blockchain::{
+    num::Num,
+    transaction::{
+        HashLock, Lock, Output, Payment, TokenDefinition, Transaction, TransactionContent, Witness,
+    },
+};
+use ed25519_dalek::Keypair;
 
 #[test]
 fn num_to_bytes_from_bytes() {
@@ -40,6 +55,309 @@ fn transaction_to_bytes_from_bytes() {
     )
 }
 
+#[test]
+fn transaction_with_fee_to_bytes_from_bytes() {
+    let from = User::from_nothingness();
+    let to: User = User::from_nothingness();
+
+    let original =
+        Transaction::new_with_fee(from, to.as_public(), Num::from_u64(10), Num::from_u64(1), 1);
+    let original_as_bytes = original.to_bytes();
+
+    let reconstructed = match Transaction::from_bytes(&original_as_bytes) {
+        Ok(v) => v,
+        Err(_) => panic!("Transaction::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed.content.fee, original.content.fee,
+        "Transaction::from_bytes lost the fee"
+    );
+    assert!(
+        reconstructed.verify_signature(),
+        "Transaction::from_bytes broke the signature"
+    )
+}
+
+#[test]
+fn batch_transaction_to_bytes_from_bytes() {
+    let from = User::from_nothingness();
+    let outputs: Vec<Output> = (1..4)
+        .map(|i| Output::new(User::from_nothingness().as_public(), Num::from_u64(i)))
+        .collect();
+
+    let original = Transaction::new_batch(from, outputs, Num::from_u64(1), 1);
+    let original_as_bytes = original.to_bytes();
+
+    let reconstructed = match Transaction::from_bytes(&original_as_bytes) {
+        Ok(v) => v,
+        Err(_) => panic!("Transaction::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "Transaction::from_bytes completed incorrectly"
+    );
+    assert!(
+        reconstructed.verify_signature(),
+        "Transaction::from_bytes broke the signature"
+    )
+}
+
+#[test]
+fn multisig_transaction_to_bytes_from_bytes() {
+    let keys: Vec<User> = (0..3).map(|_| User::from_nothingness()).collect();
+    let account = MultisigAccount::new(2, keys.iter().map(|u| u.as_public()).collect()).unwrap();
+    let to: User = User::from_nothingness();
+
+    let content = TransactionContent::new(
+        account.address(),
+        Payment::Single(Output::new(to.as_public(), Num::from_u64(10))),
+        Num::zero(),
+        1,
+    );
+    let mut collector = MultisigCollector::new(account, content.clone());
+    for signer in &keys[1..] {
+        collector
+            .add(PartialSignature::new(&content, signer))
+            .unwrap();
+    }
+    let original = collector.finish().unwrap();
+    let original_as_bytes = original.to_bytes();
+
+    let reconstructed = match Transaction::from_bytes(&original_as_bytes) {
+        Ok(v) => v,
+        Err(_) => panic!("Transaction::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "Transaction::from_bytes completed incorrectly"
+    );
+    assert!(
+        reconstructed.verify_signature(),
+        "Transaction::from_bytes broke the signatures"
+    )
+}
+
+#[test]
+fn locked_expiring_transaction_to_bytes_from_bytes() {
+    let from = User::from_nothingness();
+    let to: User = User::from_nothingness();
+
+    for lock in [Lock::Height(12), Lock::Time(Time::from_second(3600))] {
+        let payment = Payment::Single(Output::new(to.as_public(), Num::from_u64(10)));
+        let content = TransactionContent::new(from.as_public(), payment, Num::zero(), 1);
+        let original = Transaction::from_content(content.with_lock(lock).with_expiry(40), &from);
+        let original_as_bytes = original.to_bytes();
+
+        let reconstructed = match Transaction::from_bytes(&original_as_bytes) {
+            Ok(v) => v,
+            Err(_) => panic!("Transaction::from_bytes failed to complete"),
+        };
+
+        assert_eq!(
+            reconstructed, original,
+            "Transaction::from_bytes completed incorrectly"
+        );
+        assert_eq!(reconstructed.content.lock, Some(lock));
+        assert_eq!(reconstructed.content.valid_until, Some(40));
+    }
+}
+
+#[test]
+fn transaction_with_memo_to_bytes_from_bytes() {
+    let from = User::from_nothingness();
+    let to: User = User::from_nothingness();
+
+    let payment = Payment::Single(Output::new(to.as_public(), Num::from_u64(10)));
+    let content = TransactionContent::new(from.as_public(), payment, Num::zero(), 1);
+    let original = Transaction::from_content(content.with_memo(b"invoice 12".to_vec()), &from);
+    let original_as_bytes = original.to_bytes();
+
+    let reconstructed = match Transaction::from_bytes(&original_as_bytes) {
+        Ok(v) => v,
+        Err(_) => panic!("Transaction::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "Transaction::from_bytes completed incorrectly"
+    );
+    assert!(reconstructed.verify_signature());
+
+    // a memo longer than allowed is refused before it is read
+    let payment = Payment::Single(Output::new(to.as_public(), Num::from_u64(10)));
+    let content = TransactionContent::new(from.as_public(), payment, Num::zero(), 1);
+    let long = vec![0; TransactionContent::MAX_MEMO + 1];
+    let too_long = Transaction::from_content(content.with_memo(long), &from);
+    assert!(Transaction::from_bytes(&too_long.to_bytes()).is_err());
+}
+
+#[test]
+fn hash_lock_transactions_to_bytes_from_bytes() {
+    let from = User::from_nothingness();
+    let to = User::from_nothingness();
+    let preimage = b"swap secret".to_vec();
+
+    let lock = HashLock {
+        recipient: to.as_public(),
+        value: Num::from_u64(10),
+        hash: Sha256Hash::new(&[&preimage]),
+        timeout: 12,
+    };
+    let hash_lock = Transaction::new_hash_lock(from, lock, Num::from_u64(1), 1);
+    let id = hash_lock.content_hash();
+    let claim = Transaction::new_claim(to, id, preimage, Num::zero(), 1);
+    let refund = Transaction::new_refund(User::from_nothingness(), id, Num::zero(), 2);
+
+    for original in [hash_lock, claim, refund] {
+        let reconstructed = match Transaction::from_bytes(&original.to_bytes()) {
+            Ok(v) => v,
+            Err(_) => panic!("Transaction::from_bytes failed to complete"),
+        };
+
+        assert_eq!(
+            reconstructed, original,
+            "Transaction::from_bytes completed incorrectly"
+        );
+        assert!(reconstructed.verify_signature());
+    }
+}
+
+#[test]
+fn token_transactions_to_bytes_from_bytes() {
+    let holder = User::from_nothingness();
+
+    let definition = TokenDefinition {
+        symbol: "POINTS".to_string(),
+        max_supply: Num::from_u64(1_000),
+    };
+    let creation =
+        Transaction::new_token(User::from_nothingness(), definition, Num::from_u64(1), 1);
+    let token = creation.content_hash();
+    let output = Output::new(holder.as_public(), Num::from_u64(10));
+    let mint = Transaction::new_mint(
+        User::from_nothingness(),
+        token,
+        output.clone(),
+        Num::zero(),
+        2,
+    );
+    let transfer = Transaction::new_token_transfer(holder, token, output, Num::zero(), 1);
+
+    for original in [creation, mint, transfer] {
+        let reconstructed = match Transaction::from_bytes(&original.to_bytes()) {
+            Ok(v) => v,
+            Err(_) => panic!("Transaction::from_bytes failed to complete"),
+        };
+
+        assert_eq!(
+            reconstructed, original,
+            "Transaction::from_bytes completed incorrectly"
+        );
+        assert!(reconstructed.verify_signature());
+    }
+}
+
+#[test]
+fn multisig_witness_must_be_canonical() {
+    let keys: Vec<User> = (0..3).map(|_| User::from_nothingness()).collect();
+    let account = MultisigAccount::new(2, keys.iter().map(|u| u.as_public()).collect()).unwrap();
+    let content = TransactionContent::new(
+        account.address(),
+        Payment::Single(Output::new(PublicUser::zero(), Num::from_u64(10))),
+        Num::zero(),
+        1,
+    );
+    let mut signatures: Vec<PartialSignature> = keys
+        .iter()
+        .map(|signer| PartialSignature::new(&content, signer))
+        .collect();
+    signatures.sort_by_key(|s| s.signer);
+    let outsider = PartialSignature::new(&content, &User::from_nothingness());
+
+    let reversed = signatures.iter().rev().cloned().collect();
+    let duplicated = vec![signatures[0].clone(), signatures[0].clone()];
+    let too_many = [&signatures[..], &signatures[..1]].concat();
+    let foreign = vec![outsider];
+    for forged in [reversed, duplicated, too_many, foreign] {
+        let transaction = Transaction {
+            content: content.clone(),
+            witness: Witness::Multisig {
+                account: account.clone(),
+                signatures: forged,
+            },
+        };
+        assert_eq!(
+            Transaction::from_bytes(&transaction.to_bytes()),
+            Err(Error::InvalidFormat),
+            "a non canonical multisig witness was read"
+        );
+    }
+}
+
+#[test]
+fn multisig_account_with_too_many_keys_is_rejected() {
+    // threshold 1, then a key count far above MAX_KEYS and no key
+    let bytes = [1u32.to_be_bytes(), u32::MAX.to_be_bytes()].concat();
+    assert_eq!(
+        MultisigAccount::from_bytes(&bytes),
+        Err(Error::InvalidFormat)
+    );
+
+    let keys: Vec<PublicUser> = (0..MultisigAccount::MAX_KEYS + 1)
+        .map(|_| User::from_nothingness().as_public())
+        .collect();
+    let mut bytes = [1u32.to_be_bytes(), (keys.len() as u32).to_be_bytes()].concat();
+    for key in &keys {
+        bytes.extend(key.to_bytes());
+    }
+    assert_eq!(
+        MultisigAccount::from_bytes(&bytes),
+        Err(Error::InvalidFormat)
+    );
+}
+
+#[test]
+fn script_transaction_to_bytes_from_bytes() {
+    let owner = User::from_nothingness();
+    let script = Script::new(vec![
+        Op::Push(20u32.to_be_bytes().to_vec()),
+        Op::CheckHeight,
+        Op::Push(owner.as_public().to_bytes().to_vec()),
+        Op::Swap,
+        Op::Drop,
+        Op::CheckSig,
+    ]);
+
+    let payment = Payment::Single(Output::new(owner.as_public(), Num::from_u64(3)));
+    let content = TransactionContent::new(script.address(), payment, Num::zero(), 1);
+    let original = Transaction {
+        witness: Witness::Script {
+            script: script.clone(),
+            arguments: vec![content.sign(&owner.key_pair).to_bytes().to_vec()],
+        },
+        content,
+    };
+    let original_as_bytes = original.to_bytes();
+
+    let reconstructed = match Transaction::from_bytes(&original_as_bytes) {
+        Ok(v) => v,
+        Err(_) => panic!("Transaction::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "Transaction::from_bytes completed incorrectly"
+    );
+    assert!(reconstructed.verify_signature());
+
+    // a script longer than allowed is refused before its ops are read
+    let too_long = Script::new(vec![Op::Dup; Script::MAX_OPS + 1]);
+    assert!(Script::from_bytes(&too_long.to_bytes()).is_err());
+}
+
 #[test]
 fn block_to_bytes_from_bytes() {
     let from = User::from_nothingness();
@@ -47,7 +365,13 @@ fn block_to_bytes_from_bytes() {
 
     let transaction = Transaction::new(from, to.as_public(), Num::from_u64(10), 1);
     let payload: Vec<Transaction> = vec![transaction];
-    let original = Block::new(1, Time::from_second(0), Sha256Hash::zero(), payload);
+    let original = Block::new(
+        1,
+        Time::from_second(0),
+        Sha256Hash::zero(),
+        Target::initial(),
+        payload,
+    );
     let original_as_bytes = original.to_bytes();
 
     let reconstructed = match Block::from_bytes(&original_as_bytes) {
@@ -70,10 +394,16 @@ fn merkle_to_byte_from_byte() {
 
     let transaction = Transaction::new(from, to.as_public(), Num::from_u64(10), 1);
     let payload: Vec<Transaction> = vec![transaction];
-    let block_content = BlockContent::new(1, Time::from_second(0), Sha256Hash::zero(), payload);
+    let mut block_content = BlockContent::new(
+        1,
+        Time::from_second(1),
+        Sha256Hash::zero(),
+        Target::initial(),
+        payload,
+    );
+    block_content.set_state_root(&original.main().ledger);
 
-    let difficulty: u128 = Merkle::DIFFICULTY;
-    let (nonce, hash) = Miner::mine(&block_content, difficulty);
+    let (nonce, hash) = Miner::mine(&block_content);
     let block = Block::new_mined(block_content, nonce, hash);
 
     original.add_block(block).ok();
@@ -89,3 +419,219 @@ fn merkle_to_byte_from_byte() {
         "Block::from_bytes completed incorrectly"
     )
 }
+
+#[test]
+fn merkle_with_unknown_block_index_is_rejected() {
+    let mut forged = Merkle::new_from_nothingness();
+    forged.chains[0].index.push(1000);
+
+    assert_eq!(
+        Merkle::from_bytes(&forged.to_bytes()),
+        Err(Error::InvalidFormat),
+        "a branch referring to a block that was not sent was read"
+    );
+}
+
+#[test]
+fn merkle_proof_to_bytes_from_bytes() {
+    let leaves: Vec<Sha256Hash> = (0..7u8).map(|i| Sha256Hash::new(&[&vec![i]])).collect();
+    let tree = MerkleTree::new(leaves.clone());
+    let original = tree.proof(6).unwrap();
+    let original_as_bytes = original.to_bytes();
+
+    let reconstructed = match MerkleProof::from_bytes(&original_as_bytes) {
+        Ok(v) => v,
+        Err(_) => panic!("MerkleProof::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "MerkleProof::from_bytes completed incorrectly"
+    );
+    assert!(reconstructed.verify(&leaves[6], &tree.root()))
+}
+
+#[test]
+fn ledger_to_bytes_from_bytes() {
+    let users: Vec<PublicUser> = (0..8)
+        .map(|_| User::from_nothingness().as_public())
+        .collect();
+
+    // the same accounts, created in opposite orders
+    let mut original = Ledger::empty();
+    let mut reversed = Ledger::empty();
+    for (i, user) in users.iter().enumerate() {
+        let mut user_data = UserData::new(*user);
+        user_data.money = Num::from_u64(i as u64);
+        original.set_user_data(*user, Some(user_data));
+    }
+    for (i, user) in users.iter().enumerate().rev() {
+        let mut user_data = UserData::new(*user);
+        user_data.money = Num::from_u64(i as u64);
+        reversed.set_user_data(*user, Some(user_data));
+    }
+    let original_as_bytes = original.to_bytes();
+    assert_eq!(
+        original_as_bytes,
+        reversed.to_bytes(),
+        "Ledger::to_bytes depends on the insertion order"
+    );
+
+    let reconstructed = match Ledger::from_bytes(&original_as_bytes) {
+        Ok(v) => v,
+        Err(_) => panic!("Ledger::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "Ledger::from_bytes completed incorrectly"
+    )
+}
+
+#[test]
+fn ledger_with_hash_lock_to_bytes_from_bytes() {
+    let from = User::from_nothingness();
+    let to = User::from_nothingness();
+
+    let funded = Ledger::empty().apply_unsafe(
+        &[Transaction::new(
+            User::new_coinbase(),
+            from.as_public(),
+            Num::from_u64(10),
+            1,
+        )],
+        1,
+    );
+    let lock = HashLock {
+        recipient: to.as_public(),
+        value: Num::from_u64(4),
+        hash: Sha256Hash::new(&[&b"secret".to_vec()]),
+        timeout: 5,
+    };
+    let original =
+        funded.apply_unsafe(&[Transaction::new_hash_lock(from, lock, Num::zero(), 1)], 2);
+    assert_eq!(original.hash_locks.len(), 1);
+    assert_ne!(original.state_root(), funded.state_root());
+
+    let reconstructed = match Ledger::from_bytes(&original.to_bytes()) {
+        Ok(v) => v,
+        Err(_) => panic!("Ledger::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "Ledger::from_bytes completed incorrectly"
+    )
+}
+
+#[test]
+fn balance_proof_to_bytes_from_bytes() {
+    let mut ledger = Ledger::empty();
+    let users: Vec<PublicUser> = (0..5)
+        .map(|_| User::from_nothingness().as_public())
+        .collect();
+    for (i, user) in users.iter().enumerate() {
+        let mut user_data = UserData::new(*user);
+        user_data.money = Num::from_u64(i as u64);
+        ledger.set_user_data(*user, Some(user_data));
+    }
+    let original = ledger.balance_proof(users[3]).unwrap();
+    let original_as_bytes = original.to_bytes();
+
+    let reconstructed = match BalanceProof::from_bytes(&original_as_bytes) {
+        Ok(v) => v,
+        Err(_) => panic!("BalanceProof::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "BalanceProof::from_bytes completed incorrectly"
+    );
+    assert!(reconstructed.verify(&ledger.state_root()))
+}
+
+#[test]
+fn utxo_transaction_and_set_to_bytes_from_bytes() {
+    let alice = User::from_nothingness();
+    let bob = User::from_nothingness();
+
+    let coinbase =
+        UtxoTransaction::new_coinbase(1, vec![Output::new(alice.as_public(), Num::from_u64(10))]);
+    let original = UtxoTransaction::new(
+        vec![(OutPoint::new(coinbase.hash(), 0), &alice)],
+        vec![
+            Output::new(bob.as_public(), Num::from_u64(6)),
+            Output::new(alice.as_public(), Num::from_u64(4)),
+        ],
+    );
+
+    let reconstructed = match UtxoTransaction::from_bytes(&original.to_bytes()) {
+        Ok(v) => v,
+        Err(_) => panic!("UtxoTransaction::from_bytes failed to complete"),
+    };
+    assert_eq!(
+        reconstructed, original,
+        "UtxoTransaction::from_bytes completed incorrectly"
+    );
+
+    let funded = UtxoSet::empty().apply_unsafe(&[coinbase], 1);
+    let original = funded.apply_unsafe(&[original], 2);
+    assert_eq!(original.outputs.len(), 2);
+    assert_ne!(original.state_root(), funded.state_root());
+
+    let reconstructed = match UtxoSet::from_bytes(&original.to_bytes()) {
+        Ok(v) => v,
+        Err(_) => panic!("UtxoSet::from_bytes failed to complete"),
+    };
+    assert_eq!(
+        reconstructed, original,
+        "UtxoSet::from_bytes completed incorrectly"
+    );
+}
+
+#[test]
+fn ledger_with_tokens_to_bytes_from_bytes() {
+    let issuer = User::from_nothingness();
+    let holder = User::from_nothingness();
+    let issuer_public = issuer.as_public();
+    let issuer_copy = User::new(Keypair::from_bytes(&issuer.key_pair.to_bytes()).unwrap());
+
+    let definition = TokenDefinition {
+        symbol: "POINTS".to_string(),
+        max_supply: Num::from_u64(100),
+    };
+    let creation = Transaction::new_token(issuer, definition, Num::zero(), 1);
+    let token = creation.content_hash();
+    let output = Output::new(holder.as_public(), Num::from_u64(30));
+    let mint = Transaction::new_mint(issuer_copy, token, output, Num::zero(), 2);
+
+    let funded = Ledger::empty().apply_unsafe(
+        &[Transaction::new(
+            User::new_coinbase(),
+            issuer_public,
+            Num::from_u64(10),
+            1,
+        )],
+        1,
+    );
+    let original = funded.apply_unsafe(&[creation, mint], 2);
+    assert_eq!(original.tokens.len(), 1);
+    assert_eq!(
+        original
+            .get_user_data(holder.as_public())
+            .unwrap()
+            .token_balance(&token),
+        Num::from_u64(30)
+    );
+
+    let reconstructed = match Ledger::from_bytes(&original.to_bytes()) {
+        Ok(v) => v,
+        Err(_) => panic!("Ledger::from_bytes failed to complete"),
+    };
+
+    assert_eq!(
+        reconstructed, original,
+        "Ledger::from_bytes completed incorrectly"
+    );
+    assert_eq!(reconstructed.state_root(), original.state_root());
+}